@@ -1,127 +1,944 @@
 use std::fmt::{self, Debug};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
 use std::num::NonZeroU32;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
 use ring::rand::{SecureRandom, SystemRandom};
-use ring::{aead, hkdf, pbkdf2};
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use ring::{aead, digest, hkdf, hmac, pbkdf2};
 use serde_derive::{Deserialize, Serialize};
+pub use zeroize::Zeroizing;
 
 use crate::error::{Error, Result};
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// A password KDF and its salt.
+///
+/// KDFs serialized before `algorithm` was added deserialize as a `LegacyKDF`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct KDF {
+    pub algorithm: KdfAlgorithm,
+    pub salt: [u8; 256 / 8],
+    pub requires_keyfile: bool, // keys are derived from a password and a keyfile
+}
+
+/// The password hash used to stretch passwords, along with its cost parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KdfAlgorithm {
+    Pbkdf2HmacSha256 {
+        iters: NonZeroU32,
+    },
+    /// Memory-hard, prefer this for passwords that protect data on a remote.
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+/// The serialized layout of `KDF` from when it only supported PBKDF2, convert it with `KDF::from`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LegacyKDF {
     pub pbkdf2_iters: NonZeroU32,
     pub salt: [u8; 256 / 8],
 }
 
+/// Key material is wiped from memory when it's dropped.
 pub struct KeyHierarchy {
-    key: hkdf::Prk,
+    prk: Zeroizing<[u8; 256 / 8]>, // the HKDF pseudorandom key at the root of this hierarchy
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(PartialEq, Eq)]
 pub struct CryptoKey {
-    key: [u8; 256 / 8],
+    key: Zeroizing<[u8; 256 / 8]>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Encrypted {
-    pub nonce: [u8; 96 / 8],
+    pub aead: Aead, // serialized as a single suite byte
+    pub nonce: Vec<u8>,
     pub ciphertext: Vec<u8>,
 }
 
+/// The AEAD used to encrypt data.
+///
+/// Each `Encrypted` records the AEAD it was sealed with, so data encrypted
+/// with different AEADs can be opened side by side.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum Aead {
+    #[default]
+    ChaCha20Poly1305,
+    Aes256Gcm,
+    /// Its 192 bit nonces are safe to pick at random for any number of messages.
+    XChaCha20Poly1305,
+}
+
+pub type PublicKey = [u8; 256 / 8];
+
+/// An Ed25519 public key, it checks signatures made by a `SigningKey`.
+pub type VerifyingKey = [u8; 256 / 8];
+
+/// An Ed25519 key for signing data, see `verify`.
+pub struct SigningKey {
+    seed: Zeroizing<[u8; 256 / 8]>,
+    pair: Ed25519KeyPair,
+}
+
+/// An X25519 secret key, data can be sealed to it knowing only its public key.
+pub struct SecretKey {
+    secret: x25519_dalek::StaticSecret,
+}
+
+/// Deterministic authenticated encryption of short names, a synthetic IV construction.
+///
+/// Equal names encrypt to equal ciphertexts, so encrypted names can stand in
+/// for the names themselves, eg. in git branch names, without revealing them.
+pub struct NameCipher {
+    mac_key: hmac::Key,
+    stream_key: hmac::Key,
+}
+
+/// Encryption of a local key-value store.
+///
+/// Keys are replaced by their HMAC, so they can still be looked up, and values
+/// are sealed to the key they're stored under, so they can't be swapped around.
+pub struct StorageCipher {
+    mac_key: hmac::Key,
+    value_keys: KeyHierarchy,
+}
+
+/// Data sealed to a public key, see `seal`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sealed {
+    pub ephemeral: PublicKey,
+    pub encrypted: Encrypted,
+}
+
 impl KDF {
-    pub fn derive_root(&self, pass: &[u8]) -> KeyHierarchy {
-        let mut root_key = [0u8; 256 / 8];
+    /// A KDF with a fresh random salt.
+    pub fn generate(algorithm: KdfAlgorithm) -> Result<Self> {
+        Ok(KDF {
+            algorithm,
+            salt: rand_256()?,
+            requires_keyfile: false,
+        })
+    }
 
-        pbkdf2::derive(
-            pbkdf2::PBKDF2_HMAC_SHA256,
-            self.pbkdf2_iters,
-            &self.salt,
-            pass,
-            &mut root_key,
-        );
+    /// An Argon2id KDF using `memory_kib` and `parallelism`, with the number of
+    /// iterations picked so that deriving a key takes about `target` on this machine.
+    pub fn calibrate_argon2id(memory_kib: u32, parallelism: u32, target: Duration) -> Result<Self> {
+        let mut kdf = KDF::generate(KdfAlgorithm::Argon2id {
+            memory_kib,
+            iterations: 1,
+            parallelism,
+        })?;
+
+        let start = Instant::now();
+        kdf.derive_root(b"calibration", None)?;
+        let per_iteration = start.elapsed().max(Duration::from_micros(1));
+
+        let iterations =
+            (target.as_micros() / per_iteration.as_micros()).clamp(1, u32::MAX as u128);
+        kdf.algorithm = KdfAlgorithm::Argon2id {
+            memory_kib,
+            iterations: iterations as u32,
+            parallelism,
+        };
+        Ok(kdf)
+    }
+
+    /// Require a keyfile alongside the password when deriving keys with this KDF.
+    ///
+    /// Nothing about the keyfile is stored, a wrong keyfile derives a wrong key
+    /// just like a wrong password does.
+    pub fn with_keyfile(self) -> Self {
+        KDF {
+            requires_keyfile: true,
+            ..self
+        }
+    }
+
+    /// Derive a root key from a password, and a keyfile if this KDF requires one.
+    ///
+    /// Intermediate key material is wiped, keep `pass` in a `Zeroizing` buffer
+    /// so that it's wiped too once you're done with it.
+    pub fn derive_root(&self, pass: &[u8], keyfile: Option<&[u8]>) -> Result<KeyHierarchy> {
+        let keyfile_key = match (self.requires_keyfile, keyfile) {
+            (false, None) => None,
+            (true, None) => return Err(Error::MissingKeyfile),
+            (false, Some(_)) => return Err(Error::UnexpectedKeyfile),
+            (true, Some(keyfile)) => Some(self.keyfile_key(keyfile)),
+        };
+
+        let mut root_key = Zeroizing::new([0u8; 256 / 8]);
+
+        match self.algorithm {
+            KdfAlgorithm::Pbkdf2HmacSha256 { iters } => pbkdf2::derive(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                iters,
+                &self.salt,
+                pass,
+                &mut root_key[..],
+            ),
+            KdfAlgorithm::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let params = argon2::Params::new(memory_kib, iterations, parallelism, None)
+                    .map_err(|e| Error::Crypto(format!("Bad Argon2id parameters: {}", e)))?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(pass, &self.salt, &mut root_key[..])
+                    .map_err(|e| Error::Crypto(format!("Argon2id failed: {}", e)))?;
+            }
+        }
+
+        let prk = match keyfile_key {
+            Some(keyfile_key) => {
+                let mut ikm = Zeroizing::new([0u8; 2 * 256 / 8]);
+                ikm[..256 / 8].copy_from_slice(&root_key[..]);
+                ikm[256 / 8..].copy_from_slice(&keyfile_key[..]);
+                hkdf_extract(&self.salt, &ikm[..])
+            }
+            None => hkdf_extract(&self.salt, &root_key[..]),
+        };
 
-        let key = hkdf::Salt::new(hkdf::HKDF_SHA256, &self.salt).extract(&root_key);
+        Ok(KeyHierarchy { prk })
+    }
+
+    fn keyfile_key(&self, keyfile: &[u8]) -> Zeroizing<[u8; 256 / 8]> {
+        let mut keyfile_key = Zeroizing::new([0u8; 256 / 8]);
+        keyfile_key.copy_from_slice(
+            hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &self.salt), keyfile).as_ref(),
+        );
+        keyfile_key
+    }
+}
 
-        KeyHierarchy { key }
+impl From<LegacyKDF> for KDF {
+    fn from(legacy: LegacyKDF) -> Self {
+        KDF {
+            algorithm: KdfAlgorithm::Pbkdf2HmacSha256 {
+                iters: legacy.pbkdf2_iters,
+            },
+            salt: legacy.salt,
+            requires_keyfile: false,
+        }
     }
 }
 
+/// HKDF-Extract, the pseudorandom key is HMAC(salt, input keying material).
+fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> Zeroizing<[u8; 256 / 8]> {
+    let mut prk = Zeroizing::new([0u8; 256 / 8]);
+    prk.copy_from_slice(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, salt), ikm).as_ref());
+    prk
+}
+
+/// Write a new keyfile of random bytes to `path`, an existing file is never overwritten.
+pub fn generate_keyfile(path: impl AsRef<Path>) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut keyfile = options.open(path)?;
+    keyfile.write_all(&rand_256()?)?;
+    keyfile.write_all(&rand_256()?)?;
+    keyfile.sync_all()?;
+    Ok(())
+}
+
 impl KeyHierarchy {
-    pub fn derive_child(&self, namespace: &[u8]) -> KeyHierarchy {
-        let mut salt = [0u8; 256 / 8];
-        self.key
-            .expand(&[], hkdf::HKDF_SHA256)
-            .and_then(|output_keying_mat| output_keying_mat.fill(&mut salt))
-            .unwrap();
+    /// Build a key hierarchy rooted at a high entropy secret, eg. a random data key.
+    pub fn from_secret(secret: &[u8]) -> KeyHierarchy {
+        KeyHierarchy {
+            prk: hkdf_extract(&[], secret),
+        }
+    }
 
+    pub fn derive_child(&self, namespace: &[u8]) -> KeyHierarchy {
+        let salt = self.expand(&[]);
         KeyHierarchy {
-            key: hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(namespace),
+            prk: hkdf_extract(&salt[..], namespace),
         }
     }
 
-    pub fn key_for(&self, plaintext_unique_id: &[u8]) -> CryptoKey {
-        let mut crypto_key = CryptoKey {
-            key: [0u8; 256 / 8],
-        };
+    /// A value that identifies this key without revealing it.
+    ///
+    /// Stored alongside encrypted data, it lets us tell a wrong password from corrupt data.
+    pub fn key_check(&self) -> [u8; 256 / 8] {
+        *self.expand(b"key check")
+    }
 
-        self.key
-            .expand(&[plaintext_unique_id], hkdf::HKDF_SHA256)
-            .and_then(|okm| okm.fill(&mut crypto_key.key))
+    /// HKDF-Expand, `info` is empty when deriving child keys.
+    fn expand(&self, info: &[u8]) -> Zeroizing<[u8; 256 / 8]> {
+        let mut bytes = Zeroizing::new([0u8; 256 / 8]);
+        hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &self.prk[..])
+            .expand(&[info], hkdf::HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut bytes[..]))
             .unwrap();
+        bytes
+    }
+
+    pub fn key_for(&self, plaintext_unique_id: &[u8]) -> CryptoKey {
+        CryptoKey {
+            key: self.expand(plaintext_unique_id),
+        }
+    }
 
-        crypto_key
+    /// A signing key that's derived deterministically from this key.
+    pub fn signing_key(&self) -> Result<SigningKey> {
+        SigningKey::from_bytes(&self.expand(b"signing key"))
     }
 }
 
 impl CryptoKey {
     pub fn encrypt(&self, plaintext: &[u8]) -> Result<Encrypted> {
-        let algo = &aead::CHACHA20_POLY1305;
+        self.encrypt_with_aad(plaintext, &[])
+    }
 
-        let mut cryptic = Encrypted {
-            nonce: rand_nonce()?,
-            ciphertext: vec![0u8; plaintext.len() + algo.tag_len()],
-        };
+    pub fn decrypt(&self, encrypted: &Encrypted) -> Result<Zeroizing<Vec<u8>>> {
+        self.decrypt_with_aad(encrypted, &[])
+    }
+
+    /// Encrypt `plaintext`, binding the ciphertext to `aad`.
+    ///
+    /// The associated data is authenticated but not encrypted, the same `aad`
+    /// must be given to `decrypt_with_aad` to open the ciphertext.
+    pub fn encrypt_with_aad(&self, plaintext: &[u8], aad: &[u8]) -> Result<Encrypted> {
+        self.encrypt_with(Aead::default(), plaintext, aad)
+    }
+
+    /// Like `encrypt_with_aad`, but with the given AEAD.
+    pub fn encrypt_with(&self, aead: Aead, plaintext: &[u8], aad: &[u8]) -> Result<Encrypted> {
+        self.encrypt_with_nonce(aead, rand_bytes(aead.nonce_len())?, plaintext, aad)
+    }
+
+    /// Like `encrypt_with`, but with a nonce of our choosing, see `counter_nonce`.
+    ///
+    /// A nonce must never be used twice with the same key.
+    pub fn encrypt_with_nonce(
+        &self,
+        aead: Aead,
+        nonce: Vec<u8>,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<Encrypted> {
+        aead.check_nonce(&nonce)?;
+
+        // room for the tag up front, so the plaintext is never left behind in a reallocation
+        let mut in_out = Zeroizing::new(Vec::with_capacity(plaintext.len() + TAG_LEN));
+        in_out.extend_from_slice(plaintext);
+        match aead.ring_algorithm() {
+            Some(algo) => self
+                .ring_key(algo)?
+                .seal_in_place_append_tag(
+                    ring_nonce(&nonce)?,
+                    aead::Aad::from(aad),
+                    &mut *in_out, // plaintext (encrypted in place)
+                )
+                .map_err(|_| Error::Crypto("Failed to encrypt".into()))?,
+            None => XChaCha20Poly1305::new(self.key[..].into())
+                .encrypt_in_place(XNonce::from_slice(&nonce), aad, &mut *in_out)
+                .map_err(|_| Error::Crypto("Failed to encrypt".into()))?,
+        }
+
+        Ok(Encrypted {
+            aead,
+            nonce,
+            ciphertext: std::mem::take(&mut *in_out),
+        })
+    }
+
+    /// Decrypt a ciphertext bound to `aad`, see `encrypt_with_aad`.
+    ///
+    /// The plaintext is wiped when it's dropped.
+    pub fn decrypt_with_aad(
+        &self,
+        encrypted: &Encrypted,
+        aad: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        encrypted.aead.check_nonce(&encrypted.nonce)?;
+
+        let mut in_out = Zeroizing::new(encrypted.ciphertext.clone());
+        match encrypted.aead.ring_algorithm() {
+            Some(algo) => {
+                let plain_len = self
+                    .ring_key(algo)?
+                    .open_in_place(
+                        ring_nonce(&encrypted.nonce)?,
+                        aead::Aad::from(aad),
+                        &mut in_out, // cyphertext (decrypted in place)
+                    )
+                    .map_err(|_| Error::Crypto("Failed to decrypt".into()))?
+                    .len();
+                in_out.truncate(plain_len);
+            }
+            None => XChaCha20Poly1305::new(self.key[..].into())
+                .decrypt_in_place(XNonce::from_slice(&encrypted.nonce), aad, &mut *in_out)
+                .map_err(|_| Error::Crypto("Failed to decrypt".into()))?,
+        }
+        Ok(in_out)
+    }
+
+    fn ring_key(&self, algo: &'static aead::Algorithm) -> Result<aead::LessSafeKey> {
+        let unbound_key = aead::UnboundKey::new(algo, &self.key[..])
+            .map_err(|_| Error::Crypto("Failed to create an unbound key".into()))?;
+        Ok(aead::LessSafeKey::new(unbound_key))
+    }
+}
+
+fn ring_nonce(nonce: &[u8]) -> Result<aead::Nonce> {
+    aead::Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| Error::Crypto("Bad nonce length".into()))
+}
+
+impl Aead {
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Aead::ChaCha20Poly1305 | Aead::Aes256Gcm => 96 / 8,
+            Aead::XChaCha20Poly1305 => 192 / 8,
+        }
+    }
+
+    fn check_nonce(self, nonce: &[u8]) -> Result<()> {
+        if nonce.len() != self.nonce_len() {
+            return Err(Error::Crypto(format!(
+                "{:?} needs a {} byte nonce, got {} bytes",
+                self,
+                self.nonce_len(),
+                nonce.len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// None for AEADs that ring doesn't implement.
+    fn ring_algorithm(self) -> Option<&'static aead::Algorithm> {
+        match self {
+            Aead::ChaCha20Poly1305 => Some(&aead::CHACHA20_POLY1305),
+            Aead::Aes256Gcm => Some(&aead::AES_256_GCM),
+            Aead::XChaCha20Poly1305 => None,
+        }
+    }
+}
+
+impl From<Aead> for u8 {
+    fn from(aead: Aead) -> u8 {
+        match aead {
+            Aead::ChaCha20Poly1305 => 0,
+            Aead::Aes256Gcm => 1,
+            Aead::XChaCha20Poly1305 => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for Aead {
+    type Error = String;
+
+    fn try_from(suite: u8) -> std::result::Result<Self, Self::Error> {
+        match suite {
+            0 => Ok(Aead::ChaCha20Poly1305),
+            1 => Ok(Aead::Aes256Gcm),
+            2 => Ok(Aead::XChaCha20Poly1305),
+            _ => Err(format!("unknown AEAD suite {}", suite)),
+        }
+    }
+}
+
+/// Plaintext is cut into chunks of this many bytes by `CryptoKey::encrypt_stream`.
+pub const STREAM_CHUNK_LEN: u32 = 64 * 1024;
+
+/// Chunks bigger than this are refused when decrypting, so a bad header can't exhaust memory.
+const MAX_STREAM_CHUNK_LEN: u32 = 16 * 1024 * 1024;
+
+const STREAM_VERSION: u8 = 1;
+
+/// The tag every AEAD we support appends to a ciphertext.
+const TAG_LEN: usize = 128 / 8;
+
+impl CryptoKey {
+    /// Encrypt everything read from `plaintext` to `out`, a chunk at a time.
+    ///
+    /// The stream starts with a header naming the AEAD, the chunk length and
+    /// a random nonce prefix. Each chunk's nonce is the prefix, the chunk's
+    /// index and a flag marking the final chunk, so dropping, reordering or
+    /// truncating chunks is caught by `decrypt_stream`. The nonce prefix is
+    /// random, use a key for as few streams as possible, eg. by deriving one per stream.
+    ///
+    /// Returns the number of plaintext bytes encrypted.
+    pub fn encrypt_stream(
+        &self,
+        aead: Aead,
+        plaintext: &mut impl Read,
+        out: &mut impl Write,
+    ) -> Result<u64> {
+        self.encrypt_stream_in_chunks(aead, STREAM_CHUNK_LEN, plaintext, out)
+    }
+
+    fn encrypt_stream_in_chunks(
+        &self,
+        aead: Aead,
+        chunk_len: u32,
+        plaintext: &mut impl Read,
+        out: &mut impl Write,
+    ) -> Result<u64> {
+        let nonce_prefix = rand_bytes(aead.nonce_len() - STREAM_NONCE_SUFFIX_LEN)?;
+        let mut header = vec![STREAM_VERSION, u8::from(aead)];
+        header.extend(chunk_len.to_be_bytes());
+        header.extend(&nonce_prefix);
+        out.write_all(&header)?;
+
+        let mut chunk = Zeroizing::new(vec![0u8; chunk_len as usize]);
+        let mut len = read_full(plaintext, &mut chunk)?;
+        let mut total = 0;
+        for index in 0u32.. {
+            // read ahead to learn whether this is the final chunk
+            let mut next = Zeroizing::new(vec![0u8; chunk_len as usize]);
+            let next_len = match len == chunk.len() {
+                true => read_full(plaintext, &mut next)?,
+                false => 0,
+            };
+            let last = next_len == 0;
+
+            let nonce = stream_nonce(&nonce_prefix, index, last);
+            let sealed = self.encrypt_with_nonce(aead, nonce, &chunk[..len], &header)?;
+            out.write_all(&sealed.ciphertext)?;
+            total += len as u64;
+
+            if last {
+                return Ok(total);
+            }
+            chunk = next;
+            len = next_len;
+        }
+        Err(Error::Crypto("Stream has too many chunks".into()))
+    }
+
+    /// Decrypt a stream written by `encrypt_stream` to `out`, a chunk at a time.
+    ///
+    /// Plaintext is written out as each chunk is authenticated, don't trust
+    /// what's been written unless this returns Ok.
+    ///
+    /// Returns the number of plaintext bytes decrypted.
+    pub fn decrypt_stream(&self, ciphertext: &mut impl Read, out: &mut impl Write) -> Result<u64> {
+        let mut fixed = [0u8; 6];
+        if read_full(ciphertext, &mut fixed)? != fixed.len() || fixed[0] != STREAM_VERSION {
+            return Err(Error::Crypto("Not an encrypted stream we can read".into()));
+        }
+        let aead = Aead::try_from(fixed[1]).map_err(Error::Crypto)?;
+        let chunk_len = u32::from_be_bytes([fixed[2], fixed[3], fixed[4], fixed[5]]);
+        if chunk_len == 0 || chunk_len > MAX_STREAM_CHUNK_LEN {
+            return Err(Error::Crypto(format!(
+                "Bad stream chunk length {}",
+                chunk_len
+            )));
+        }
+        let mut nonce_prefix = vec![0u8; aead.nonce_len() - STREAM_NONCE_SUFFIX_LEN];
+        if read_full(ciphertext, &mut nonce_prefix)? != nonce_prefix.len() {
+            return Err(Error::Crypto("Encrypted stream header is truncated".into()));
+        }
+        let header = [&fixed[..], &nonce_prefix[..]].concat();
+
+        let sealed_len = chunk_len as usize + TAG_LEN;
+        let mut chunk = vec![0u8; sealed_len];
+        let mut len = read_full(ciphertext, &mut chunk)?;
+        let mut total = 0;
+        for index in 0u32.. {
+            let mut next = vec![0u8; sealed_len];
+            let next_len = match len == sealed_len {
+                true => read_full(ciphertext, &mut next)?,
+                false => 0,
+            };
+            let last = next_len == 0;
+            if last && read_full(ciphertext, &mut [0u8; 1])? != 0 {
+                return Err(Error::Crypto("Encrypted stream has data after its final chunk".into()));
+            }
+
+            let encrypted = Encrypted {
+                aead,
+                nonce: stream_nonce(&nonce_prefix, index, last),
+                ciphertext: chunk[..len].to_vec(),
+            };
+            let plain = self.decrypt_with_aad(&encrypted, &header).map_err(|_| {
+                Error::Crypto("Encrypted stream is corrupt or truncated".into())
+            })?;
+            out.write_all(&plain)?;
+            total += plain.len() as u64;
+
+            if last {
+                return Ok(total);
+            }
+            chunk = next;
+            len = next_len;
+        }
+        Err(Error::Crypto("Stream has too many chunks".into()))
+    }
+}
+
+/// The chunk index and the final chunk flag take up the end of a stream chunk's nonce.
+const STREAM_NONCE_SUFFIX_LEN: usize = 4 + 1;
+
+fn stream_nonce(prefix: &[u8], index: u32, last: bool) -> Vec<u8> {
+    let mut nonce = prefix.to_vec();
+    nonce.extend(index.to_be_bytes());
+    nonce.push(last as u8);
+    nonce
+}
+
+/// Fill as much of `buf` as we can, less only if `reader` runs out.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::IO(e)),
+        }
+    }
+    Ok(filled)
+}
+
+impl NameCipher {
+    const TAG_LEN: usize = 128 / 8;
+
+    pub fn new(keys: &KeyHierarchy) -> Self {
+        NameCipher {
+            mac_key: hmac::Key::new(hmac::HMAC_SHA256, &keys.expand(b"name mac")[..]),
+            stream_key: hmac::Key::new(hmac::HMAC_SHA256, &keys.expand(b"name stream")[..]),
+        }
+    }
+
+    pub fn encrypt(&self, name: &[u8]) -> Vec<u8> {
+        let mac = hmac::sign(&self.mac_key, name);
+        let tag = &mac.as_ref()[..Self::TAG_LEN];
+        let mut encrypted = tag.to_vec();
+        encrypted.extend(self.apply_keystream(tag, name));
+        encrypted
+    }
+
+    pub fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>> {
+        if encrypted.len() < Self::TAG_LEN {
+            return Err(Error::Crypto("Encrypted name is too short".into()));
+        }
+        let (tag, ciphertext) = encrypted.split_at(Self::TAG_LEN);
+        let name = self.apply_keystream(tag, ciphertext);
+        if &hmac::sign(&self.mac_key, &name).as_ref()[..Self::TAG_LEN] != tag {
+            return Err(Error::Crypto("Failed to decrypt name".into()));
+        }
+        Ok(name)
+    }
+
+    fn apply_keystream(&self, tag: &[u8], bytes: &[u8]) -> Vec<u8> {
+        bytes
+            .chunks(256 / 8)
+            .enumerate()
+            .flat_map(|(block, chunk)| {
+                let mut ctx = hmac::Context::with_key(&self.stream_key);
+                ctx.update(tag);
+                ctx.update(&(block as u64).to_be_bytes());
+                let keystream = ctx.sign();
+                chunk
+                    .iter()
+                    .zip(keystream.as_ref())
+                    .map(|(b, k)| b ^ k)
+                    .collect::<Vec<u8>>()
+            })
+            .collect()
+    }
+}
+
+impl StorageCipher {
+    /// Storage keys are derived from `keys` under the "storage" namespace.
+    pub fn new(keys: &KeyHierarchy) -> Self {
+        let keys = keys.derive_child(b"storage");
+        StorageCipher {
+            mac_key: hmac::Key::new(hmac::HMAC_SHA256, &keys.expand(b"storage keys")[..]),
+            value_keys: keys.derive_child(b"storage values"),
+        }
+    }
+
+    /// What `key` is stored under, equal keys give equal results.
+    pub fn mac_key(&self, key: &[u8]) -> Vec<u8> {
+        hmac::sign(&self.mac_key, key).as_ref().to_vec()
+    }
+
+    pub fn seal(&self, stored_key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let encrypted = self
+            .value_keys
+            .key_for(stored_key)
+            .encrypt_with_aad(value, stored_key)?;
+        Ok(bincode::serialize(&encrypted)?)
+    }
+
+    pub fn open(&self, stored_key: &[u8], sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let encrypted: Encrypted = bincode::deserialize(sealed)?;
+        self.value_keys
+            .key_for(stored_key)
+            .decrypt_with_aad(&encrypted, stored_key)
+    }
+
+    /// Identifies these keys without revealing them, see `KeyHierarchy::key_check`.
+    pub fn key_check(&self) -> [u8; 256 / 8] {
+        self.value_keys.key_check()
+    }
+}
+
+impl SecretKey {
+    pub fn generate() -> Result<Self> {
+        Ok(SecretKey::from_bytes(&Zeroizing::new(rand_256()?)))
+    }
+
+    pub fn from_bytes(bytes: &[u8; 256 / 8]) -> Self {
+        SecretKey {
+            secret: x25519_dalek::StaticSecret::from(*bytes),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<[u8; 256 / 8]> {
+        Zeroizing::new(self.secret.to_bytes())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        x25519_dalek::PublicKey::from(&self.secret).to_bytes()
+    }
+
+    /// A backup of this key that can be written down on paper.
+    ///
+    /// The key and a 16 bit checksum are written out in Crockford's base32,
+    /// in dash separated groups of five characters.
+    pub fn to_recovery_phrase(&self) -> Zeroizing<String> {
+        let mut bytes = Zeroizing::new(self.to_bytes().to_vec());
+        let checksum = recovery_checksum(&bytes);
+        bytes.extend(checksum);
+
+        let mut phrase = Zeroizing::new(String::new());
+        for (chars, i) in (0..bytes.len() * 8).step_by(5).enumerate() {
+            if chars > 0 && chars % RECOVERY_GROUP_LEN == 0 {
+                phrase.push('-');
+            }
+            let digit = (0..5).fold(0, |digit, b| (digit << 1) | bit(&bytes, i + b));
+            phrase.push(CROCKFORD[digit as usize] as char);
+        }
+        phrase
+    }
+
+    /// Restore a key from its recovery phrase.
+    ///
+    /// Case, whitespace and dashes don't matter and the letters commonly
+    /// mistaken for digits are read as those digits.
+    pub fn from_recovery_phrase(phrase: &str) -> Result<Self> {
+        let mut bits = Zeroizing::new(Vec::new());
+        for c in phrase.chars() {
+            let c = match c.to_ascii_uppercase() {
+                '-' => continue,
+                c if c.is_whitespace() => continue,
+                'O' => '0',
+                'I' | 'L' => '1',
+                c => c,
+            };
+            let digit = CROCKFORD
+                .iter()
+                .position(|d| *d as char == c)
+                .ok_or_else(|| {
+                    Error::Parse(format!("'{}' can't appear in a recovery phrase", c))
+                })?;
+            bits.extend((0..5).rev().map(|b| (digit >> b) as u8 & 1));
+        }
+
+        let len = 256 / 8 + RECOVERY_CHECKSUM_LEN;
+        if bits.len() / 5 != (len * 8).div_ceil(5) {
+            return Err(Error::Parse("Recovery phrase has the wrong length".into()));
+        }
+        let mut bytes = Zeroizing::new(vec![0u8; len]);
+        for (i, bit) in bits.iter().take(len * 8).enumerate() {
+            bytes[i / 8] |= bit << (7 - i % 8);
+        }
+        let (key, checksum) = bytes.split_at(256 / 8);
+        if recovery_checksum(key) != checksum {
+            return Err(Error::Parse(
+                "Recovery phrase checksum doesn't match, check for typos".into(),
+            ));
+        }
+
+        let mut key_bytes = Zeroizing::new([0u8; 256 / 8]);
+        key_bytes.copy_from_slice(key);
+        Ok(SecretKey::from_bytes(&key_bytes))
+    }
+
+    /// Open data that was sealed to our public key.
+    pub fn open(&self, sealed: &Sealed, aad: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let ephemeral = x25519_dalek::PublicKey::from(sealed.ephemeral);
+        let shared = self.secret.diffie_hellman(&ephemeral);
+        if !shared.was_contributory() {
+            return Err(Error::Crypto("Sealed with a low order public key".into()));
+        }
+        sealing_key(shared.as_bytes(), &sealed.ephemeral, &self.public_key())
+            .decrypt_with_aad(&sealed.encrypted, aad)
+    }
+}
+
+/// Hashes everything read or written through it, for naming content by its
+/// hash while it's streamed.
+pub struct Hashing<T> {
+    inner: T,
+    hasher: Hasher,
+}
+
+enum Hasher {
+    Sha256(digest::Context),
+    Keyed(hmac::Context),
+}
 
-        cryptic
-            .ciphertext
-            .splice(0..plaintext.len(), plaintext.iter().cloned());
+impl<T> Hashing<T> {
+    /// Hash with SHA-256, anyone can tell which content the hash names.
+    pub fn sha256(inner: T) -> Self {
+        Hashing {
+            inner,
+            hasher: Hasher::Sha256(digest::Context::new(&digest::SHA256)),
+        }
+    }
+
+    /// Hash with a key derived from `keys`, only its holders can tell which content the hash names.
+    pub fn keyed(inner: T, keys: &KeyHierarchy) -> Self {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &keys.expand(b"content hash")[..]);
+        Hashing {
+            inner,
+            hasher: Hasher::Keyed(hmac::Context::with_key(&key)),
+        }
+    }
+
+    /// The hash of everything that went through.
+    pub fn finish(self) -> [u8; 256 / 8] {
+        let mut hash = [0u8; 256 / 8];
+        match self.hasher {
+            Hasher::Sha256(ctx) => hash.copy_from_slice(ctx.finish().as_ref()),
+            Hasher::Keyed(ctx) => hash.copy_from_slice(ctx.sign().as_ref()),
+        }
+        hash
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match &mut self.hasher {
+            Hasher::Sha256(ctx) => ctx.update(bytes),
+            Hasher::Keyed(ctx) => ctx.update(bytes),
+        }
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Crockford's base32 alphabet, it leaves out letters that look like digits.
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+const RECOVERY_GROUP_LEN: usize = 5;
+const RECOVERY_CHECKSUM_LEN: usize = 16 / 8;
+
+fn recovery_checksum(key: &[u8]) -> [u8; RECOVERY_CHECKSUM_LEN] {
+    let mut checksum = [0u8; RECOVERY_CHECKSUM_LEN];
+    checksum.copy_from_slice(&sha256(key)[..RECOVERY_CHECKSUM_LEN]);
+    checksum
+}
 
-        // TODO: sanity check, rm this once you've convinced yourself
-        assert_eq!(&cryptic.ciphertext[0..plaintext.len()], plaintext);
+/// The `i`th bit of `bytes`, most significant bit first, zero past the end.
+fn bit(bytes: &[u8], i: usize) -> u8 {
+    bytes.get(i / 8).map_or(0, |byte| (byte >> (7 - i % 8)) & 1)
+}
+
+/// Seal `plaintext` so that only the holder of the `recipient`'s secret key can open it.
+///
+/// A fresh ephemeral key is agreed with the recipient's key for each message.
+pub fn seal(recipient: &PublicKey, plaintext: &[u8], aad: &[u8]) -> Result<Sealed> {
+    let ephemeral_secret = SecretKey::generate()?;
+    let ephemeral = ephemeral_secret.public_key();
+    let shared = ephemeral_secret
+        .secret
+        .diffie_hellman(&x25519_dalek::PublicKey::from(*recipient));
+    if !shared.was_contributory() {
+        return Err(Error::Crypto("Can't seal to a low order public key".into()));
+    }
+    let encrypted =
+        sealing_key(shared.as_bytes(), &ephemeral, recipient).encrypt_with_aad(plaintext, aad)?;
+    Ok(Sealed {
+        ephemeral,
+        encrypted,
+    })
+}
+
+fn sealing_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> CryptoKey {
+    KeyHierarchy::from_secret(shared)
+        .derive_child(&[&ephemeral[..], &recipient[..]].concat())
+        .key_for(b"sealed")
+}
 
-        let unbound_key = aead::UnboundKey::new(algo, &self.key)
-            .map_err(|_| Error::Crypto("Failed to create an unbound key for sealing".into()))?;
+impl SigningKey {
+    pub fn generate() -> Result<Self> {
+        SigningKey::from_bytes(&rand_256()?)
+    }
 
-        let key = aead::LessSafeKey::new(unbound_key);
+    pub fn from_bytes(seed: &[u8; 256 / 8]) -> Result<Self> {
+        let pair = Ed25519KeyPair::from_seed_unchecked(seed)
+            .map_err(|_| Error::Crypto("Failed to create a signing key".into()))?;
+        Ok(SigningKey {
+            seed: Zeroizing::new(*seed),
+            pair,
+        })
+    }
 
-        key.seal_in_place_append_tag(
-            aead::Nonce::assume_unique_for_key(cryptic.nonce),
-            aead::Aad::empty(),
-            &mut cryptic.ciphertext, // plaintext (encrypted in place)
-        )
-        .map_err(|_| Error::Crypto("Failed to encrypt".into()))?;
+    pub fn to_bytes(&self) -> Zeroizing<[u8; 256 / 8]> {
+        self.seed.clone()
+    }
 
-        Ok(cryptic)
+    pub fn verifying_key(&self) -> VerifyingKey {
+        let mut key = [0u8; 256 / 8];
+        key.copy_from_slice(self.pair.public_key().as_ref());
+        key
     }
 
-    pub fn decrypt(&self, encrypted: &Encrypted) -> Result<Vec<u8>> {
-        let algo = &aead::CHACHA20_POLY1305;
+    pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        self.pair.sign(msg).as_ref().to_vec()
+    }
+}
 
-        let unbound_key = aead::UnboundKey::new(algo, &self.key)
-            .map_err(|_| Error::Crypto("Failed to create an unbound key for opening".into()))?;
-        let key = aead::LessSafeKey::new(unbound_key);
+/// Check that `signature` was made over `msg` by the holder of `key`'s signing key.
+pub fn verify(key: &VerifyingKey, msg: &[u8], signature: &[u8]) -> Result<()> {
+    signature::UnparsedPublicKey::new(&signature::ED25519, key)
+        .verify(msg, signature)
+        .map_err(|_| Error::Crypto("Bad signature".into()))
+}
 
-        let mut in_out = encrypted.ciphertext.clone();
-        let plain_with_tag = key
-            .open_in_place(
-                aead::Nonce::assume_unique_for_key(encrypted.nonce),
-                aead::Aad::empty(),
-                &mut in_out, // cyphertext (decrypted in place)
-            )
-            .map_err(|_| Error::Crypto("Failed to decrypt".into()))?;
+impl Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SigningKey")
+    }
+}
 
-        let plain = plain_with_tag[0..plain_with_tag.len() - algo.tag_len()].to_vec();
-        Ok(plain)
+impl Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretKey")
     }
 }
 
@@ -131,6 +948,40 @@ impl Debug for KeyHierarchy {
     }
 }
 
+impl Debug for StorageCipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StorageCipher")
+    }
+}
+
+impl Debug for CryptoKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CryptoKey")
+    }
+}
+
+/// A nonce for `aead` holding `counter`, unique as long as the counter never repeats under a key.
+pub fn counter_nonce(aead: Aead, counter: u64) -> Vec<u8> {
+    let mut nonce = vec![0u8; aead.nonce_len() - 8];
+    nonce.extend(counter.to_be_bytes());
+    nonce
+}
+
+/// SHA-256 of `bytes`.
+pub fn sha256(bytes: &[u8]) -> [u8; 256 / 8] {
+    let mut hash = [0u8; 256 / 8];
+    hash.copy_from_slice(digest::digest(&digest::SHA256, bytes).as_ref());
+    hash
+}
+
+pub fn rand_bytes(len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    SystemRandom::new()
+        .fill(&mut buf)
+        .map_err(|_| Error::Crypto(format!("Failed to generate {} bytes of random", len)))?;
+    Ok(buf)
+}
+
 pub fn rand_nonce() -> Result<[u8; 96 / 8]> {
     let mut buf = [0u8; 96 / 8];
     // TAI: Should this rng live in a session so we don't have to recreate it each time?
@@ -155,45 +1006,63 @@ mod test {
 
     impl PartialEq for KeyHierarchy {
         fn eq(&self, other: &Self) -> bool {
-            let mut self_expanded = [0u8; 256 / 8];
-            let mut other_expanded = [0u8; 256 / 8];
-            self.key
-                .expand(&[], hkdf::HKDF_SHA256)
-                .and_then(|okm| okm.fill(&mut self_expanded))
-                .unwrap();
-            other
-                .key
-                .expand(&[], hkdf::HKDF_SHA256)
-                .and_then(|okm| okm.fill(&mut other_expanded))
-                .unwrap();
-
-            self_expanded == other_expanded
+            self.expand(&[]) == other.expand(&[])
         }
     }
 
     #[test]
     fn kdf() {
         let kdf = KDF {
-            pbkdf2_iters: NonZeroU32::new(1000).unwrap(),
+            algorithm: KdfAlgorithm::Pbkdf2HmacSha256 {
+                iters: NonZeroU32::new(1000).unwrap(),
+            },
             salt: rand_256().unwrap(),
+            requires_keyfile: false,
         };
 
-        let root_key1 = kdf.derive_root(b"sssshh.. it's a secret");
-        let root_key2 = kdf.derive_root(b"sssshh.. it's a secret");
-        let imposter_key = kdf.derive_root(b"imposter!!");
+        let root_key1 = kdf.derive_root(b"sssshh.. it's a secret", None).unwrap();
+        let root_key2 = kdf.derive_root(b"sssshh.. it's a secret", None).unwrap();
+        let imposter_key = kdf.derive_root(b"imposter!!", None).unwrap();
 
         assert_eq!(root_key1, root_key2); // proof: kdf is deterministic
         assert_ne!(root_key1, imposter_key) // proof: varied password => varied key
     }
 
+    #[test]
+    fn legacy_kdf() {
+        let iters = NonZeroU32::new(1000).unwrap();
+        let salt = rand_256().unwrap();
+        // a KDF as it was serialized before KdfAlgorithm
+        let legacy_bytes = bincode::serialize(&(iters, salt)).unwrap();
+        let legacy: LegacyKDF = bincode::deserialize(&legacy_bytes).unwrap();
+        let kdf = KDF::from(legacy);
+
+        let mut root_key = [0u8; 256 / 8];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iters,
+            &salt,
+            b"password",
+            &mut root_key,
+        );
+        let legacy_root = KeyHierarchy {
+            prk: hkdf_extract(&salt, &root_key),
+        };
+
+        assert_eq!(kdf.derive_root(b"password", None).unwrap(), legacy_root);
+    }
+
     #[test]
     fn key_hierarchy() {
         let kdf = KDF {
-            pbkdf2_iters: NonZeroU32::new(1000).unwrap(),
+            algorithm: KdfAlgorithm::Pbkdf2HmacSha256 {
+                iters: NonZeroU32::new(1000).unwrap(),
+            },
             salt: rand_256().unwrap(),
+            requires_keyfile: false,
         };
 
-        let root_key = kdf.derive_root(b"pass");
+        let root_key = kdf.derive_root(b"pass", None).unwrap();
         let log_key = root_key.derive_child(b"log");
 
         assert_ne!(root_key, log_key);
@@ -220,11 +1089,14 @@ mod test {
     #[test]
     fn plaintext_encrypt_decrypt() {
         let kdf = KDF {
-            pbkdf2_iters: NonZeroU32::new(1000).unwrap(),
+            algorithm: KdfAlgorithm::Pbkdf2HmacSha256 {
+                iters: NonZeroU32::new(1000).unwrap(),
+            },
             salt: rand_256().unwrap(),
+            requires_keyfile: false,
         };
 
-        let root_key = kdf.derive_root(b"password");
+        let root_key = kdf.derive_root(b"password", None).unwrap();
 
         let msg = b"I kinda like you";
         let msg_id = [0u8, 1u8];
@@ -247,7 +1119,402 @@ mod test {
         assert_ne!(cryptic.ciphertext, cryptic2.ciphertext); // ciphertexts must differ!
 
         let decrypted_msg = key.decrypt(&cryptic).unwrap();
-        let decrypted_string = String::from_utf8(decrypted_msg).unwrap();
+        let decrypted_string = String::from_utf8(decrypted_msg.to_vec()).unwrap();
         assert_eq!(decrypted_string, "I kinda like you");
     }
+
+    #[test]
+    fn associated_data_must_match() {
+        let kdf = KDF {
+            algorithm: KdfAlgorithm::Pbkdf2HmacSha256 {
+                iters: NonZeroU32::new(1000).unwrap(),
+            },
+            salt: rand_256().unwrap(),
+            requires_keyfile: false,
+        };
+
+        let key = kdf.derive_root(b"password", None).unwrap().key_for(&[0u8]);
+
+        let cryptic = key.encrypt_with_aad(b"msg", b"position 1").unwrap();
+
+        assert_eq!(
+            *key.decrypt_with_aad(&cryptic, b"position 1").unwrap(),
+            b"msg"
+        );
+        assert!(key.decrypt_with_aad(&cryptic, b"position 2").is_err());
+        assert!(key.decrypt(&cryptic).is_err());
+    }
+
+    #[test]
+    fn each_aead_round_trips_and_records_its_suite() {
+        let key = KeyHierarchy::from_secret(b"secret").key_for(b"aead");
+
+        for aead in [
+            Aead::ChaCha20Poly1305,
+            Aead::Aes256Gcm,
+            Aead::XChaCha20Poly1305,
+        ] {
+            let cryptic = key.encrypt_with(aead, b"msg", b"aad").unwrap();
+            assert_eq!(cryptic.nonce.len(), aead.nonce_len());
+            assert_eq!(*key.decrypt_with_aad(&cryptic, b"aad").unwrap(), b"msg");
+            assert!(key.decrypt_with_aad(&cryptic, b"other aad").is_err());
+
+            let bytes = bincode::serialize(&cryptic).unwrap();
+            assert_eq!(bytes[0], u8::from(aead));
+            assert_eq!(bincode::deserialize::<Encrypted>(&bytes).unwrap(), cryptic);
+        }
+
+        // a ciphertext doesn't open under another suite
+        let mut cryptic = key
+            .encrypt_with(Aead::ChaCha20Poly1305, b"msg", b"")
+            .unwrap();
+        cryptic.aead = Aead::Aes256Gcm;
+        assert!(key.decrypt(&cryptic).is_err());
+        assert!(bincode::deserialize::<Encrypted>(&[9]).is_err());
+    }
+
+    #[test]
+    fn counter_nonces_make_encryption_deterministic() {
+        let key = KeyHierarchy::from_secret(b"secret").key_for(b"op 3");
+
+        for aead in [Aead::ChaCha20Poly1305, Aead::XChaCha20Poly1305] {
+            let nonce = counter_nonce(aead, 3);
+            assert_eq!(nonce.len(), aead.nonce_len());
+            assert_eq!(nonce[aead.nonce_len() - 1], 3);
+
+            let cryptic = key
+                .encrypt_with_nonce(aead, nonce.clone(), b"msg", b"")
+                .unwrap();
+            let again = key.encrypt_with_nonce(aead, nonce, b"msg", b"").unwrap();
+            assert_eq!(cryptic, again);
+            assert_eq!(*key.decrypt(&cryptic).unwrap(), b"msg");
+        }
+        assert!(
+            key.encrypt_with_nonce(Aead::Aes256Gcm, vec![0u8; 24], b"msg", b"")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn streams_round_trip_in_chunks() {
+        let key = KeyHierarchy::from_secret(b"secret").key_for(b"stream");
+
+        for aead in [Aead::ChaCha20Poly1305, Aead::XChaCha20Poly1305] {
+            for len in [0, 1, 16, 17, 48, 50] {
+                let plaintext: Vec<u8> = (0..len as u8).collect();
+                let mut sealed = Vec::new();
+                let written = key
+                    .encrypt_stream_in_chunks(aead, 16, &mut &plaintext[..], &mut sealed)
+                    .unwrap();
+                assert_eq!(written, len as u64);
+
+                let mut opened = Vec::new();
+                key.decrypt_stream(&mut &sealed[..], &mut opened).unwrap();
+                assert_eq!(opened, plaintext);
+            }
+        }
+
+        let plaintext = vec![7u8; 3 * STREAM_CHUNK_LEN as usize + 5];
+        let mut sealed = Vec::new();
+        key.encrypt_stream(Aead::Aes256Gcm, &mut &plaintext[..], &mut sealed)
+            .unwrap();
+        let mut opened = Vec::new();
+        key.decrypt_stream(&mut &sealed[..], &mut opened).unwrap();
+        assert_eq!(opened, plaintext);
+    }
+
+    #[test]
+    fn tampered_streams_are_caught() {
+        let key = KeyHierarchy::from_secret(b"secret").key_for(b"stream");
+        let plaintext = [7u8; 48];
+        let mut sealed = Vec::new();
+        key.encrypt_stream_in_chunks(Aead::ChaCha20Poly1305, 16, &mut &plaintext[..], &mut sealed)
+            .unwrap();
+        let header_len = 6 + 7;
+        let sealed_chunk = 16 + TAG_LEN;
+        assert_eq!(sealed.len(), header_len + 3 * sealed_chunk);
+
+        let opens = |bytes: &[u8]| key.decrypt_stream(&mut &bytes[..], &mut Vec::new());
+
+        // truncated at a chunk boundary
+        assert!(opens(&sealed[..header_len + 2 * sealed_chunk]).is_err());
+        // truncated mid chunk
+        assert!(opens(&sealed[..sealed.len() - 1]).is_err());
+        // chunks swapped
+        let mut swapped = sealed[..header_len].to_vec();
+        swapped.extend(&sealed[header_len + sealed_chunk..header_len + 2 * sealed_chunk]);
+        swapped.extend(&sealed[header_len..header_len + sealed_chunk]);
+        swapped.extend(&sealed[header_len + 2 * sealed_chunk..]);
+        assert!(opens(&swapped).is_err());
+        // chunk length changed in the header
+        let mut resized = sealed.clone();
+        resized[5] = 32;
+        assert!(opens(&resized).is_err());
+        // wrong key
+        let other = KeyHierarchy::from_secret(b"secret").key_for(b"other stream");
+        assert!(
+            other
+                .decrypt_stream(&mut &sealed[..], &mut Vec::new())
+                .is_err()
+        );
+
+        assert!(opens(&sealed).is_ok());
+    }
+
+    /// Reads `parts` one after the other, with an end of file between each.
+    struct Parts<'a> {
+        parts: Vec<&'a [u8]>,
+    }
+
+    impl Read for Parts<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.parts.first_mut() {
+                Some(&mut []) => {
+                    self.parts.remove(0);
+                    Ok(0)
+                }
+                Some(part) => part.read(buf),
+                None => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn data_after_a_streams_final_chunk_is_caught() {
+        let key = KeyHierarchy::from_secret(b"secret").key_for(b"stream");
+        let plaintext = [7u8; 40];
+        let mut sealed = Vec::new();
+        key.encrypt_stream_in_chunks(Aead::ChaCha20Poly1305, 16, &mut &plaintext[..], &mut sealed)
+            .unwrap();
+
+        let mut parts = Parts {
+            parts: vec![&sealed[..], b"trailing"],
+        };
+        assert!(key.decrypt_stream(&mut parts, &mut Vec::new()).is_err());
+
+        let mut opened = Vec::new();
+        let mut parts = Parts {
+            parts: vec![&sealed[..]],
+        };
+        key.decrypt_stream(&mut parts, &mut opened).unwrap();
+        assert_eq!(opened, plaintext);
+    }
+
+    #[test]
+    fn hashing_sees_what_goes_through_either_way() {
+        let mut read = Vec::new();
+        let mut reader = Hashing::sha256(&b"content"[..]);
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"content");
+        assert_eq!(reader.finish(), sha256(b"content"));
+
+        let keys = KeyHierarchy::from_secret(b"secret");
+        let mut writer = Hashing::keyed(Vec::new(), &keys);
+        writer.write_all(b"content").unwrap();
+        let keyed = writer.finish();
+        assert_ne!(keyed, sha256(b"content"));
+
+        let mut writer = Hashing::keyed(Vec::new(), &KeyHierarchy::from_secret(b"other"));
+        writer.write_all(b"content").unwrap();
+        assert_ne!(writer.finish(), keyed);
+    }
+
+    #[test]
+    fn sealed_data_opens_only_with_the_recipients_secret() {
+        let recipient = SecretKey::generate().unwrap();
+        let other = SecretKey::generate().unwrap();
+
+        let sealed = seal(&recipient.public_key(), b"msg", b"epoch 0").unwrap();
+        assert_eq!(*recipient.open(&sealed, b"epoch 0").unwrap(), b"msg");
+        assert!(recipient.open(&sealed, b"epoch 1").is_err());
+        assert!(other.open(&sealed, b"epoch 0").is_err());
+    }
+
+    #[test]
+    fn signatures_verify_only_with_the_signers_key() {
+        let signer = SigningKey::generate().unwrap();
+        let other = SigningKey::generate().unwrap();
+
+        let signature = signer.sign(b"msg");
+        assert!(verify(&signer.verifying_key(), b"msg", &signature).is_ok());
+        assert!(verify(&signer.verifying_key(), b"other msg", &signature).is_err());
+        assert!(verify(&other.verifying_key(), b"msg", &signature).is_err());
+
+        let keys = KeyHierarchy::from_secret(b"secret");
+        assert_eq!(
+            keys.signing_key().unwrap().verifying_key(),
+            keys.signing_key().unwrap().verifying_key()
+        );
+        let restored = SigningKey::from_bytes(&signer.to_bytes()).unwrap();
+        assert_eq!(restored.verifying_key(), signer.verifying_key());
+    }
+
+    #[test]
+    fn recovery_phrase_restores_the_key_and_catches_typos() {
+        let secret = SecretKey::from_bytes(&[7u8; 256 / 8]);
+        let phrase = secret.to_recovery_phrase();
+        assert_eq!(phrase.split('-').count(), 11);
+
+        let restored = SecretKey::from_recovery_phrase(&phrase).unwrap();
+        assert_eq!(restored.public_key(), secret.public_key());
+
+        let sloppy = phrase.to_lowercase().replace('-', " ").replace('0', "o");
+        let restored = SecretKey::from_recovery_phrase(&sloppy).unwrap();
+        assert_eq!(restored.public_key(), secret.public_key());
+
+        let mut typo = phrase.to_string();
+        let c = if typo.starts_with('A') { "B" } else { "A" };
+        typo.replace_range(0..1, c);
+        assert_matches::assert_matches!(
+            SecretKey::from_recovery_phrase(&typo),
+            Err(Error::Parse(_))
+        );
+        assert!(SecretKey::from_recovery_phrase(&phrase[..20]).is_err());
+        assert!(SecretKey::from_recovery_phrase("U").is_err());
+    }
+
+    #[test]
+    fn key_check_identifies_the_key() {
+        let kdf = KDF::generate(KdfAlgorithm::Pbkdf2HmacSha256 {
+            iters: NonZeroU32::new(1).unwrap(),
+        })
+        .unwrap();
+        let check = kdf.derive_root(b"password", None).unwrap().key_check();
+
+        assert_eq!(
+            kdf.derive_root(b"password", None).unwrap().key_check(),
+            check
+        );
+        assert_ne!(
+            kdf.derive_root(b"imposter!!", None).unwrap().key_check(),
+            check
+        );
+    }
+
+    #[test]
+    fn argon2id() {
+        let kdf = KDF::generate(KdfAlgorithm::Argon2id {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        })
+        .unwrap();
+
+        let root_key = kdf.derive_root(b"password", None).unwrap();
+        assert_eq!(root_key, kdf.derive_root(b"password", None).unwrap());
+        assert_ne!(root_key, kdf.derive_root(b"imposter!!", None).unwrap());
+
+        let pbkdf2 = KDF {
+            algorithm: KdfAlgorithm::Pbkdf2HmacSha256 {
+                iters: NonZeroU32::new(1).unwrap(),
+            },
+            salt: kdf.salt,
+            requires_keyfile: false,
+        };
+        assert_ne!(root_key, pbkdf2.derive_root(b"password", None).unwrap());
+    }
+
+    #[test]
+    fn bad_argon2id_params_are_an_error() {
+        let kdf = KDF::generate(KdfAlgorithm::Argon2id {
+            memory_kib: 64,
+            iterations: 0,
+            parallelism: 1,
+        })
+        .unwrap();
+        assert!(kdf.derive_root(b"password", None).is_err());
+    }
+
+    #[test]
+    fn calibrated_argon2id_records_its_params() {
+        let kdf = KDF::calibrate_argon2id(64, 1, Duration::from_millis(5)).unwrap();
+        assert_matches::assert_matches!(
+            kdf.algorithm,
+            KdfAlgorithm::Argon2id { memory_kib: 64, parallelism: 1, iterations } if iterations >= 1
+        );
+        assert!(kdf.derive_root(b"password", None).is_ok());
+    }
+
+    #[test]
+    fn keyfile_is_mixed_into_the_root_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let keyfile_path = dir.path().join("vault.key");
+        generate_keyfile(&keyfile_path).unwrap();
+        assert!(generate_keyfile(&keyfile_path).is_err());
+        let keyfile = std::fs::read(&keyfile_path).unwrap();
+
+        let kdf = KDF::generate(KdfAlgorithm::Pbkdf2HmacSha256 {
+            iters: NonZeroU32::new(1).unwrap(),
+        })
+        .unwrap();
+        let with_keyfile = kdf.clone().with_keyfile();
+
+        let root_key = with_keyfile
+            .derive_root(b"password", Some(&keyfile))
+            .unwrap();
+        assert_eq!(
+            root_key,
+            with_keyfile
+                .derive_root(b"password", Some(&keyfile))
+                .unwrap()
+        );
+        assert_ne!(root_key, kdf.derive_root(b"password", None).unwrap());
+
+        assert_matches::assert_matches!(
+            with_keyfile.derive_root(b"password", None),
+            Err(Error::MissingKeyfile)
+        );
+        assert_ne!(
+            root_key,
+            with_keyfile
+                .derive_root(b"password", Some(b"not the keyfile"))
+                .unwrap()
+        );
+        assert_matches::assert_matches!(
+            kdf.derive_root(b"password", Some(&keyfile)),
+            Err(Error::UnexpectedKeyfile)
+        );
+    }
+
+    #[test]
+    fn name_cipher_is_deterministic_and_authenticated() {
+        let kdf = KDF::generate(KdfAlgorithm::Pbkdf2HmacSha256 {
+            iters: NonZeroU32::new(1).unwrap(),
+        })
+        .unwrap();
+        let names = NameCipher::new(&kdf.derive_root(b"password", None).unwrap());
+        let imposter = NameCipher::new(&kdf.derive_root(b"imposter!!", None).unwrap());
+
+        let long_name = [7u8; 100];
+        let encrypted = names.encrypt(&long_name);
+        assert_eq!(encrypted, names.encrypt(&long_name));
+        assert_ne!(encrypted, names.encrypt(b"another name"));
+        assert_eq!(names.decrypt(&encrypted).unwrap(), long_name.to_vec());
+        assert!(imposter.decrypt(&encrypted).is_err());
+
+        let mut tampered = encrypted.clone();
+        tampered[20] ^= 1;
+        assert!(names.decrypt(&tampered).is_err());
+    }
+
+    #[test]
+    fn debug_output_never_shows_key_material() {
+        let kdf = KDF::generate(KdfAlgorithm::Pbkdf2HmacSha256 {
+            iters: NonZeroU32::new(1).unwrap(),
+        })
+        .unwrap();
+        let root_key = kdf.derive_root(b"password", None).unwrap();
+
+        assert_eq!(format!("{:?}", root_key), "KeyHierarchy");
+        assert_eq!(format!("{:?}", root_key.key_for(b"op")), "CryptoKey");
+        assert_eq!(
+            format!("{:?}", StorageCipher::new(&root_key)),
+            "StorageCipher"
+        );
+        assert_eq!(format!("{:?}", SecretKey::generate().unwrap()), "SecretKey");
+        assert_eq!(
+            format!("{:?}", SigningKey::generate().unwrap()),
+            "SigningKey"
+        );
+    }
 }
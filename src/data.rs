@@ -4,6 +4,7 @@ use crdts::{self, CmRDT, CvRDT, ResetRemove};
 use serde_derive::{Deserialize, Serialize};
 
 use crate::error::{Error, Result};
+use crate::log::AttachmentId;
 
 pub type Actor = u128;
 
@@ -16,6 +17,14 @@ pub enum Prim {
     Int(i64),
     Str(String),
     Blob(Vec<u8>),
+    BlobRef(BlobRef),
+}
+
+/// A large blob kept in the log's attachment store, see `DB::attach`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct BlobRef {
+    pub id: AttachmentId,
+    pub len: u64,
 }
 
 impl Eq for Prim {}
@@ -29,6 +38,7 @@ impl Hash for Prim {
             Prim::Int(i) => i.hash(state),
             Prim::Str(s) => s.hash(state),
             Prim::Blob(b) => b.hash(state),
+            Prim::BlobRef(b) => b.hash(state),
         }
     }
 }
@@ -44,6 +54,7 @@ pub enum Kind {
     Int,
     Str,
     Blob,
+    BlobRef,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -196,6 +207,19 @@ impl Data {
         }
     }
 
+    /// Every attachment referenced from this data, including from nested maps.
+    pub fn blob_refs(&self) -> Vec<BlobRef> {
+        let prims = match self {
+            Data::Nil => Vec::new(),
+            Data::Reg(r) => r.read().val,
+            Data::Set(s) => s.read().val.into_iter().collect(),
+            Data::Map(m) => {
+                return m.values().flat_map(|v| v.val.blob_refs()).collect();
+            }
+        };
+        prims.iter().filter_map(|p| p.to_blob_ref().ok()).collect()
+    }
+
     pub fn to_map(&self) -> Result<crdts::Map<(String, Kind), Box<Data>, Actor>> {
         match self {
             Data::Nil => Ok(crdts::Map::default()),
@@ -213,6 +237,7 @@ impl Prim {
             Prim::Int(_) => Kind::Int,
             Prim::Str(_) => Kind::Str,
             Prim::Blob(_) => Kind::Blob,
+            Prim::BlobRef(_) => Kind::BlobRef,
         }
     }
 
@@ -250,6 +275,13 @@ impl Prim {
             other => Err(Error::UnexpectedKind(Kind::Blob, other.kind())),
         }
     }
+
+    pub fn to_blob_ref(&self) -> Result<BlobRef> {
+        match self {
+            Prim::BlobRef(p) => Ok(*p),
+            other => Err(Error::UnexpectedKind(Kind::BlobRef, other.kind())),
+        }
+    }
 }
 
 impl Op {
@@ -275,6 +307,7 @@ impl Kind {
             Kind::Int => panic!("attempted to call default_data on Kind::Int"),
             Kind::Str => panic!("attempted to call default_data on Kind::Str"),
             Kind::Blob => panic!("attempted to call default_data on Kind::Blob"),
+            Kind::BlobRef => panic!("attempted to call default_data on Kind::BlobRef"),
         }
     }
 }
@@ -309,6 +342,12 @@ impl From<Vec<u8>> for Prim {
     }
 }
 
+impl From<BlobRef> for Prim {
+    fn from(p: BlobRef) -> Self {
+        Prim::BlobRef(p)
+    }
+}
+
 impl From<crdts::mvreg::Op<Prim, Actor>> for Op {
     fn from(op: crdts::mvreg::Op<Prim, Actor>) -> Self {
         Op::Reg(op)
@@ -1,28 +1,160 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
 use crdts::ctx::{AddCtx, ReadCtx, RmCtx};
-use crdts::CmRDT;
 
-use crate::data::{Actor, Data, Kind, Op};
-use crate::error::Result;
-use crate::log::{LogReplicable, TaggedOp};
+use crate::data::{Actor, BlobRef, Data, Kind, Op, Prim};
+use crate::error::{Error, Result};
+use crate::log::{
+    AttachmentId, AttachmentStore, BundleReplicable, Lockable, LogReadable, LogReplicable, TaggedOp,
+};
 use crate::map;
 
 pub type Map = map::Map<(String, Kind), Data, Actor>;
 pub type Entry = map::Entry<Data, Actor>;
 
-pub struct DB<L: LogReplicable<Actor, Map>> {
+pub struct DB<L: LogReadable<Actor, Map>> {
     log: L,
     map: Map,
+    remotes: BTreeMap<String, L::Remote>,
+    locked: bool,
+    idle_lock: Option<IdleLock<L>>,
+    pinned: BTreeSet<AttachmentId>, // attached but not yet seen in an entry, see `gc_attachments`
 }
 
-impl<L: LogReplicable<Actor, Map>> DB<L> {
+/// Locks the DB once it's gone unused for `timeout`, see `DB::lock_when_idle`.
+struct IdleLock<L> {
+    timeout: Duration,
+    last_used: Cell<Instant>,
+    lock_log: fn(&mut L),
+}
+
+impl<L: LogReadable<Actor, Map>> DB<L> {
     pub fn new(log: L, map: Map) -> Self {
-        DB { log, map }
+        DB {
+            log,
+            map,
+            remotes: BTreeMap::new(),
+            locked: false,
+            idle_lock: None,
+            pinned: BTreeSet::new(),
+        }
     }
 
     pub fn get(&self, key: &(String, Kind)) -> Result<ReadCtx<Option<Data>, Actor>> {
+        self.check_unlocked()?;
         self.map.get(key)
     }
 
+    pub fn iter(&self) -> Result<map::Iter<(String, Kind), Data, Actor>> {
+        self.check_unlocked()?;
+        self.map.iter()
+    }
+
+    /// Add a remote to the set of remotes we sync with, replacing any remote with the same name.
+    pub fn add_remote(&mut self, name: impl Into<String>, remote: L::Remote) -> Option<L::Remote> {
+        self.remotes.insert(name.into(), remote)
+    }
+
+    pub fn remove_remote(&mut self, name: &str) -> Option<L::Remote> {
+        self.remotes.remove(name)
+    }
+
+    pub fn remotes(&self) -> impl Iterator<Item = &str> {
+        self.remotes.keys().map(|name| name.as_str())
+    }
+
+    /// Fetch and apply the ops on `remote` without pushing ours.
+    ///
+    /// This is the only way read-only replicas can catch up.
+    pub fn pull(&mut self, remote: &L::Remote) -> Result<()> {
+        self.lock_if_idle();
+        self.check_unlocked()?;
+        self.log.pull(remote)?;
+        self.apply_new_ops()
+    }
+
+    pub fn pull_remote(&mut self, name: &str) -> Result<()> {
+        self.lock_if_idle();
+        self.check_unlocked()?;
+        let remote = self
+            .remotes
+            .get(name)
+            .ok_or_else(|| Error::State(format!("No remote named '{}'", name)))?;
+        self.log.pull(remote)?;
+        self.apply_new_ops()
+    }
+
+    /// Pull from every remote in the remote set, see `sync_all` for how failures are handled.
+    pub fn pull_all(&mut self) -> Result<()> {
+        self.lock_if_idle();
+        self.check_unlocked()?;
+        let mut failed = Vec::new();
+        for (name, remote) in self.remotes.iter() {
+            if let Err(e) = self.log.pull(remote) {
+                failed.push((name.clone(), e));
+            }
+        }
+        self.apply_new_ops()?;
+
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(Error::SyncFailed(failed)),
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked || self.is_idle()
+    }
+
+    /// Lock the DB if it's been idle for longer than its idle timeout, returning whether it's locked.
+    ///
+    /// Reads are refused as soon as the timeout passes, but our keys are only
+    /// dropped by the next write, sync or call to this. Apps should call this
+    /// from a timer to drop keys soon after the timeout.
+    pub fn lock_if_idle(&mut self) -> bool {
+        if !self.locked && self.is_idle() {
+            if let Some(idle_lock) = &self.idle_lock {
+                (idle_lock.lock_log)(&mut self.log);
+            }
+            self.map.lock();
+            self.locked = true;
+        }
+        self.locked
+    }
+
+    fn is_idle(&self) -> bool {
+        match &self.idle_lock {
+            Some(idle_lock) => idle_lock.last_used.get().elapsed() >= idle_lock.timeout,
+            None => false,
+        }
+    }
+
+    /// Fails with `Error::Locked` if we're locked, otherwise counts as using the DB.
+    fn check_unlocked(&self) -> Result<()> {
+        if self.is_locked() {
+            return Err(Error::Locked);
+        }
+        if let Some(idle_lock) = &self.idle_lock {
+            idle_lock.last_used.set(Instant::now());
+        }
+        Ok(())
+    }
+
+    fn apply_new_ops(&mut self) -> Result<()> {
+        // ops fetched from more than one remote are only handed to us once by the log
+        while let Some(tagged_op) = self.log.next()? {
+            self.map.apply(tagged_op.op().clone())?;
+            self.log.ack(&tagged_op)?;
+        }
+        Ok(())
+    }
+}
+
+impl<L: LogReplicable<Actor, Map>> DB<L> {
     pub fn update<F, O>(
         &mut self,
         key: (impl Into<String>, Kind),
@@ -33,36 +165,190 @@ impl<L: LogReplicable<Actor, Map>> DB<L> {
         F: FnOnce(&Data, AddCtx<Actor>) -> O,
         O: Into<Op>,
     {
+        self.lock_if_idle();
+        self.check_unlocked()?;
         let (key_str, key_kind) = key;
         let key = (key_str.into(), key_kind);
 
         let map_op = self.map.update(key, ctx, f)?;
         let tagged_op = self.log.commit(map_op)?;
-        self.map.apply(tagged_op.op().clone());
+        self.map.apply(tagged_op.op().clone())?;
         self.log.ack(&tagged_op)
     }
 
     pub fn rm(&mut self, key: (impl Into<String>, Kind), ctx: RmCtx<Actor>) -> Result<()> {
+        self.lock_if_idle();
+        self.check_unlocked()?;
         let (key_str, key_kind) = key;
         let key = (key_str.into(), key_kind);
 
         let op = self.map.rm(key, ctx);
         let tagged_op = self.log.commit(op)?;
-        self.map.apply(tagged_op.op().clone());
+        self.map.apply(tagged_op.op().clone())?;
         self.log.ack(&tagged_op)
     }
 
-    pub fn iter(&self) -> Result<map::Iter<(String, Kind), Data, Actor>> {
-        self.map.iter()
+    pub fn sync(&mut self, remote: &mut L::Remote) -> Result<()> {
+        self.lock_if_idle();
+        self.check_unlocked()?;
+        self.log.sync(remote)?;
+        self.apply_new_ops()
     }
 
-    pub fn sync(&mut self, remote: &mut L::Remote) -> Result<()> {
+    pub fn sync_remote(&mut self, name: &str) -> Result<()> {
+        self.lock_if_idle();
+        self.check_unlocked()?;
+        let remote = self
+            .remotes
+            .get_mut(name)
+            .ok_or_else(|| Error::State(format!("No remote named '{}'", name)))?;
         self.log.sync(remote)?;
+        self.apply_new_ops()
+    }
 
-        while let Some(tagged_op) = self.log.next()? {
-            self.map.apply(tagged_op.op().clone());
-            self.log.ack(&tagged_op)?;
+    /// Sync with every remote in the remote set.
+    ///
+    /// A remote failing to sync doesn't stop the others from syncing. Once
+    /// all remotes have been tried, every remote that failed is named in an
+    /// `Error::SyncFailed` along with its error.
+    pub fn sync_all(&mut self) -> Result<()> {
+        self.lock_if_idle();
+        self.check_unlocked()?;
+        let mut failed = Vec::new();
+        for (name, remote) in self.remotes.iter_mut() {
+            if let Err(e) = self.log.sync(remote) {
+                failed.push((name.clone(), e));
+            }
+        }
+        self.apply_new_ops()?;
+
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(Error::SyncFailed(failed)),
+        }
+    }
+}
+
+impl<L: LogReadable<Actor, Map> + Lockable> DB<L> {
+    /// Drop the key material held by the log, and by the map if its storage is encrypted.
+    ///
+    /// Reads and writes fail with `Error::Locked` until we're unlocked.
+    pub fn lock(&mut self) {
+        self.log.lock();
+        self.map.lock();
+        self.locked = true;
+    }
+
+    /// Recover our keys, `credential` must be for whoever opened the log.
+    pub fn unlock(&mut self, credential: L::Credential) -> Result<()> {
+        self.log.unlock(credential)?;
+        if self.map.is_locked() {
+            self.map.unlock(self.log.storage_cipher()?)?;
+        }
+        self.locked = false;
+        if let Some(idle_lock) = &self.idle_lock {
+            idle_lock.last_used.set(Instant::now());
         }
         Ok(())
     }
+
+    /// Lock the DB once it's gone unused for `timeout`, see `lock_if_idle`.
+    pub fn lock_when_idle(&mut self, timeout: Duration) {
+        self.idle_lock = Some(IdleLock {
+            timeout,
+            last_used: Cell::new(Instant::now()),
+            lock_log: L::lock,
+        });
+    }
+}
+
+impl<L: LogReplicable<Actor, Map> + BundleReplicable> DB<L> {
+    /// Sync through a bundle file instead of a remote.
+    ///
+    /// The bundle at `path` (if there is one) is imported, then overwritten
+    /// with a bundle of our own ops for the other device to pick up.
+    pub fn sync_bundle(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.lock_if_idle();
+        self.check_unlocked()?;
+        let path = path.as_ref();
+        if path.exists() {
+            self.log.import_bundle(path)?;
+            self.apply_new_ops()?;
+        }
+        self.log.export_bundle(path)
+    }
+}
+
+impl<L> DB<L>
+where
+    L: LogReplicable<Actor, Map> + AttachmentStore<Remote = <L as LogReadable<Actor, Map>>::Remote>,
+{
+    /// Store a large blob outside of the op log, returning a `Prim::BlobRef` to store in its place.
+    ///
+    /// Attachments are pushed with our ops but other devices only fetch them
+    /// when they're read. Bundles don't carry attachments. The attachment is
+    /// kept by `gc_attachments` until an entry refers to it or we're dropped.
+    pub fn attach(&mut self, data: &mut dyn Read) -> Result<Prim> {
+        self.lock_if_idle();
+        self.check_unlocked()?;
+        let (id, len) = self.log.put_attachment(data)?;
+        self.pinned.insert(id);
+        Ok(Prim::BlobRef(BlobRef { id, len }))
+    }
+
+    /// Write out an attachment, fetching it from our remotes if we don't have it yet.
+    pub fn read_attachment(&mut self, blob: &BlobRef, out: &mut dyn Write) -> Result<()> {
+        self.lock_if_idle();
+        self.check_unlocked()?;
+        let mut failures = Vec::new();
+        if !self.log.has_attachment(&blob.id)? {
+            for (name, remote) in self.remotes.iter() {
+                match self.log.fetch_attachment(remote, &blob.id) {
+                    Ok(()) => break,
+                    Err(e) => failures.push(format!("'{}': {}", name, e)),
+                }
+            }
+        }
+        if !self.log.has_attachment(&blob.id)? {
+            return Err(Error::State(format!(
+                "None of our remotes have this attachment ({})",
+                failures.join(", ")
+            )));
+        }
+
+        let len = self.log.read_attachment(&blob.id, out)?;
+        if len != blob.len {
+            return Err(Error::State(format!(
+                "Attachment is {} bytes, expected {}",
+                len, blob.len
+            )));
+        }
+        Ok(())
+    }
+
+    /// Drop the attachments that no entry refers to anymore, returning how many were dropped.
+    ///
+    /// Only our copies are dropped. Dropping attachments from remotes is out
+    /// of scope, they keep every attachment they were pushed. Attachments we
+    /// made with `attach` are kept until an entry refers to them, so one
+    /// that's about to be written isn't dropped.
+    pub fn gc_attachments(&mut self) -> Result<usize> {
+        self.lock_if_idle();
+        self.check_unlocked()?;
+        let mut referenced = BTreeSet::new();
+        for entry in self.map.iter()? {
+            let (_, data) = entry?;
+            referenced.extend(data.val.blob_refs().into_iter().map(|blob| blob.id));
+        }
+        self.pinned.retain(|id| !referenced.contains(id));
+
+        let mut removed = 0;
+        for id in self.log.attachments()? {
+            if !referenced.contains(&id) && !self.pinned.contains(&id) {
+                self.log.remove_attachment(&id)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
 }
@@ -1,5 +1,8 @@
+use std::collections::BTreeMap;
 use std::fmt::{self, Debug};
+use std::io::{Read, Write};
 use std::marker::PhantomData;
+use std::path::Path;
 /// An Encrypted Git Log
 /// Implementation wraps the unencypted git log with an encryption layer.
 use std::str::FromStr;
@@ -10,32 +13,225 @@ use serde_derive::{Deserialize, Serialize};
 use crdts::{Actor, CmRDT};
 use git2;
 
-use crate::crypto::{rand_256, Encrypted, KeyHierarchy};
-use crate::error::Result;
+use crate::crypto::{
+    self, rand_256, Aead, Encrypted, Hashing, KeyHierarchy, NameCipher, PublicKey, Sealed,
+    SecretKey, SigningKey, StorageCipher, VerifyingKey, Zeroizing, KDF,
+};
+use crate::error::{Error, Result};
 use crate::git_log;
-use crate::log::{LogReplicable, TaggedOp};
+use crate::log::{
+    AttachmentId, AttachmentStore, BundleReplicable, Lockable, LogReadable, LogReplicable, TaggedOp,
+};
 
 struct EncryptedCRDT<C: CmRDT> {
     phantom_crdt: PhantomData<C>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// An op as it's stored in the log.
+///
+/// Ops are serialized behind `OP_MAGIC`, a version byte and a byte of flags
+/// describing the payload (see `COMPRESSED`). Ops written before
+/// ops were versioned are just a salt and the ciphertext, they're read back as
+/// `LEGACY_OP_VERSION` ops under epoch 0, with no position bound to them.
+/// They're only accepted before an actor's first versioned op.
+#[derive(Debug, Clone)]
 struct EncryptedOp {
+    version: u8,
+    flags: u8,
+    epoch: u32, // the data key epoch this op was encrypted under
     salt: [u8; 256 / 8],
+    seq: u64, // position of this op in its actor's log, starting from 0
     op: Encrypted,
 }
 
+/// Marks versioned ops, legacy ops start with their random salt instead.
+const OP_MAGIC: [u8; 8] = *b"hermitop";
+
+/// The version of ops written before ops were versioned.
+const LEGACY_OP_VERSION: u8 = 0;
+
+/// The first versioned ops, they had no flags.
+const UNFLAGGED_OP_VERSION: u8 = 1;
+
+/// The op version we write, and the latest we know how to read.
+const OP_VERSION: u8 = 2;
+
+/// Flags the payload as zstd compressed, compression happens before padding and encryption.
+const COMPRESSED: u8 = 1;
+
 unsafe impl Send for EncryptedOp {}
 
 pub struct Log<A: Actor, C: CmRDT>
 where
     C::Op: serde::Serialize + serde::de::DeserializeOwned,
 {
-    root_key: KeyHierarchy,
-    actor_key: KeyHierarchy,
+    member: Option<Membership>,   // None if we were given a data key directly
+    data_keys: Vec<KeyHierarchy>, // indexed by epoch, new ops use the latest
+    writing: WriteOptions,        // how new ops are written
+    opaque: bool,                 // pad ops to hide their size
+    locked: Option<Locked>,       // set while our keys are dropped, see `lock`
     log: git_log::Log<A, EncryptedCRDT<C>>,
 }
 
+/// A vault opened only to read it, see `ReadOnlyLog::open`.
+///
+/// It has no `commit` or `push`, and never creates an actor branch or signs
+/// anything. That's only enforced on this device, the member it's opened as
+/// can still write to the vault through a `Log`, and so can whoever finds
+/// their credential on a lost reader device. Remove the member if that happens.
+pub struct ReadOnlyLog<A: Actor, C: CmRDT>
+where
+    C::Op: serde::Serialize + serde::de::DeserializeOwned,
+{
+    log: Log<A, C>,
+}
+
+/// What we remember about ourselves while locked, enough to check who unlocks us.
+#[derive(Debug)]
+struct Locked {
+    member: Option<String>, // our member id, None if we were given a data key directly
+}
+
+/// How we write new ops, ops record enough about how they were written to be read back.
+#[derive(Debug, Clone, Copy, Default)]
+struct WriteOptions {
+    aead: Aead,
+    compression: Option<i32>, // the zstd level new ops are compressed with
+    counter_nonces: bool,     // derive salts and nonces from op positions, not the rng
+}
+
+/// Settings for a new vault, they're recorded in the vault's header.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Passwords of the vault's members are stretched with this KDF.
+    pub kdf: KDF,
+    /// Ops are encrypted with this AEAD.
+    pub aead: Aead,
+    /// Hide actor ids, commit metadata and op sizes from whoever hosts our remotes.
+    pub opaque: bool,
+}
+
+/// What a member unlocks the vault with.
+#[derive(Debug)]
+pub enum Credential {
+    /// The member's password, their key is derived with the KDF in the vault's header.
+    Password(Zeroizing<Vec<u8>>),
+    /// The member's password and the vault's keyfile, for vaults whose KDF requires one.
+    PasswordAndKeyfile(Zeroizing<Vec<u8>>, Zeroizing<Vec<u8>>),
+    /// An X25519 secret key held by the member.
+    SecretKey(SecretKey),
+    /// The recovery phrase of the member's secret key, see `Log::recovery_phrase`.
+    RecoveryPhrase(Zeroizing<String>),
+}
+
+/// What we need to know about a new member to share the vault with them.
+#[derive(Debug)]
+pub enum MemberKey {
+    /// The new member's password, if the vault has a keyfile they'll need it too.
+    Password(Zeroizing<Vec<u8>>),
+    /// The public keys of a secret key held by the new member, see `MemberKey::public`.
+    PublicKey(PublicKey, VerifyingKey),
+}
+
+impl MemberKey {
+    /// What the holder of `secret` hands a member for them to add them to a vault.
+    pub fn public(secret: &SecretKey) -> Result<MemberKey> {
+        Ok(MemberKey::PublicKey(
+            secret.public_key(),
+            member_signing_key(secret)?.verifying_key(),
+        ))
+    }
+}
+
+/// The member we opened the vault as.
+#[derive(Debug)]
+struct Membership {
+    id: String, // see `Log::member_id`
+    secret: SecretKey,
+    keyfile: Option<Zeroizing<Vec<u8>>>, // needed to derive the keys of password members
+}
+
+/// The members of a vault and their copies of the vault's data keys, keyed by member id.
+///
+/// Each member has an X25519 key pair, the data keys are sealed to their
+/// public key. Password members keep their secret key in the keyring wrapped
+/// by their password, changing the password only rewraps this secret key.
+///
+/// Each rotation of the data key starts a new epoch, ops from older epochs
+/// stay readable. Removing a member rotates the data key so they can't read
+/// ops committed after their removal.
+///
+/// Anyone who can push to a remote can rewrite the keyring, so it's only
+/// used once it's authenticated, see `check_keyring`. The first epoch's data
+/// key is anchored to each member's secret, every later one is proven under
+/// the one before it, and the whole keyring and header are MACed under the
+/// latest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Keyring {
+    members: BTreeMap<String, Member>,
+    epoch_proofs: Vec<[u8; 256 / 8]>, // proofs of each epoch after the first, see `epoch_proof`
+    mac: [u8; 256 / 8],               // see `keyring_mac`
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Member {
+    public_key: PublicKey,
+    verifying_key: VerifyingKey, // checks the member's certificates of their actor keys
+    wrapped_secret: Option<Encrypted>, // None if the member holds their secret key
+    epochs: Vec<Sealed>,               // the data key of each epoch, sealed to `public_key`
+    // The first epoch's data key MACed under the member's secret, see
+    // `vault_anchor`. None for members added by their public key, the first
+    // time they open the vault on a device they trust the keyring they find.
+    anchor: Option<[u8; 256 / 8]>,
+}
+
+/// The keyring is stored as log metadata under this name.
+const KEYRING: &str = "keyring";
+
+/// Each device remembers the key check of the first epoch's data key under this name.
+const FIRST_KEY_CHECK: &str = "first_key_check";
+
+/// Each device keeps the key its copy of the vault is stored under, wrapped
+/// under the member's secret, under this name followed by a hash of the member id.
+const STORAGE_KEY: &str = "storage_key";
+
+/// The verifying key of an actor, certified by the member whose device it logs from.
+///
+/// Anyone who can push to a remote can rewrite the actor keys, an actor's
+/// key is only trusted while its certificate checks out under the
+/// verifying key of a member in the authenticated keyring.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ActorKey {
+    key: VerifyingKey,
+    member: String,       // the id of the certifying member
+    certificate: Vec<u8>, // see `actor_key_certificate`
+}
+
+/// The actor keys are stored as log metadata under this name, keyed by actor branch.
+const ACTOR_KEYS: &str = "actor_keys";
+
+/// How the vault is encrypted, stored unencrypted as log metadata.
+///
+/// Everything needed to turn a member's password back into their key is
+/// kept here, so a fresh clone and a password are enough to open the vault.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    aead: Aead,
+    kdf: KDF,
+    key_checks: BTreeMap<String, [u8; 256 / 8]>, // each password member's key check, by member id
+    opaque: bool,
+}
+
+/// In opaque vaults, ops are padded up to the next power of two of at least this many bytes.
+const MIN_PADDED_LEN: usize = 64;
+
+/// The header is stored as log metadata under this name.
+const HEADER: &str = "header";
+
+/// The latest header version we know how to read.
+const HEADER_VERSION: u32 = 1;
+
 pub struct LoggedOp<A: Actor, C: CmRDT>
 where
     C::Op: serde::Serialize + serde::de::DeserializeOwned,
@@ -74,26 +270,218 @@ where
 }
 
 impl EncryptedOp {
-    fn encrypt<C: CmRDT>(op: &C::Op, root: &KeyHierarchy) -> Result<Self>
+    fn encrypt<C: CmRDT>(
+        op: &C::Op,
+        epoch: u32,
+        root: &KeyHierarchy,
+        position: &Position,
+        writing: WriteOptions,
+        padded: bool,
+    ) -> Result<Self>
     where
         C::Op: serde::Serialize + serde::de::DeserializeOwned,
     {
-        let bytes = bincode::serialize(&op)?;
-        let salt = rand_256()?;
-        let crypto_key = root.key_for(&salt);
-        let op = crypto_key.encrypt(&bytes)?;
-        Ok(EncryptedOp { salt, op })
+        let mut flags = 0;
+        let mut bytes = Zeroizing::new(bincode::serialize(&op)?);
+        if let Some(level) = writing.compression {
+            bytes = Zeroizing::new(git_log::compress(&bytes, level)?);
+            flags |= COMPRESSED;
+        }
+        if padded {
+            bytes = pad(&bytes);
+        }
+        let aad = Self::associated_data(OP_VERSION, flags, position);
+        // with counter nonces each position gets its own key and the seq is its nonce
+        let aead = writing.aead;
+        let (salt, nonce) = match writing.counter_nonces {
+            true => (
+                crypto::sha256(&aad),
+                crypto::counter_nonce(aead, position.seq),
+            ),
+            false => (rand_256()?, crypto::rand_bytes(aead.nonce_len())?),
+        };
+        let op = root
+            .key_for(&salt)
+            .encrypt_with_nonce(aead, nonce, &bytes, &aad)?;
+        Ok(EncryptedOp {
+            version: OP_VERSION,
+            flags,
+            epoch,
+            salt,
+            seq: position.seq,
+            op,
+        })
     }
 
-    fn decrypt<C: CmRDT>(&self, root: &KeyHierarchy) -> Result<C::Op>
+    fn decrypt<C: CmRDT>(
+        &self,
+        root: &KeyHierarchy,
+        position: &Position,
+        padded: bool,
+    ) -> Result<C::Op>
     where
         C::Op: serde::Serialize + serde::de::DeserializeOwned,
     {
+        if self.version == LEGACY_OP_VERSION {
+            if position.after_versioned {
+                return Err(Error::Crypto(format!(
+                    "a legacy op can't follow a versioned op, it sits at #{}",
+                    position.seq
+                )));
+            }
+            let bytes = root.key_for(&self.salt).decrypt(&self.op)?;
+            return Ok(bincode::deserialize(&bytes)?);
+        }
+        if self.seq != position.seq {
+            return Err(Error::Crypto(format!(
+                "op claims to be #{} in its log but sits at #{}",
+                self.seq, position.seq
+            )));
+        }
         let crypto_key = root.key_for(&self.salt);
-        let bytes = crypto_key.decrypt(&self.op)?;
-        let op = bincode::deserialize(&bytes)?;
+        let aad = Self::associated_data(self.version, self.flags, position);
+        let bytes = crypto_key.decrypt_with_aad(&self.op, &aad)?;
+        let bytes = match padded {
+            true => unpad(&bytes)?,
+            false => &bytes[..],
+        };
+        let op = match self.flags & COMPRESSED != 0 {
+            true => bincode::deserialize(&Zeroizing::new(git_log::decompress(bytes)?))?,
+            false => bincode::deserialize(bytes)?,
+        };
         Ok(op)
     }
+
+    /// The op's position, and from version 2 its header, are bound to its ciphertext.
+    fn associated_data(version: u8, flags: u8, position: &Position) -> Vec<u8> {
+        let mut aad = position.associated_data();
+        if version != UNFLAGGED_OP_VERSION {
+            aad.extend([version, flags]);
+        }
+        aad
+    }
+}
+
+impl serde::Serialize for EncryptedOp {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::SerializeTuple;
+
+        let mut tuple = serializer.serialize_tuple(7)?;
+        tuple.serialize_element(&OP_MAGIC)?;
+        tuple.serialize_element(&self.version)?;
+        tuple.serialize_element(&self.flags)?;
+        tuple.serialize_element(&self.epoch)?;
+        tuple.serialize_element(&self.salt)?;
+        tuple.serialize_element(&self.seq)?;
+        tuple.serialize_element(&self.op)?;
+        tuple.end()
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for EncryptedOp {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        use serde::de::{Error as _, SeqAccess, Visitor};
+
+        struct OpVisitor;
+
+        fn next<'de, T: serde::Deserialize<'de>, S: SeqAccess<'de>>(
+            seq: &mut S,
+        ) -> std::result::Result<T, S::Error> {
+            seq.next_element()?
+                .ok_or_else(|| S::Error::custom("op ended early"))
+        }
+
+        impl<'de> Visitor<'de> for OpVisitor {
+            type Value = EncryptedOp;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an encrypted op")
+            }
+
+            fn visit_seq<S: SeqAccess<'de>>(
+                self,
+                mut seq: S,
+            ) -> std::result::Result<EncryptedOp, S::Error> {
+                let magic: [u8; 8] = next(&mut seq)?;
+                if magic != OP_MAGIC {
+                    // a legacy op, what we took for the magic is the start of its salt
+                    let rest_of_salt: [u8; 24] = next(&mut seq)?;
+                    let nonce: [u8; 96 / 8] = next(&mut seq)?;
+                    let mut salt = [0u8; 256 / 8];
+                    salt[..8].copy_from_slice(&magic);
+                    salt[8..].copy_from_slice(&rest_of_salt);
+                    return Ok(EncryptedOp {
+                        version: LEGACY_OP_VERSION,
+                        flags: 0,
+                        epoch: 0,
+                        salt,
+                        seq: 0,
+                        op: Encrypted {
+                            aead: Aead::ChaCha20Poly1305,
+                            nonce: nonce.to_vec(),
+                            ciphertext: next(&mut seq)?,
+                        },
+                    });
+                }
+
+                let version: u8 = next(&mut seq)?;
+                let flags = match version {
+                    UNFLAGGED_OP_VERSION => 0,
+                    OP_VERSION => next(&mut seq)?,
+                    _ => {
+                        return Err(S::Error::custom(format!(
+                            "op version {} is newer than we know how to read",
+                            version
+                        )));
+                    }
+                };
+                if flags & !COMPRESSED != 0 {
+                    return Err(S::Error::custom(format!("unknown op flags {:#x}", flags)));
+                }
+                Ok(EncryptedOp {
+                    version,
+                    flags,
+                    epoch: next(&mut seq)?,
+                    salt: next(&mut seq)?,
+                    seq: next(&mut seq)?,
+                    op: next(&mut seq)?,
+                })
+            }
+        }
+
+        // legacy ops have fewer fields than versioned ones, we stop reading when we're done
+        deserializer.deserialize_tuple(7, OpVisitor)
+    }
+}
+
+/// Where an op sits in the chain of commits of its actor's log.
+///
+/// The position is bound to the op's ciphertext as associated data, so ops
+/// can't be replayed or reordered across commits without `next()` noticing.
+struct Position {
+    actor: Vec<u8>,
+    parent: Option<git2::Oid>,
+    seq: u64,
+    after_versioned: bool, // whether the parent is a versioned op, legacy ops can't follow one
+}
+
+impl Position {
+    fn associated_data(&self) -> Vec<u8> {
+        let mut aad = Vec::new();
+        aad.extend((self.actor.len() as u64).to_be_bytes());
+        aad.extend(&self.actor);
+        match self.parent {
+            Some(parent) => aad.extend(parent.as_bytes()),
+            None => aad.extend([0u8; 20]),
+        }
+        aad.extend(self.seq.to_be_bytes());
+        aad
+    }
 }
 
 impl<A: Actor, C: CmRDT> TaggedOp<C> for LoggedOp<A, C>
@@ -111,7 +499,7 @@ where
     }
 }
 
-impl<A: Actor, C: CmRDT> LogReplicable<A, C> for Log<A, C>
+impl<A: Actor, C: CmRDT> LogReadable<A, C> for Log<A, C>
 where
     C::Op: Debug + serde::Serialize + serde::de::DeserializeOwned,
     A: Actor + Debug + ToString + FromStr + serde::Serialize,
@@ -120,16 +508,12 @@ where
     type Remote = git_log::Remote;
 
     fn next(&self) -> Result<Option<Self::LoggedOp>> {
+        self.unlocked()?;
         match self.log.next() {
             Ok(Some(encrypted_logged_op)) => {
                 let actor_bytes = bincode::serialize(encrypted_logged_op.actor())?;
-                let actor_key = self.root_key.derive_child(&actor_bytes);
-
-                let plaintext_op = {
-                    let encrypted_op = encrypted_logged_op.op();
-                    encrypted_op.decrypt::<C>(&actor_key)?
-                };
-
+                let position = self.position(actor_bytes, encrypted_logged_op.parent())?;
+                let plaintext_op = self.decrypt_logged(&encrypted_logged_op, &position)?;
                 Ok(Some(LoggedOp {
                     encrypted_logged_op,
                     plaintext_op,
@@ -141,11 +525,50 @@ where
     }
 
     fn ack(&mut self, logged_op: &Self::LoggedOp) -> Result<()> {
+        self.unlocked()?;
         self.log.ack(&logged_op.encrypted_logged_op)
     }
 
+    fn pull(&mut self, remote: &Self::Remote) -> Result<()> {
+        self.unlocked()?;
+        let pulled = self.log.pull(remote);
+        if meta_diverged(&pulled) {
+            // the keyring can't be authenticated until the quarantine is released
+            return pulled;
+        }
+        // another device may have rotated the data key
+        self.load_new_epochs()?;
+        pulled
+    }
+}
+
+impl<A: Actor, C: CmRDT> LogReplicable<A, C> for Log<A, C>
+where
+    C::Op: Debug + serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + Debug + ToString + FromStr + serde::Serialize,
+{
     fn commit(&mut self, op: C::Op) -> Result<Self::LoggedOp> {
-        let encrypted_op = EncryptedOp::encrypt::<C>(&op, &self.actor_key)?;
+        self.unlocked()?;
+        let actor = self
+            .log
+            .try_actor()
+            .ok_or_else(|| Error::State("Read-only replicas can't write to the log".into()))?;
+        if self.writing.counter_nonces {
+            // a position another device logged to would reuse its key and nonce
+            self.log.check_remotes_behind_tip()?;
+        }
+        let actor_bytes = bincode::serialize(actor)?;
+        let position = self.position(actor_bytes, self.log.tip()?)?;
+        let epoch = (self.data_keys.len() - 1) as u32;
+        let actor_key = self.actor_key(epoch, &position.actor)?;
+        let encrypted_op = EncryptedOp::encrypt::<C>(
+            &op,
+            epoch,
+            &actor_key,
+            &position,
+            self.writing,
+            self.opaque,
+        )?;
 
         let encrypted_logged_op = self.log.commit(encrypted_op)?;
         Ok(LoggedOp {
@@ -154,28 +577,1101 @@ where
         })
     }
 
+    fn push(&self, remote: &mut Self::Remote) -> Result<()> {
+        // branch names of opaque vaults are encrypted, we can't find ours while locked
+        self.unlocked()?;
+        self.log.push(remote)
+    }
+}
+
+impl<A: Actor, C: CmRDT> LogReadable<A, C> for ReadOnlyLog<A, C>
+where
+    C::Op: Debug + serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + Debug + ToString + FromStr + serde::Serialize,
+{
+    type LoggedOp = LoggedOp<A, C>;
+    type Remote = git_log::Remote;
+
+    fn next(&self) -> Result<Option<Self::LoggedOp>> {
+        LogReadable::next(&self.log)
+    }
+
+    fn ack(&mut self, logged_op: &Self::LoggedOp) -> Result<()> {
+        LogReadable::ack(&mut self.log, logged_op)
+    }
+
     fn pull(&mut self, remote: &Self::Remote) -> Result<()> {
-        self.log.pull(remote)
+        LogReadable::pull(&mut self.log, remote)
     }
+}
 
-    fn push(&self, remote: &mut Self::Remote) -> Result<()> {
-        self.log.push(remote)
+impl<A, C: CmRDT> Log<A, C>
+where
+    C::Op: serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + ToString + FromStr + serde::Serialize,
+{
+    /// Check the vault's whole history for corruption without changing anything.
+    ///
+    /// Every commit on the actor branches we know of is checked as in
+    /// `git_log::Log::verify_with`, and every op must decrypt and deserialize.
+    /// Like `Log::position`, an op's sequence number follows on from the one
+    /// stored in its parent op, legacy ops store 0.
+    pub fn verify(&self) -> Result<git_log::VerifyReport> {
+        self.unlocked()?;
+        self.log
+            .verify_with(|logged_op, seq| {
+                // a parent we can't read is reported on its own
+                let after_versioned = logged_op
+                    .parent()
+                    .and_then(|parent| self.log.op_at(parent).ok())
+                    .is_some_and(|parent_op| parent_op.version != LEGACY_OP_VERSION);
+                let position = Position {
+                    actor: bincode::serialize(logged_op.actor())?,
+                    parent: logged_op.parent(),
+                    seq,
+                    after_versioned,
+                };
+                self.decrypt_logged(logged_op, &position)?;
+                Ok(logged_op.op().seq)
+            })
+    }
+
+    /// Decrypt an op logged at `position`, see `Log::position`.
+    fn decrypt_logged(
+        &self,
+        logged_op: &git_log::LoggedOp<A, EncryptedCRDT<C>>,
+        position: &Position,
+    ) -> Result<C::Op> {
+        let encrypted_op = logged_op.op();
+        let actor_key = self.actor_key(encrypted_op.epoch, &position.actor)?;
+        encrypted_op.decrypt::<C>(&actor_key, position, self.opaque)
+    }
+}
+
+impl<A: Actor, C: CmRDT> Log<A, C>
+where
+    C::Op: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// Each actor encrypts their ops under their own key derived from the epoch's data key.
+    fn actor_key(&self, epoch: u32, actor: &[u8]) -> Result<KeyHierarchy> {
+        let data_key = self.data_keys.get(epoch as usize).ok_or_else(|| {
+            Error::Crypto(format!("op was encrypted under unknown key epoch {}", epoch))
+        })?;
+        Ok(data_key.derive_child(actor))
+    }
+
+    /// The key an attachment is encrypted under and the key its id is hashed with.
+    fn attachment_keys(
+        &self,
+        epoch: u32,
+        salt: &[u8],
+    ) -> Result<(crypto::CryptoKey, KeyHierarchy)> {
+        let data_key = self.data_keys.get(epoch as usize).ok_or_else(|| {
+            Error::Crypto(format!(
+                "attachment was encrypted under unknown key epoch {}",
+                epoch
+            ))
+        })?;
+        Ok((
+            data_key.derive_child(b"attachments").key_for(salt),
+            data_key.derive_child(b"attachment ids"),
+        ))
+    }
+
+    /// Open any epochs in the keyring that we don't hold data keys for yet.
+    fn load_new_epochs(&mut self) -> Result<()> {
+        if self.member.is_none() {
+            return Ok(());
+        }
+        let keyring = self.read_unverified_keyring()?;
+        let new_keys = self.check_keyring(&keyring)?;
+        self.data_keys.extend(new_keys);
+        if self.log.read_local(FIRST_KEY_CHECK)?.is_none() {
+            let first_key_check = self.data_keys[0].key_check();
+            self.log.write_local(FIRST_KEY_CHECK, &first_key_check)?;
+        }
+        // members may have come and gone, and with them the actor keys they certified
+        let trusted = self.certified_actor_keys(&keyring)?;
+        self.log.set_trusted_keys(trusted);
+        Ok(())
+    }
+
+    fn read_actor_keys(&self) -> Result<BTreeMap<String, ActorKey>> {
+        match self.log.read_meta(ACTOR_KEYS)? {
+            Some(bytes) => Ok(bincode::deserialize(&bytes)?),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// The actor keys certified by members of `keyring`, keyed by actor branch.
+    ///
+    /// `keyring` must be authenticated, keys certified by anyone else are dropped.
+    fn certified_actor_keys(&self, keyring: &Keyring) -> Result<BTreeMap<String, VerifyingKey>> {
+        let certified = self
+            .read_actor_keys()?
+            .into_iter()
+            .filter(|(branch, actor_key)| match keyring.members.get(&actor_key.member) {
+                Some(member) => crypto::verify(
+                    &member.verifying_key,
+                    &actor_key_certificate(branch, &actor_key.key),
+                    &actor_key.certificate,
+                )
+                .is_ok(),
+                None => false,
+            })
+            .map(|(branch, actor_key)| (branch, actor_key.key))
+            .collect();
+        Ok(certified)
+    }
+
+    /// Check the keyring was written by a holder of the vault's data keys.
+    ///
+    /// Returns the data keys of the epochs we don't hold yet. When we hold
+    /// none, the first epoch's must match our anchor and the one this device
+    /// first saw, each later epoch must be proven under the one before it, and
+    /// the keyring's MAC must check out under the latest.
+    fn check_keyring(&self, keyring: &Keyring) -> Result<Vec<KeyHierarchy>> {
+        let member = self.membership()?;
+        let ours = keyring
+            .members
+            .get(&member.id)
+            .ok_or_else(|| Error::Crypto(format!("{} is no longer a member", member.id)))?;
+        if ours.epochs.len() < self.data_keys.len()
+            || keyring.epoch_proofs.len() + 1 != ours.epochs.len()
+        {
+            return Err(forged_keyring());
+        }
+
+        let mut new_keys: Vec<KeyHierarchy> = Vec::new();
+        for (epoch, sealed) in ours.epochs.iter().enumerate().skip(self.data_keys.len()) {
+            let data_key = KeyHierarchy::from_secret(
+                &open_data_key(&member.secret, epoch as u32, sealed)?[..],
+            );
+            let authentic = match self.data_keys.iter().chain(&new_keys).last() {
+                Some(previous) => {
+                    keyring.epoch_proofs[epoch - 1]
+                        == epoch_proof(previous, epoch as u32, &data_key)?
+                }
+                None => {
+                    let seen_on_device = self.log.read_local(FIRST_KEY_CHECK)?;
+                    let anchored = match ours.anchor {
+                        Some(anchor) => anchor == vault_anchor(&member.secret, &data_key)?,
+                        // password members are anchored when they're added
+                        None => ours.wrapped_secret.is_none(),
+                    };
+                    anchored && seen_on_device.is_none_or(|check| check == data_key.key_check())
+                }
+            };
+            if !authentic {
+                return Err(forged_keyring());
+            }
+            new_keys.push(data_key);
+        }
+
+        let latest = self
+            .data_keys
+            .iter()
+            .chain(&new_keys)
+            .last()
+            .ok_or_else(forged_keyring)?;
+        if keyring.mac != self.keyring_mac(latest, keyring)? {
+            return Err(forged_keyring());
+        }
+        Ok(new_keys)
+    }
+
+    /// A MAC of the keyring's members and epoch proofs and of the header, under `data_key`.
+    fn keyring_mac(&self, data_key: &KeyHierarchy, keyring: &Keyring) -> Result<[u8; 256 / 8]> {
+        let header = self.log.read_meta(HEADER)?.unwrap_or_default();
+        let mut mac = Hashing::keyed(std::io::sink(), &data_key.derive_child(b"keyring mac"));
+        mac.write_all(&bincode::serialize(&(
+            &keyring.members,
+            &keyring.epoch_proofs,
+        ))?)?;
+        mac.write_all(&header)?;
+        Ok(mac.finish())
+    }
+
+    /// The keyring, once `check_keyring` has authenticated it.
+    fn read_keyring(&self) -> Result<Keyring> {
+        let keyring = self.read_unverified_keyring()?;
+        self.check_keyring(&keyring)?;
+        Ok(keyring)
+    }
+
+    /// The keyring as we find it, only good for finding our own entry before we hold any data keys.
+    fn read_unverified_keyring(&self) -> Result<Keyring> {
+        let bytes = self.log.read_meta(KEYRING)?.ok_or_else(no_keyring)?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    /// Write the keyring with a MAC under our latest data key, write the header first.
+    fn write_keyring(&mut self, mut keyring: Keyring) -> Result<()> {
+        let latest = self.data_keys.last().ok_or_else(no_keyring)?;
+        keyring.mac = self.keyring_mac(latest, &keyring)?;
+        self.log.write_meta(KEYRING, &bincode::serialize(&keyring)?)
+    }
+
+    fn read_header(&self) -> Result<Header> {
+        let bytes = self
+            .log
+            .read_meta(HEADER)?
+            .ok_or_else(|| Error::State("This vault has no header".into()))?;
+        let header: Header = bincode::deserialize(&bytes)?;
+        if header.version > HEADER_VERSION {
+            return Err(Error::State(format!(
+                "This vault was created with a newer header version ({}), we read up to {}",
+                header.version, HEADER_VERSION
+            )));
+        }
+        Ok(header)
+    }
+
+    fn write_header(&mut self, header: &Header) -> Result<()> {
+        self.log.write_meta(HEADER, &bincode::serialize(header)?)
+    }
+
+    fn membership(&self) -> Result<&Membership> {
+        self.unlocked()?;
+        self.member.as_ref().ok_or_else(no_keyring)
+    }
+
+    fn unlocked(&self) -> Result<()> {
+        match self.locked {
+            Some(_) => Err(Error::Locked),
+            None => Ok(()),
+        }
+    }
+
+    /// The position of an op following `parent` in an actor's log.
+    fn position(&self, actor: Vec<u8>, parent: Option<git2::Oid>) -> Result<Position> {
+        let (seq, after_versioned) = match parent {
+            Some(parent) => {
+                let parent_op = self.log.op_at(parent)?;
+                (parent_op.seq + 1, parent_op.version != LEGACY_OP_VERSION)
+            }
+            None => (0, false),
+        };
+        Ok(Position {
+            actor,
+            parent,
+            seq,
+            after_versioned,
+        })
+    }
+}
+
+impl<A, C: CmRDT> BundleReplicable for Log<A, C>
+where
+    C::Op: serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + ToString + FromStr,
+{
+    fn export_bundle(&mut self, path: &Path) -> Result<()> {
+        self.unlocked()?;
+        self.log.export_bundle(path)
+    }
+
+    fn import_bundle(&mut self, path: &Path) -> Result<()> {
+        self.unlocked()?;
+        let imported = self.log.import_bundle(path);
+        if meta_diverged(&imported) {
+            return imported;
+        }
+        self.load_new_epochs()?;
+        imported
+    }
+}
+
+/// Encrypted attachments start with the epoch and salt their key was derived from,
+/// followed by the attachment encrypted as a stream (see `CryptoKey::encrypt_stream`).
+///
+/// Attachment ids are keyed hashes of the plaintext, so whoever hosts our
+/// remotes can't tell which content an attachment holds from its id.
+impl<A: Actor, C: CmRDT> AttachmentStore for Log<A, C>
+where
+    C::Op: serde::Serialize + serde::de::DeserializeOwned,
+{
+    type Remote = git_log::Remote;
+
+    fn put_attachment(&mut self, data: &mut dyn Read) -> Result<(AttachmentId, u64)> {
+        self.unlocked()?;
+        let epoch = (self.data_keys.len() - 1) as u32;
+        let salt = rand_256()?;
+        let (key, id_key) = self.attachment_keys(epoch, &salt)?;
+
+        let mut writer = self.log.attachment_writer()?;
+        writer.write_all(&epoch.to_be_bytes())?;
+        writer.write_all(&salt)?;
+        let mut hashing = crypto::Hashing::keyed(data, &id_key);
+        let len = key.encrypt_stream(self.writing.aead, &mut hashing, &mut writer)?;
+        let id = hashing.finish();
+        writer.finish(&id)?;
+        Ok((id, len))
+    }
+
+    fn has_attachment(&self, id: &AttachmentId) -> Result<bool> {
+        self.log.has_attachment(id)
+    }
+
+    fn fetch_attachment(&self, remote: &git_log::Remote, id: &AttachmentId) -> Result<()> {
+        self.log.fetch_attachment(remote, id)
+    }
+
+    /// The decrypted content is checked against its id once it's all been written to `out`.
+    fn read_attachment(&self, id: &AttachmentId, out: &mut dyn Write) -> Result<u64> {
+        self.unlocked()?;
+        let mut reader = self.log.attachment_reader(id)?;
+        let mut epoch = [0u8; 32 / 8];
+        let mut salt = [0u8; 256 / 8];
+        reader.read_exact(&mut epoch)?;
+        reader.read_exact(&mut salt)?;
+        let (key, id_key) = self.attachment_keys(u32::from_be_bytes(epoch), &salt)?;
+
+        let mut hashing = crypto::Hashing::keyed(out, &id_key);
+        let len = key.decrypt_stream(&mut reader, &mut hashing)?;
+        if hashing.finish() != *id {
+            return Err(Error::Crypto("Attachment doesn't match its id".into()));
+        }
+        Ok(len)
+    }
+
+    fn attachments(&self) -> Result<Vec<AttachmentId>> {
+        self.log.attachments()
+    }
+
+    fn remove_attachment(&mut self, id: &AttachmentId) -> Result<()> {
+        self.log.remove_attachment(id)
+    }
+}
+
+/// Locking drops our member secret, every data key and the keys derived from
+/// them. Vaults opened with a data key through `new` can't be unlocked again.
+impl<A, C: CmRDT> Lockable for Log<A, C>
+where
+    C::Op: serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + ToString + FromStr + serde::Serialize + serde::de::DeserializeOwned,
+{
+    type Credential = Credential;
+
+    fn lock(&mut self) {
+        if self.locked.is_some() {
+            return;
+        }
+        self.locked = Some(Locked {
+            member: self.member.take().map(|member| member.id),
+        });
+        self.data_keys = Vec::new();
+        self.log.forget_keys();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.is_some()
+    }
+
+    fn unlock(&mut self, credential: Credential) -> Result<()> {
+        let id = match &self.locked {
+            Some(Locked { member: Some(id) }) => id.clone(),
+            Some(Locked { member: None }) => return Err(no_keyring()),
+            None => return Ok(()),
+        };
+        let header = self.read_header()?;
+        let membership = open_membership(&header, &self.read_unverified_keyring()?, credential)?;
+        if membership.id != id {
+            return Err(Error::Crypto(format!(
+                "The vault was locked by {}, not {}",
+                id, membership.id
+            )));
+        }
+
+        self.locked = None;
+        self.member = Some(membership);
+        self.load_new_epochs()?;
+        if header.opaque {
+            self.make_opaque();
+        }
+        self.sign_ops()
+    }
+
+    fn storage_cipher(&self) -> Result<StorageCipher> {
+        Log::storage_cipher(self)
+    }
+}
+
+impl<A, C: CmRDT> Lockable for ReadOnlyLog<A, C>
+where
+    C::Op: serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + ToString + FromStr + serde::Serialize + serde::de::DeserializeOwned,
+{
+    type Credential = Credential;
+
+    fn lock(&mut self) {
+        self.log.lock()
+    }
+
+    fn is_locked(&self) -> bool {
+        self.log.is_locked()
+    }
+
+    fn unlock(&mut self, credential: Credential) -> Result<()> {
+        self.log.unlock(credential)
+    }
+
+    fn storage_cipher(&self) -> Result<StorageCipher> {
+        self.log.storage_cipher()
+    }
+}
+
+impl<A, C: CmRDT> ReadOnlyLog<A, C>
+where
+    C::Op: serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + ToString + FromStr + serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// Open a vault for reading as whichever member `credential` belongs to.
+    pub fn open(repo: git2::Repository, credential: Credential) -> Result<Self> {
+        Ok(ReadOnlyLog {
+            log: Log::open_log(git_log::Log::replica(repo), credential)?,
+        })
+    }
+
+    /// The names of the vault's members.
+    pub fn members(&self) -> Result<Vec<String>> {
+        self.log.members()
+    }
+
+    /// See `Log::verify`.
+    pub fn verify(&self) -> Result<git_log::VerifyReport> {
+        self.log.verify()
     }
 }
 
 impl<A, C: CmRDT> Log<A, C>
 where
     C::Op: serde::Serialize + serde::de::DeserializeOwned,
-    A: Actor + serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + ToString + FromStr + serde::Serialize + serde::de::DeserializeOwned,
 {
+    /// Open a log whose ops are encrypted directly under `root_key`.
+    ///
+    /// Logs opened this way have no keyring, so the password can't be changed
+    /// without re-encrypting the log. Prefer `init` and `open`.
     pub fn new(actor: A, repo: git2::Repository, root_key: KeyHierarchy) -> Self {
-        let actor_bytes = bincode::serialize(&actor).unwrap();
-        let actor_key = root_key.derive_child(&actor_bytes);
-
         Log {
-            root_key,
-            actor_key,
+            member: None,
+            data_keys: vec![root_key],
+            writing: WriteOptions::default(),
+            opaque: false,
+            locked: None,
             log: git_log::Log::new(actor, repo),
         }
     }
+
+    /// Create a new vault with `name` as its only member.
+    pub fn init(
+        actor: A,
+        repo: git2::Repository,
+        name: &str,
+        credential: Credential,
+        settings: Settings,
+    ) -> Result<Self> {
+        let mut log = Log {
+            member: None,
+            data_keys: Vec::new(),
+            writing: WriteOptions::default(),
+            opaque: false,
+            locked: None,
+            log: git_log::Log::new(actor, repo),
+        };
+        if log.log.read_meta(HEADER)?.is_some() || log.log.read_meta(KEYRING)?.is_some() {
+            return Err(Error::State("This vault has already been initialized".into()));
+        }
+
+        let mut header = Header {
+            version: HEADER_VERSION,
+            aead: settings.aead,
+            kdf: settings.kdf,
+            key_checks: BTreeMap::new(),
+            opaque: settings.opaque,
+        };
+        let data_key = Zeroizing::new(rand_256()?);
+        log.data_keys.push(KeyHierarchy::from_secret(&data_key[..]));
+        log.writing.aead = header.aead;
+        if header.opaque {
+            log.make_opaque();
+        }
+        let id = log.member_id(name)?;
+
+        let (secret, wrapped_secret, keyfile) = match credential {
+            Credential::SecretKey(secret) => (secret, None, None),
+            Credential::RecoveryPhrase(phrase) => {
+                (SecretKey::from_recovery_phrase(&phrase)?, None, None)
+            }
+            Credential::Password(password) => {
+                let kek = header.kdf.derive_root(&password, None)?;
+                header.key_checks.insert(id.clone(), kek.key_check());
+                let secret = SecretKey::generate()?;
+                let wrapped = wrap_secret(&kek, &id, &secret)?;
+                (secret, Some(wrapped), None)
+            }
+            Credential::PasswordAndKeyfile(password, keyfile) => {
+                let kek = header.kdf.derive_root(&password, Some(&keyfile))?;
+                header.key_checks.insert(id.clone(), kek.key_check());
+                let secret = SecretKey::generate()?;
+                let wrapped = wrap_secret(&kek, &id, &secret)?;
+                (secret, Some(wrapped), Some(keyfile))
+            }
+        };
+        let member = Member {
+            public_key: secret.public_key(),
+            verifying_key: member_signing_key(&secret)?.verifying_key(),
+            wrapped_secret,
+            epochs: vec![seal_data_key(&secret.public_key(), 0, &data_key)?],
+            anchor: Some(vault_anchor(&secret, &log.data_keys[0])?),
+        };
+        let keyring = Keyring {
+            members: vec![(id.clone(), member)].into_iter().collect(),
+            epoch_proofs: Vec::new(),
+            mac: [0u8; 256 / 8],
+        };
+        log.write_header(&header)?;
+        log.write_keyring(keyring)?;
+
+        log.member = Some(Membership {
+            id,
+            secret,
+            keyfile,
+        });
+        log.init_storage_key()?;
+        log.sign_ops()?;
+        Ok(log)
+    }
+
+    /// Open a vault as whichever member `credential` belongs to.
+    pub fn open(actor: A, repo: git2::Repository, credential: Credential) -> Result<Self> {
+        Self::open_log(git_log::Log::new(actor, repo), credential)
+    }
+
+    fn open_log(log: git_log::Log<A, EncryptedCRDT<C>>, credential: Credential) -> Result<Self> {
+        let mut log = Log {
+            member: None,
+            data_keys: Vec::new(),
+            writing: WriteOptions::default(),
+            opaque: false,
+            locked: None,
+            log,
+        };
+        let header = log.read_header()?;
+        log.member = Some(open_membership(
+            &header,
+            &log.read_unverified_keyring()?,
+            credential,
+        )?);
+        log.load_new_epochs()?;
+        log.writing.aead = header.aead;
+        if header.opaque {
+            log.make_opaque();
+        }
+        log.init_storage_key()?;
+        log.sign_ops()?;
+        Ok(log)
+    }
+
+    /// Compress new ops with zstd at `level` before they're encrypted.
+    ///
+    /// How well an op compresses says something about its contents, in
+    /// opaque vaults this is blurred by padding ops to a power of two.
+    pub fn compress_ops(&mut self, level: i32) {
+        self.writing.compression = Some(level);
+    }
+
+    /// Derive the salt and nonce of new ops from their position in our log instead of the rng.
+    ///
+    /// Every position gets its own key, with the op's sequence number as its
+    /// nonce. Positions are only unique while our actor id is used by a single
+    /// device and our history is never rewritten, don't use this otherwise.
+    /// Commits are refused while a remote we've fetched from has a copy of our
+    /// log that isn't part of ours, but we can't see what we haven't fetched.
+    pub fn counter_nonces(&mut self) {
+        self.writing.counter_nonces = true;
+    }
+
+    /// The names of the vault's members.
+    pub fn members(&self) -> Result<Vec<String>> {
+        self.read_keyring()?
+            .members
+            .into_keys()
+            .map(|id| self.member_name(&id))
+            .collect()
+    }
+
+    /// Share the vault with a new member, they can read ops from every epoch.
+    pub fn add_member(&mut self, name: &str, key: MemberKey) -> Result<()> {
+        self.load_new_epochs()?;
+        let mut keyring = self.read_keyring()?;
+        let id = self.member_id(name)?;
+        if keyring.members.contains_key(&id) {
+            return Err(Error::State(format!("{} is already a member", name)));
+        }
+
+        let (public_key, verifying_key, wrapped_secret, anchor) = match key {
+            MemberKey::Password(password) => {
+                let mut header = self.read_header()?;
+                let keyfile = self.membership()?.keyfile.as_ref().map(|k| &k[..]);
+                let kek = header.kdf.derive_root(&password, keyfile)?;
+                header.key_checks.insert(id.clone(), kek.key_check());
+                self.write_header(&header)?;
+                let secret = SecretKey::generate()?;
+                let wrapped = wrap_secret(&kek, &id, &secret)?;
+                let anchor = vault_anchor(&secret, &self.data_keys[0])?;
+                let verifying_key = member_signing_key(&secret)?.verifying_key();
+                (secret.public_key(), verifying_key, Some(wrapped), Some(anchor))
+            }
+            MemberKey::PublicKey(public_key, verifying_key) => {
+                (public_key, verifying_key, None, None)
+            }
+        };
+        let member = Member {
+            public_key,
+            verifying_key,
+            wrapped_secret,
+            epochs: self.seal_data_keys(&keyring, &public_key)?,
+            anchor,
+        };
+        keyring.members.insert(id, member);
+        self.write_keyring(keyring)
+    }
+
+    /// Remove a member and rotate the data key so they can't read new ops.
+    ///
+    /// Returns the new epoch. The removed member can still read ops from
+    /// earlier epochs, they had the keys to those. We certify the keys of
+    /// their actors in their place so the ops they committed still verify.
+    pub fn remove_member(&mut self, name: &str) -> Result<u32> {
+        let id = self.member_id(name)?;
+        if self.membership()?.id == id {
+            return Err(Error::State("Can't remove ourselves from the vault".into()));
+        }
+        let mut keyring = self.read_keyring()?;
+        let certified = self.certified_actor_keys(&keyring)?;
+        if keyring.members.remove(&id).is_none() {
+            return Err(Error::State(format!("{} is not a member", name)));
+        }
+        let mut actor_keys = self.read_actor_keys()?;
+        let theirs: Vec<String> = actor_keys
+            .iter()
+            .filter(|(branch, actor_key)| actor_key.member == id && certified.contains_key(*branch))
+            .map(|(branch, _)| branch.clone())
+            .collect();
+        for branch in theirs.iter() {
+            let key = certified[branch];
+            actor_keys.insert(branch.clone(), self.certify_actor_key(branch, key)?);
+        }
+        if !theirs.is_empty() {
+            self.log
+                .write_meta(ACTOR_KEYS, &bincode::serialize(&actor_keys)?)?;
+        }
+        let mut header = self.read_header()?;
+        if header.key_checks.remove(&id).is_some() {
+            self.write_header(&header)?;
+        }
+        self.write_keyring(keyring)?;
+        self.rotate_data_key()
+    }
+
+    /// A recovery phrase for our secret key, write it down and keep it safe.
+    ///
+    /// It opens the vault as us through every key rotation, without our
+    /// password or keyfile. Follow up with `change_password` to pick a new password.
+    pub fn recovery_phrase(&self) -> Result<Zeroizing<String>> {
+        Ok(self.membership()?.secret.to_recovery_phrase())
+    }
+
+    /// A cipher for keeping the decrypted vault encrypted on disk, see `map::Map::encrypted`.
+    ///
+    /// It's derived from a random key kept on this device only, wrapped under
+    /// our secret key. Members we've removed hold the vault's older data keys
+    /// but not this key, so they can't read a copy of our storage. Logs opened
+    /// with `new` have no members, their storage keys derive from the root key.
+    pub fn storage_cipher(&self) -> Result<StorageCipher> {
+        self.unlocked()?;
+        let membership = match &self.member {
+            Some(membership) => membership,
+            None => return Ok(StorageCipher::new(&self.data_keys[0])),
+        };
+        let wrapped = self
+            .log
+            .read_local(&storage_key_name(&membership.id))?
+            .ok_or_else(|| Error::State("This device has no storage key for the vault".into()))?;
+        let storage_key = storage_key_wrapping(&membership.secret)
+            .decrypt_with_aad(&bincode::deserialize(&wrapped)?, membership.id.as_bytes())?;
+        Ok(StorageCipher::new(&KeyHierarchy::from_secret(&storage_key[..])))
+    }
+
+    /// Generate this device's storage key if it doesn't have one, see `storage_cipher`.
+    fn init_storage_key(&mut self) -> Result<()> {
+        let membership = self.membership()?;
+        let name = storage_key_name(&membership.id);
+        if self.log.read_local(&name)?.is_some() {
+            return Ok(());
+        }
+        let storage_key = Zeroizing::new(rand_256()?);
+        let wrapped = storage_key_wrapping(&membership.secret)
+            .encrypt_with_aad(&storage_key[..], membership.id.as_bytes())?;
+        self.log.write_local(&name, &bincode::serialize(&wrapped)?)
+    }
+
+    /// Take another device's version of vault metadata we changed concurrently.
+    ///
+    /// See `git_log::Log::release_meta_quarantine`, our concurrent changes are
+    /// lost. Members we added must be added again, and ops we committed under
+    /// a data key we rotated to become unreadable, to us as well as the others.
+    pub fn release_meta_quarantine(&mut self, name: &str) -> Result<()> {
+        self.unlocked()?;
+        self.log.release_meta_quarantine(name)?;
+        if self.member.is_some() {
+            self.data_keys = Vec::new();
+            self.load_new_epochs()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrap our secret key under a key derived from a new password.
+    pub fn change_password(&mut self, new_password: &[u8]) -> Result<()> {
+        let mut header = self.read_header()?;
+        let membership = self.membership()?;
+        let new_kek = header
+            .kdf
+            .derive_root(new_password, membership.keyfile.as_ref().map(|k| &k[..]))?;
+        let mut keyring = self.read_keyring()?;
+        let member = keyring
+            .members
+            .get_mut(&membership.id)
+            .ok_or_else(|| Error::Crypto(format!("{} is no longer a member", membership.id)))?;
+        if member.wrapped_secret.is_none() {
+            return Err(Error::State(format!(
+                "{} unlocks the vault with a secret key, not a password",
+                membership.id
+            )));
+        }
+        member.wrapped_secret = Some(wrap_secret(&new_kek, &membership.id, &membership.secret)?);
+        header
+            .key_checks
+            .insert(membership.id.clone(), new_kek.key_check());
+        self.write_header(&header)?;
+        self.write_keyring(keyring)
+    }
+
+    /// Start a new key epoch, ops committed from now on are encrypted under a fresh data key.
+    pub fn rotate_data_key(&mut self) -> Result<u32> {
+        self.load_new_epochs()?;
+        self.membership()?;
+        let mut keyring = self.read_keyring()?;
+        let epoch = self.data_keys.len() as u32;
+        let data_key = Zeroizing::new(rand_256()?);
+        let next_key = KeyHierarchy::from_secret(&data_key[..]);
+        let previous_key = self.data_keys.last().ok_or_else(no_keyring)?;
+        keyring
+            .epoch_proofs
+            .push(epoch_proof(previous_key, epoch, &next_key)?);
+        for member in keyring.members.values_mut() {
+            member
+                .epochs
+                .push(seal_data_key(&member.public_key, epoch, &data_key)?);
+        }
+        self.data_keys.push(next_key);
+        if let Err(e) = self.write_keyring(keyring) {
+            self.data_keys.pop();
+            return Err(e);
+        }
+        Ok(epoch)
+    }
+
+    /// Actor ids are encrypted under a key derived from the first epoch's data
+    /// key, it's the one key every member holds for the vault's lifetime.
+    fn make_opaque(&mut self) {
+        let names = NameCipher::new(&self.data_keys[0].derive_child(b"actor names"));
+        self.log.make_opaque(names);
+        self.opaque = true;
+    }
+
+    /// The key of a member's entries in the keyring and header.
+    ///
+    /// It's the member's name, in opaque vaults it's encrypted like actor ids
+    /// but under a key of its own.
+    fn member_id(&self, name: &str) -> Result<String> {
+        if !self.opaque {
+            return Ok(name.to_string());
+        }
+        Ok(git_log::to_hex(&self.member_names()?.encrypt(name.as_bytes())))
+    }
+
+    fn member_name(&self, id: &str) -> Result<String> {
+        if !self.opaque {
+            return Ok(id.to_string());
+        }
+        git_log::from_hex(id)
+            .and_then(|encrypted| self.member_names().ok()?.decrypt(&encrypted).ok())
+            .and_then(|name| String::from_utf8(name).ok())
+            .ok_or_else(|| Error::Crypto(format!("Failed to decrypt member name: {}", id)))
+    }
+
+    fn member_names(&self) -> Result<NameCipher> {
+        let first_key = self.data_keys.first().ok_or_else(no_keyring)?;
+        Ok(NameCipher::new(&first_key.derive_child(b"member names")))
+    }
+
+    /// Sign our ops and only accept ops signed by their actor's certified key.
+    ///
+    /// Our signing key is derived from our member secret and actor, so it's the
+    /// same on every open. We certify its verifying key the first time we open
+    /// the vault as this actor, see `ActorKey`.
+    fn sign_ops(&mut self) -> Result<()> {
+        self.log.require_signed_ops();
+        let actor = match self.log.try_actor() {
+            Some(actor) => actor.clone(),
+            // replicas only check signatures
+            None => return Ok(()),
+        };
+        let secret = self.membership()?.secret.to_bytes();
+        let signing_key = KeyHierarchy::from_secret(&secret[..])
+            .derive_child(&bincode::serialize(&actor)?)
+            .signing_key()?;
+        let key = signing_key.verifying_key();
+        let branch = self.log.actor_branch(&actor);
+        let mut trusted = self.certified_actor_keys(&self.read_keyring()?)?;
+        match trusted.get(&branch) {
+            Some(trusted_key) if *trusted_key == key => (),
+            Some(_) => {
+                return Err(Error::State(format!(
+                    "{} is already trusted with a different key",
+                    actor.to_string()
+                )));
+            }
+            None => {
+                // an uncertified key for our branch is replaced, no member wrote it
+                let mut actor_keys = self.read_actor_keys()?;
+                actor_keys.insert(branch.clone(), self.certify_actor_key(&branch, key)?);
+                self.log
+                    .write_meta(ACTOR_KEYS, &bincode::serialize(&actor_keys)?)?;
+                trusted.insert(branch, key);
+            }
+        }
+        self.log.set_trusted_keys(trusted);
+        self.log.sign_ops(signing_key);
+        Ok(())
+    }
+
+    /// Certify `key` as the verifying key of the actor logging to `branch`, as us.
+    fn certify_actor_key(&self, branch: &str, key: VerifyingKey) -> Result<ActorKey> {
+        let membership = self.membership()?;
+        let certificate =
+            member_signing_key(&membership.secret)?.sign(&actor_key_certificate(branch, &key));
+        Ok(ActorKey {
+            key,
+            member: membership.id.clone(),
+            certificate,
+        })
+    }
+
+    /// Seal every data key in `keyring` to `public_key`.
+    fn seal_data_keys(&self, keyring: &Keyring, public_key: &PublicKey) -> Result<Vec<Sealed>> {
+        let membership = self.membership()?;
+        let ours = keyring
+            .members
+            .get(&membership.id)
+            .ok_or_else(|| Error::Crypto(format!("{} is no longer a member", membership.id)))?;
+        ours.epochs
+            .iter()
+            .enumerate()
+            .map(|(epoch, sealed)| {
+                let data_key = open_data_key(&membership.secret, epoch as u32, sealed)?;
+                seal_data_key(public_key, epoch as u32, &data_key)
+            })
+            .collect()
+    }
+}
+
+fn no_keyring() -> Error {
+    Error::State("This log was not opened with a keyring".into())
+}
+
+/// Whether syncing quarantined some of the vault's metadata, see `git_log::Log::pull`.
+fn meta_diverged(synced: &Result<()>) -> bool {
+    match synced {
+        Err(Error::HistoryRewritten(branches)) => {
+            branches.iter().any(|branch| branch.starts_with("meta_"))
+        }
+        _ => false,
+    }
+}
+
+fn forged_keyring() -> Error {
+    Error::Crypto("The keyring failed authentication, it wasn't written by a member".into())
+}
+
+/// Proof that the data key of `epoch` was added by a holder of the `previous` epoch's data key.
+fn epoch_proof(
+    previous: &KeyHierarchy,
+    epoch: u32,
+    data_key: &KeyHierarchy,
+) -> Result<[u8; 256 / 8]> {
+    let mut proof = Hashing::keyed(std::io::sink(), &previous.derive_child(b"epoch proof"));
+    proof.write_all(&epoch.to_be_bytes())?;
+    proof.write_all(&data_key.key_check())?;
+    Ok(proof.finish())
+}
+
+/// Ties the vault's first data key to a member's secret, only the member and
+/// whoever generated their secret can produce it.
+fn vault_anchor(secret: &SecretKey, first_data_key: &KeyHierarchy) -> Result<[u8; 256 / 8]> {
+    let anchor_key =
+        KeyHierarchy::from_secret(&secret.to_bytes()[..]).derive_child(b"vault anchor");
+    let mut anchor = Hashing::keyed(std::io::sink(), &anchor_key);
+    anchor.write_all(&first_data_key.key_check())?;
+    Ok(anchor.finish())
+}
+
+/// The key a member certifies their actor keys with, derived from their secret.
+fn member_signing_key(secret: &SecretKey) -> Result<SigningKey> {
+    KeyHierarchy::from_secret(&secret.to_bytes()[..])
+        .derive_child(b"member signing key")
+        .signing_key()
+}
+
+/// What a member signs to certify `key` as the key of the actor logging to `branch`.
+fn actor_key_certificate(branch: &str, key: &VerifyingKey) -> Vec<u8> {
+    let mut msg = b"hermitdb actor key".to_vec();
+    msg.extend((branch.len() as u64).to_be_bytes());
+    msg.extend(branch.as_bytes());
+    msg.extend(key);
+    msg
+}
+
+fn storage_key_name(id: &str) -> String {
+    format!("{}_{}", STORAGE_KEY, git_log::to_hex(&crypto::sha256(id.as_bytes())))
+}
+
+/// The key a member's storage key is wrapped under on their devices.
+fn storage_key_wrapping(secret: &SecretKey) -> crypto::CryptoKey {
+    KeyHierarchy::from_secret(&secret.to_bytes()[..])
+        .derive_child(b"storage key")
+        .key_for(b"wrap")
+}
+
+/// The membership of whoever holds `secret`, if they're a member.
+fn find_member(keyring: &Keyring, secret: SecretKey) -> Option<Membership> {
+    keyring
+        .members
+        .iter()
+        .find(|(_, member)| member.public_key == secret.public_key())
+        .map(|(id, _)| Membership {
+            id: id.clone(),
+            secret,
+            keyfile: None,
+        })
+}
+
+/// Find the member that `credential` belongs to.
+fn open_membership(
+    header: &Header,
+    keyring: &Keyring,
+    credential: Credential,
+) -> Result<Membership> {
+    let membership = match credential {
+        Credential::Password(password) => {
+            Some(open_with_password(header, keyring, &password, None)?)
+        }
+        Credential::PasswordAndKeyfile(password, keyfile) => Some(open_with_password(
+            header,
+            keyring,
+            &password,
+            Some(keyfile),
+        )?),
+        Credential::SecretKey(secret) => find_member(keyring, secret),
+        Credential::RecoveryPhrase(phrase) => {
+            find_member(keyring, SecretKey::from_recovery_phrase(&phrase)?)
+        }
+    };
+    membership
+        .ok_or_else(|| Error::Crypto("No member of this vault matches, wrong password?".into()))
+}
+
+/// Find the password member whose key is derived from `password` and unwrap their secret key.
+fn open_with_password(
+    header: &Header,
+    keyring: &Keyring,
+    password: &[u8],
+    keyfile: Option<Zeroizing<Vec<u8>>>,
+) -> Result<Membership> {
+    let kek = header
+        .kdf
+        .derive_root(password, keyfile.as_ref().map(|k| &k[..]))?;
+    let key_check = kek.key_check();
+    let id = header
+        .key_checks
+        .iter()
+        .find(|(_, check)| **check == key_check)
+        .map(|(id, _)| id.clone())
+        .ok_or_else(|| match keyfile {
+            Some(_) => Error::Crypto("Wrong password or keyfile".into()),
+            None => Error::Crypto("Wrong password".into()),
+        })?;
+    let secret = keyring
+        .members
+        .get(&id)
+        .and_then(|member| member.wrapped_secret.as_ref())
+        .and_then(|wrapped| unwrap_secret(&kek, &id, wrapped).ok())
+        .ok_or_else(|| Error::Crypto(format!("The keyring entry of {} is corrupt", id)))?;
+    Ok(Membership {
+        id,
+        secret,
+        keyfile,
+    })
+}
+
+/// Frame `bytes` with their length and pad them with zeros up to a size bucket.
+fn pad(bytes: &[u8]) -> Zeroizing<Vec<u8>> {
+    let bucket = (bytes.len() + 4).next_power_of_two().max(MIN_PADDED_LEN);
+    let mut padded = Zeroizing::new(Vec::with_capacity(bucket));
+    padded.extend((bytes.len() as u32).to_be_bytes());
+    padded.extend(bytes);
+    padded.resize(bucket, 0);
+    padded
+}
+
+fn unpad(padded: &[u8]) -> Result<&[u8]> {
+    let mut len = [0u8; 4];
+    len.copy_from_slice(padded.get(..4).ok_or_else(bad_padding)?);
+    padded
+        .get(4..4 + u32::from_be_bytes(len) as usize)
+        .ok_or_else(bad_padding)
+}
+
+fn bad_padding() -> Error {
+    Error::Crypto("Padded op is malformed".into())
+}
+
+fn seal_data_key(public_key: &PublicKey, epoch: u32, data_key: &[u8; 256 / 8]) -> Result<Sealed> {
+    crypto::seal(public_key, data_key, &epoch.to_be_bytes())
+}
+
+fn open_data_key(
+    secret: &SecretKey,
+    epoch: u32,
+    sealed: &Sealed,
+) -> Result<Zeroizing<[u8; 256 / 8]>> {
+    let bytes = secret.open(sealed, &epoch.to_be_bytes())?;
+    let mut data_key = Zeroizing::new([0u8; 256 / 8]);
+    data_key.copy_from_slice(&bytes);
+    Ok(data_key)
+}
+
+fn wrap_secret(kek: &KeyHierarchy, id: &str, secret: &SecretKey) -> Result<Encrypted> {
+    kek.key_for(b"kek")
+        .encrypt_with_aad(&secret.to_bytes()[..], id.as_bytes())
+}
+
+fn unwrap_secret(kek: &KeyHierarchy, id: &str, wrapped: &Encrypted) -> Result<SecretKey> {
+    let bytes = kek.key_for(b"kek").decrypt_with_aad(wrapped, id.as_bytes())?;
+    let mut secret = Zeroizing::new([0u8; 256 / 8]);
+    secret.copy_from_slice(&bytes);
+    Ok(SecretKey::from_bytes(&secret))
 }
@@ -14,6 +14,15 @@ pub enum Error {
     Parse(String),
     Crypto(String),
     State(String),
+    SyncCancelled,
+    PushRejected(Vec<(String, String)>),
+    PullRefused(Vec<(String, Error)>), // each fetched branch we refused to take in, with why
+    SyncFailed(Vec<(String, Error)>), // each remote that failed, with why
+    HistoryRewritten(Vec<String>), // rewritten actors, and `meta_<name>` for diverged metadata
+    MissingKeyfile,
+    UnexpectedKeyfile,
+    BadSignature(String),
+    Locked,
     Bincode(bincode::Error),
     Git(git2::Error),
     IO(std::io::Error),
@@ -30,13 +39,35 @@ impl fmt::Display for Error {
             Error::BranchIsNotADirectReference =>
                 write!(f, "A branch reference isn't a direct ref to an oid"),
             Error::LogCommitDoesNotContainOp =>
-                write!(f, "Trees attached to commits in git are expected to have an 'op' entry"),
+                write!(f, "Trees attached to commits in git are expected to have an 'op' or 'op.zst' entry"),
             Error::Parse(s) =>
                 write!(f, "Parsing failed: {}", s),
             Error::Crypto(s) =>
                 write!(f, "Crypto failure: {}", s),
             Error::State(s) =>
                 write!(f, "Gitdb entered a bad state: {}", s),
+            Error::SyncCancelled =>
+                write!(f, "Sync was cancelled by the progress callback"),
+            Error::PushRejected(refs) =>
+                write!(f, "The remote rejected pushed refs (ref, reason): {:?}", refs),
+            Error::PullRefused(branches) => {
+                write!(f, "Refused to take in")?;
+                write_failures(f, branches)
+            }
+            Error::SyncFailed(remotes) => {
+                write!(f, "Failed to sync with")?;
+                write_failures(f, remotes)
+            }
+            Error::HistoryRewritten(actors) =>
+                write!(f, "The history of {:?} was rewritten, it's been quarantined", actors),
+            Error::MissingKeyfile =>
+                write!(f, "This key is derived from a password and a keyfile, but no keyfile was given"),
+            Error::UnexpectedKeyfile =>
+                write!(f, "This key is derived from a password alone, but a keyfile was given"),
+            Error::BadSignature(actor) =>
+                write!(f, "An op from {} isn't signed by the key we trust for them", actor),
+            Error::Locked =>
+                write!(f, "The vault is locked, unlock it to read or write"),
             Error::Bincode(e) => e.fmt(f),
             Error::Git(e) => e.fmt(f),
             Error::IO(e) => e.fmt(f),
@@ -45,6 +76,15 @@ impl fmt::Display for Error {
     }
 }
 
+/// Lists what failed, and why, after an error's opening words.
+fn write_failures(f: &mut fmt::Formatter, failures: &[(String, Error)]) -> fmt::Result {
+    for (i, (name, e)) in failures.iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        write!(f, "{} '{}' ({})", sep, name, e)?;
+    }
+    Ok(())
+}
+
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
@@ -55,6 +95,15 @@ impl std::error::Error for Error {
             Error::Parse(_) => None,
             Error::Crypto(_) => None,
             Error::State(_) => None,
+            Error::SyncCancelled => None,
+            Error::PushRejected(_) => None,
+            Error::PullRefused(_) => None,
+            Error::SyncFailed(_) => None,
+            Error::HistoryRewritten(_) => None,
+            Error::MissingKeyfile => None,
+            Error::UnexpectedKeyfile => None,
+            Error::BadSignature(_) => None,
+            Error::Locked => None,
             Error::Bincode(e) => Some(e),
             Error::Git(e) => Some(e),
             Error::IO(e) => Some(e),
@@ -1,21 +1,75 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fmt::{self, Debug};
+use std::fs;
+use std::io::{self, Read, Write};
 use std::marker::PhantomData;
+use std::path::Path;
 use std::str::FromStr;
 use std::string::ToString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use crdts::{Actor, CmRDT};
 use git2;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::crypto::{self, NameCipher, SigningKey, VerifyingKey};
 use crate::error::{Error, Result};
-use crate::log::{LogReplicable, TaggedOp};
+use crate::log::{
+    AttachmentId, AttachmentStore, BundleReplicable, LogReadable, LogReplicable, TaggedOp,
+};
 
 pub struct Log<A: Actor, C: CmRDT> {
-    actor: A,
+    actor: Option<A>, // None for read-only replicas, they never write to the log
     repo: git2::Repository,
+    opaque: Option<NameCipher>, // set if actor ids and commit metadata are hidden
+    compression: Option<i32>,   // the zstd level new ops are compressed with
+    signer: Option<SigningKey>, // set if we sign our ops
+    require_signatures: bool,   // reject ops not signed by their actor's trusted key
+    trust: Trust,
     phantom_crdt: PhantomData<C>,
 }
 
+/// Where the verifying keys of trusted actors come from, keyed by the name of their branch.
+enum Trust {
+    /// The trusted_actors metadata, cached until the metadata moves.
+    Metadata(RefCell<Option<BTreeMap<String, VerifyingKey>>>),
+    /// Keys given with `set_trusted_keys`, the metadata is ignored.
+    Given(BTreeMap<String, VerifyingKey>),
+}
+
+/// The verifying keys of trusted actors are stored as log metadata under this name.
+const TRUSTED_ACTORS: &str = "trusted_actors";
+
+/// Ops are stored under this name in their commit's tree.
+const OP: &str = "op";
+
+/// Ops compressed with zstd are stored under this name instead of `OP`.
+const COMPRESSED_OP: &str = "op.zst";
+
+/// Compressed ops that decompress to more than this many bytes are refused.
+const MAX_DECOMPRESSED_OP: u64 = 16 * 1024 * 1024;
+
+/// Attachments are split into blobs of this size, so we never hold a whole one in memory.
+pub const ATTACHMENT_CHUNK_LEN: usize = 1024 * 1024;
+
+/// Streams an attachment into the repo, see `Log::attachment_writer`.
+pub struct AttachmentWriter<'r> {
+    repo: &'r git2::Repository,
+    sig: git2::Signature<'static>,
+    buf: Vec<u8>,
+    chunks: Vec<git2::Oid>,
+}
+
+/// Streams an attachment out of the repo, see `Log::attachment_reader`.
+pub struct AttachmentReader<'r> {
+    repo: &'r git2::Repository,
+    chunks: std::vec::IntoIter<git2::Oid>,
+    chunk: Option<git2::Blob<'r>>,
+    pos: usize,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum Auth {
     None,
@@ -27,12 +81,68 @@ pub struct Remote {
     name: String,
     url: String,
     auth: Auth,
+    #[serde(skip)]
+    progress: Option<ProgressFn>,
+}
+
+/// Progress reported while a sync with a `Remote` is in flight.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    /// Transfer stats for a fetch.
+    Fetch {
+        received_objects: usize,
+        indexed_objects: usize,
+        total_objects: usize,
+        received_bytes: usize,
+    },
+    /// Transfer stats for a push, pushes can't be cancelled.
+    Push {
+        current: usize,
+        total: usize,
+        bytes: usize,
+    },
+    /// The remote's verdict on a pushed ref, `status` is None if the ref was updated.
+    PushUpdate {
+        refname: String,
+        status: Option<String>,
+    },
+}
+
+/// A progress callback attached to a `Remote`.
+///
+/// Returning `false` from the callback cancels an in-flight fetch. libgit2
+/// can't abort a push once it's sending, so what the callback returns while
+/// we push is ignored and the push runs to completion.
+#[derive(Clone)]
+pub struct ProgressFn {
+    callback: Arc<Mutex<ProgressCallback>>,
+    cancelled: Arc<AtomicBool>, // set once the callback asks to cancel the sync in flight
+}
+
+type ProgressCallback = dyn FnMut(&Progress) -> bool + Send;
+
+/// What `Log::verify` found, nothing is changed while verifying.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// How many commits held a good op.
+    pub ops: usize,
+    pub bad_commits: Vec<BadCommit>,
+}
+
+/// A commit that failed verification.
+#[derive(Debug)]
+pub struct BadCommit {
+    /// The ref we reached the commit through.
+    pub branch: String,
+    pub commit: git2::Oid,
+    pub error: Error,
 }
 
 #[derive(PartialEq, Eq, Serialize, Deserialize)]
 pub struct LoggedOp<A: Actor, C: CmRDT> {
     actor: A,
-    oid: Vec<u8>, // the object id of the commit with this op
+    oid: Vec<u8>,            // the object id of the commit with this op
+    parent: Option<Vec<u8>>, // the object id of the commit preceding this op
     op: C::Op,
 }
 
@@ -74,17 +184,23 @@ where
         &self.actor
     }
 
+    /// The commit preceding this op in its actor's log, None if this is the first op.
+    pub fn parent(&self) -> Option<git2::Oid> {
+        self.parent
+            .as_ref()
+            .map(|oid| git2::Oid::from_bytes(oid).unwrap())
+    }
+
     fn from_commit(actor: A, repo: &git2::Repository, commit: &git2::Commit) -> Result<Self> {
-        let tree = commit.tree()?;
-        let tree_entry = tree
-            .get_name("op")
-            .ok_or(Error::LogCommitDoesNotContainOp)?;
-        let id = tree_entry.id();
-        let blob = repo.find_blob(id)?;
-        let bytes = blob.content();
-        let op = bincode::deserialize(bytes)?;
+        let op = op_from_commit(repo, commit)?;
         let oid = commit.id().as_bytes().to_vec();
-        Ok(LoggedOp { actor, oid, op })
+        let parent = commit.parent_id(0).ok().map(|p| p.as_bytes().to_vec());
+        Ok(LoggedOp {
+            actor,
+            oid,
+            parent,
+            op,
+        })
     }
 
     fn next_from_branches(
@@ -92,7 +208,10 @@ where
         repo: &git2::Repository,
         unacked: Option<git2::Branch>,
         acked: Option<git2::Branch>,
-    ) -> Result<Option<LoggedOp<A, C>>> {
+    ) -> Result<Option<LoggedOp<A, C>>>
+    where
+        A: ToString,
+    {
         match (unacked, acked) {
             (Some(unacked), Some(acked)) => {
                 let local_unacked_oid = unacked
@@ -104,24 +223,28 @@ where
                     .target()
                     .ok_or(Error::BranchIsNotADirectReference)?;
 
-                if local_unacked_oid != local_acked_oid {
-                    let mut curr_oid = local_unacked_oid;
-                    let mut commit;
-                    loop {
-                        commit = repo.find_commit(curr_oid)?;
-                        let parents: Vec<git2::Oid> = commit.parent_ids().collect();
-                        assert_eq!(parents.len(), 1);
-                        if parents[0] == local_acked_oid {
-                            break;
-                        }
-                        curr_oid = parents[0];
-                    }
+                if local_unacked_oid == local_acked_oid
+                    || repo.graph_descendant_of(local_acked_oid, local_unacked_oid)?
+                {
+                    // nothing new here, we've already acked past this point
+                    return Ok(None);
+                }
 
-                    let op = LoggedOp::from_commit(actor, repo, &commit)?;
-                    Ok(Some(op))
-                } else {
-                    Ok(None)
+                let mut curr_oid = local_unacked_oid;
+                let mut commit;
+                loop {
+                    commit = repo.find_commit(curr_oid)?;
+                    let parents: Vec<git2::Oid> = commit.parent_ids().collect();
+                    match parents.as_slice() {
+                        [parent] if *parent == local_acked_oid => break,
+                        [parent] => curr_oid = *parent,
+                        // we walked off the end of the chain without finding our ack
+                        _ => return Err(Error::HistoryRewritten(vec![actor.to_string()])),
+                    }
                 }
+
+                let op = LoggedOp::from_commit(actor, repo, &commit)?;
+                Ok(Some(op))
             }
             (Some(unacked), None) => {
                 let mut curr_oid = unacked
@@ -132,12 +255,11 @@ where
                 loop {
                     commit = repo.find_commit(curr_oid)?;
                     let parents: Vec<git2::Oid> = commit.parent_ids().collect();
-                    if parents.is_empty() {
-                        break;
+                    match parents.as_slice() {
+                        [] => break,
+                        [parent] => curr_oid = *parent,
+                        _ => return Err(Error::HistoryRewritten(vec![actor.to_string()])),
                     }
-
-                    assert_eq!(parents.len(), 1);
-                    curr_oid = parents[0];
                 }
 
                 let op = LoggedOp::from_commit(actor, repo, &commit)?;
@@ -149,7 +271,7 @@ where
     }
 }
 
-impl<A, C: CmRDT> LogReplicable<A, C> for Log<A, C>
+impl<A, C: CmRDT> LogReadable<A, C> for Log<A, C>
 where
     C::Op: Debug + serde::Serialize + serde::de::DeserializeOwned,
     A: Actor + Debug + ToString + FromStr,
@@ -158,15 +280,18 @@ where
     type Remote = Remote;
 
     fn next(&self) -> Result<Option<Self::LoggedOp>> {
-        let local_name = format!("actor_{}", self.actor.to_string());
-        let local_acked = format!("acked_actor_{}", self.actor.to_string());
+        if let Some(actor) = &self.actor {
+            let local_name = self.actor_branch(actor);
+            let local_acked = format!("acked_{}", local_name);
 
-        let unacked = self.repo.find_branch(&local_name, git2::BranchType::Local);
-        let acked = self.repo.find_branch(&local_acked, git2::BranchType::Local);
-        if let Some(op) =
-            LoggedOp::next_from_branches(self.actor.clone(), &self.repo, unacked.ok(), acked.ok())?
-        {
-            return Ok(Some(op));
+            let unacked = self.repo.find_branch(&local_name, git2::BranchType::Local);
+            let acked = self.repo.find_branch(&local_acked, git2::BranchType::Local);
+            if let Some(op) =
+                LoggedOp::next_from_branches(actor.clone(), &self.repo, unacked.ok(), acked.ok())?
+            {
+                self.check_signature(&op)?;
+                return Ok(Some(op));
+            }
         }
 
         // we have no local unacked ops, check for remote ops
@@ -187,19 +312,20 @@ where
                 let split: Vec<&str> = branch_name.split("/actor_").collect();
                 println!("branch_name split: {:?}", split);
                 let actor: A = match split.as_slice() {
-                    [_, s] => s.parse().map_err(|_| {
-                        Error::Parse(format!("Failed to parse actor from branch: {}", s))
-                    })?,
+                    [_, s] => self.parse_actor(s)?,
                     _ => continue,
                 };
                 println!("actor {:?}", actor.to_string());
                 actor
             };
 
-            let tracking_branch = self.repo.find_branch(
-                &format!("actor_{}", actor.to_string()),
-                git2::BranchType::Local,
-            );
+            if self.is_quarantined(&actor)? {
+                continue;
+            }
+
+            let tracking_branch = self
+                .repo
+                .find_branch(&self.actor_branch(&actor), git2::BranchType::Local);
 
             let next_op = LoggedOp::next_from_branches(
                 actor,
@@ -209,6 +335,7 @@ where
             )?;
 
             if let Some(op) = next_op {
+                self.check_signature(&op)?;
                 return Ok(Some(op));
             }
         }
@@ -231,10 +358,10 @@ where
             }
         }
 
-        let branch_name: String = if logged_op.actor == self.actor {
-            format!("acked_actor_{}", logged_op.actor.to_string())
+        let branch_name: String = if Some(&logged_op.actor) == self.actor.as_ref() {
+            format!("acked_{}", self.actor_branch(&logged_op.actor))
         } else {
-            format!("actor_{}", logged_op.actor.to_string())
+            self.actor_branch(&logged_op.actor)
         };
 
         let commit = self.repo.find_commit(logged_op.id())?;
@@ -243,8 +370,23 @@ where
         Ok(())
     }
 
+    fn pull(&mut self, remote: &Self::Remote) -> Result<()> {
+        let tips_before_fetch = self.remote_actor_tips(&remote.name)?;
+        fetch(&self.repo, remote)?;
+        let mut rewritten = self.fast_forward_meta(&remote.name)?;
+        rewritten.extend(self.quarantine_rewritten(&remote.name, &tips_before_fetch)?);
+        rewritten_error(rewritten)
+    }
+}
+
+impl<A, C: CmRDT> LogReplicable<A, C> for Log<A, C>
+where
+    C::Op: Debug + serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + Debug + ToString + FromStr,
+{
     fn commit(&mut self, op: C::Op) -> Result<Self::LoggedOp> {
-        let name = format!("actor_{}", self.actor.to_string());
+        let actor = self.writer()?.clone();
+        let name = self.actor_branch(&actor);
         let parent = match self.repo.find_branch(&name, git2::BranchType::Local) {
             Ok(branch) => {
                 let target = branch
@@ -257,14 +399,28 @@ where
             _ => None,
         };
 
-        let op_bytes = bincode::serialize(&op)?;
+        let mut op_bytes = bincode::serialize(&op)?;
+        let mut op_name = OP;
+        if let Some(level) = self.compression {
+            op_bytes = compress(&op_bytes, level)?;
+            op_name = COMPRESSED_OP;
+        }
         let op_oid = self.repo.blob(&op_bytes)?;
         let mut builder = self.repo.treebuilder(None)?;
-        builder.insert("op", op_oid, 0o100_644)?; // TODO: what is this constant?
+        builder.insert(op_name, op_oid, 0o100_644)?; // TODO: what is this constant?
+        if let Some(signer) = &self.signer {
+            let msg = signed_message(
+                parent.as_ref().map(|p| p.id()),
+                op_name == COMPRESSED_OP,
+                &op_bytes,
+            );
+            let sig_oid = self.repo.blob(&signer.sign(&msg))?;
+            builder.insert("sig", sig_oid, 0o100_644)?;
+        }
         let tree_oid = builder.write()?;
         let tree = self.repo.find_tree(tree_oid)?;
 
-        let sig = self.repo.signature()?;
+        let sig = self.signature()?;
 
         let mut parent_commits = Vec::new();
         if let Some(ref commit) = parent {
@@ -283,73 +439,428 @@ where
             &parent_commits,
         )?;
 
-        LoggedOp::from_commit(
-            self.actor.clone(),
-            &self.repo,
-            &self.repo.find_commit(commit_oid)?,
-        )
+        LoggedOp::from_commit(actor, &self.repo, &self.repo.find_commit(commit_oid)?)
     }
 
-    fn pull(&mut self, remote: &Self::Remote) -> Result<()> {
-        println!("fetching remote: {}", &remote.name);
+    fn push(&self, remote: &mut Self::Remote) -> Result<()> {
+        self.writer()?;
+        let mut branches = self.moved_branches(&remote.name)?;
+        let attachments = self.unpushed_attachments(&remote.name)?;
+        branches.extend(attachments.iter().map(attachment_ref));
+        if branches.is_empty() {
+            println!("nothing to push");
+            return Ok(());
+        }
 
-        println!("searching for existing remote in repo");
-        let mut git_remote = match self.repo.find_remote(&remote.name) {
-            Ok(git_remote) => git_remote,
-            Err(_) => {
-                eprintln!(
-                    "Failed to find remote '{}', adding remote to git",
-                    remote.name
-                );
-                // this remote is not added to git yet, we add it
-                self.repo.remote(&remote.name, &remote.url)?
+        let rejected = push_refs(&self.repo, remote, &branches)?;
+        for id in attachments.iter() {
+            let name = attachment_ref(id);
+            if !rejected.iter().any(|(refname, _)| *refname == name) {
+                self.track_attachment(&remote.name, id)?;
             }
-        };
+        }
+        if rejected.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::PushRejected(rejected))
+        }
+    }
+}
 
-        println!("found a remote, starting fetch...");
+/// Push `refs` to a remote, returning the refs it rejected along with its reasons.
+pub(crate) fn push_refs(
+    repo: &git2::Repository,
+    remote: &Remote,
+    refs: &[String],
+) -> Result<Vec<(String, String)>> {
+    println!("searching for existing remote in repo");
+    let mut git_remote = match repo.find_remote(&remote.name) {
+        Ok(git_remote) => git_remote,
+        Err(_) => {
+            eprintln!(
+                "Failed to find remote '{}', adding remote to git",
+                remote.name
+            );
+            // this remote is not added to git yet, we add it
+            repo.remote(&remote.name, &remote.url)?
+        }
+    };
 
-        let mut fetch_opt = git2::FetchOptions::new();
-        fetch_opt.remote_callbacks(remote.git_callbacks());
-        let refspec_iter = git_remote.fetch_refspecs()?;
-        let refspecs: Vec<&str> = refspec_iter.iter().map(|r| r.unwrap()).collect();
-        git_remote.fetch(&refspecs, Some(&mut fetch_opt), None)?;
-        println!("finished fetch");
+    let rejected = RefCell::new(Vec::new());
+    let mut callbacks = remote.git_callbacks();
+    callbacks.push_update_reference(|refname, status| {
+        remote.report(&Progress::PushUpdate {
+            refname: refname.to_string(),
+            status: status.map(|s| s.to_string()),
+        });
+        if let Some(reason) = status {
+            rejected
+                .borrow_mut()
+                .push((refname.to_string(), reason.to_string()));
+        }
+        Ok(())
+    });
+
+    let mut push_opt = git2::PushOptions::new();
+    push_opt.remote_callbacks(callbacks);
+
+    let borrowed: Vec<&str> = refs.iter().map(|s| s.as_ref()).collect();
+
+    println!("branches to push: {:?}", borrowed);
+    git_remote
+        .push(&borrowed, Some(&mut push_opt))
+        .map_err(|e| match e.code() {
+            // local transports refuse non-fast-forward pushes before asking the remote
+            git2::ErrorCode::NotFastForward => Error::PushRejected(
+                refs.iter()
+                    .map(|b| (b.clone(), e.message().to_string()))
+                    .collect(),
+            ),
+            _ => remote.sync_error(e),
+        })?;
+    eprintln!("Finish push");
+
+    drop(push_opt); // releases the callbacks borrowing `rejected`
+    Ok(rejected.into_inner())
+}
+
+/// Fetch a remote into `repo` without consuming anything that was fetched.
+///
+/// This is useful for bootstrapping a new device, where the metadata needed to
+/// open the log has to be fetched before a log can be constructed.
+pub fn fetch(repo: &git2::Repository, remote: &Remote) -> Result<()> {
+    println!("fetching remote: {}", &remote.name);
+
+    println!("searching for existing remote in repo");
+    let mut git_remote = match repo.find_remote(&remote.name) {
+        Ok(git_remote) => git_remote,
+        Err(_) => {
+            eprintln!(
+                "Failed to find remote '{}', adding remote to git",
+                remote.name
+            );
+            // this remote is not added to git yet, we add it
+            repo.remote(&remote.name, &remote.url)?
+        }
+    };
+
+    println!("found a remote, starting fetch...");
+
+    let mut fetch_opt = git2::FetchOptions::new();
+    fetch_opt.remote_callbacks(remote.git_callbacks());
+    let refspec_iter = git_remote.fetch_refspecs()?;
+    let refspecs: Vec<&str> = refspec_iter.iter().map(|r| r.unwrap()).collect();
+    git_remote
+        .fetch(&refspecs, Some(&mut fetch_opt), None)
+        .map_err(|e| remote.sync_error(e))?;
+    println!("finished fetch");
+    Ok(())
+}
+
+/// Bundles are imported as if they were fetched from a remote with this name.
+const BUNDLE_REMOTE: &str = "bundle";
+
+/// The first line of a git bundle file.
+const BUNDLE_SIGNATURE: &str = "# v2 git bundle";
+
+impl<A, C: CmRDT> BundleReplicable for Log<A, C>
+where
+    C::Op: serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + ToString + FromStr,
+{
+    /// Writes our actor's commits into a git bundle.
+    ///
+    /// We don't know which device imports the bundle next, so it holds every
+    /// commit that one of the devices we've had bundles from may be missing.
+    /// Each bundle tells its importer how far the exporter has got through the
+    /// other actors' logs, under `refs/hermitdb/has/`.
+    fn export_bundle(&mut self, path: &Path) -> Result<()> {
+        let branch_name = self.actor_branch(self.writer()?);
+        let tip = self.tip()?;
+        let delivered = self.delivered_to_every_peer()?;
+        let has = self.other_actor_tips(&branch_name)?;
+
+        let mut pack = git2::Buf::new();
+        let mut packbuilder = self.repo.packbuilder()?;
+        if let Some(tip) = tip {
+            let mut walk = self.repo.revwalk()?;
+            walk.push(tip)?;
+            if let Some(delivered) = delivered {
+                walk.hide(delivered)?;
+            }
+            packbuilder.insert_walk(&mut walk)?;
+        }
+        let meta_tips = self.meta_tips("refs/heads/")?;
+        for tip in meta_tips.values() {
+            // meta branches are small, they're always sent in full
+            let mut walk = self.repo.revwalk()?;
+            walk.push(*tip)?;
+            packbuilder.insert_walk(&mut walk)?;
+        }
+        packbuilder.write_buf(&mut pack)?;
+
+        let mut bundle = format!("{}\n", BUNDLE_SIGNATURE);
+        if let Some(delivered) = delivered {
+            bundle.push_str(&format!("-{}\n", delivered));
+        }
+        if let Some(tip) = tip {
+            bundle.push_str(&format!("{} refs/heads/{}\n", tip, branch_name));
+        }
+        for (meta_branch, meta_tip) in meta_tips {
+            bundle.push_str(&format!("{} refs/heads/{}\n", meta_tip, meta_branch));
+        }
+        for (actor_branch, actor_tip) in has {
+            bundle.push_str(&format!(
+                "{} refs/hermitdb/has/{}\n",
+                actor_tip, actor_branch
+            ));
+        }
+        bundle.push('\n');
+
+        let mut file = fs::File::create(path)?;
+        file.write_all(bundle.as_bytes())?;
+        file.write_all(&pack)?;
+        file.sync_all()?;
         Ok(())
     }
 
-    fn push(&self, remote: &mut Self::Remote) -> Result<()> {
-        println!("searching for existing remote in repo");
+    fn import_bundle(&mut self, path: &Path) -> Result<()> {
+        let bytes = fs::read(path)?;
+        let (prerequisites, refs, pack) = parse_bundle(&bytes)?;
+
+        for oid in prerequisites {
+            if self.repo.find_commit(oid).is_err() {
+                return Err(Error::State(format!(
+                    "The bundle builds on commit {} which we don't have",
+                    oid
+                )));
+            }
+        }
+
+        let odb = self.repo.odb()?;
+        let mut packwriter = odb.packwriter()?;
+        packwriter.write_all(pack)?;
+        packwriter.commit()?;
+
+        let tips_before_import = self.remote_actor_tips(BUNDLE_REMOTE)?;
+        let own_branch = self.actor.as_ref().map(|actor| self.actor_branch(actor));
+        let mut exporter = None;
+        let mut exporter_has = None;
+        for (oid, refname) in refs {
+            if let Some(branch_name) = refname.strip_prefix("refs/hermitdb/has/") {
+                if Some(branch_name) == own_branch.as_deref() {
+                    exporter_has = Some(oid);
+                }
+                continue;
+            }
+            let branch_name = match refname.strip_prefix("refs/heads/") {
+                Some(name) if name.starts_with("actor_") || name.starts_with("meta_") => name,
+                _ => continue, // not an actor log or metadata
+            };
+            if branch_name.starts_with("actor_") && Some(branch_name) != own_branch.as_deref() {
+                exporter = Some(branch_name.to_string());
+            }
+            self.repo.reference(
+                &format!("refs/remotes/{}/{}", BUNDLE_REMOTE, branch_name),
+                oid,
+                true,
+                "hermitdb: imported bundle",
+            )?;
+        }
+
+        // devices that have never committed can't be told apart, we don't track what they have
+        if let (Some(exporter), Some(has)) = (exporter, exporter_has)
+            && self.repo.find_commit(has).is_ok()
+        {
+            self.repo.reference(
+                &format!("refs/hermitdb/bundle/delivered/{}", exporter),
+                has,
+                true,
+                "hermitdb: bundle delivered",
+            )?;
+        }
+
+        let mut rewritten = self.fast_forward_meta(BUNDLE_REMOTE)?;
+        rewritten.extend(self.quarantine_rewritten(BUNDLE_REMOTE, &tips_before_import)?);
+        rewritten_error(rewritten)
+    }
+}
+
+impl<A, C: CmRDT> Log<A, C>
+where
+    C::Op: serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + ToString + FromStr,
+{
+    /// The latest of our commits that every device we've had a bundle from has.
+    ///
+    /// Our log is a single line of history, so this is the oldest of the
+    /// commits each of those devices told us they have.
+    fn delivered_to_every_peer(&self) -> Result<Option<git2::Oid>> {
+        let mut delivered: Option<git2::Oid> = None;
+        for reference in self
+            .repo
+            .references_glob("refs/hermitdb/bundle/delivered/*")?
+        {
+            let has = reference?
+                .target()
+                .ok_or(Error::BranchIsNotADirectReference)?;
+            delivered = match delivered {
+                Some(oldest) if !self.repo.graph_descendant_of(oldest, has)? => Some(oldest),
+                _ => Some(has),
+            };
+        }
+        Ok(delivered)
+    }
+
+    /// How far we've got through each of the other actors' logs, keyed by branch name.
+    fn other_actor_tips(&self, own_branch: &str) -> Result<BTreeMap<String, git2::Oid>> {
+        let mut tips: BTreeMap<String, git2::Oid> = BTreeMap::new();
+        for glob in ["refs/heads/actor_*", "refs/remotes/*/actor_*"] {
+            for reference in self.repo.references_glob(glob)? {
+                let reference = reference?;
+                let name = reference.name().ok_or(Error::BranchNameEncodingError)?;
+                let branch_name = name.rsplit('/').next().unwrap_or_default();
+                if branch_name == own_branch {
+                    continue;
+                }
+                let tip = reference
+                    .target()
+                    .ok_or(Error::BranchIsNotADirectReference)?;
+                match tips.get(branch_name) {
+                    Some(known) if !self.repo.graph_descendant_of(tip, *known)? => (),
+                    _ => {
+                        tips.insert(branch_name.to_string(), tip);
+                    }
+                }
+            }
+        }
+        Ok(tips)
+    }
+}
+
+/// Splits a git bundle into its prerequisite commits, its refs and its packfile.
+#[allow(clippy::type_complexity)]
+fn parse_bundle(bytes: &[u8]) -> Result<(Vec<git2::Oid>, Vec<(git2::Oid, String)>, &[u8])> {
+    let header_end = bytes
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .ok_or_else(|| Error::Parse("bundle header is not terminated".into()))?;
+    let header = std::str::from_utf8(&bytes[..header_end])
+        .map_err(|_| Error::Parse("bundle header is not utf8".into()))?;
+    let pack = &bytes[header_end + 2..];
+
+    let mut lines = header.lines();
+    if lines.next() != Some(BUNDLE_SIGNATURE) {
+        return Err(Error::Parse("not a v2 git bundle".into()));
+    }
+
+    let parse_oid = |s: &str| {
+        git2::Oid::from_str(s).map_err(|_| Error::Parse(format!("bad oid in bundle: {}", s)))
+    };
+
+    let mut prerequisites = Vec::new();
+    let mut refs = Vec::new();
+    for line in lines {
+        if let Some(prerequisite) = line.strip_prefix('-') {
+            // prerequisites may carry a comment after the oid
+            let oid = prerequisite.split(' ').next().unwrap_or_default();
+            prerequisites.push(parse_oid(oid)?);
+        } else {
+            match line.split_once(' ') {
+                Some((oid, refname)) => refs.push((parse_oid(oid)?, refname.to_string())),
+                None => return Err(Error::Parse(format!("bad ref line in bundle: {}", line))),
+            }
+        }
+    }
+
+    Ok((prerequisites, refs, pack))
+}
+
+impl<A: Actor, C: CmRDT> AttachmentStore for Log<A, C>
+where
+    C::Op: serde::Serialize + serde::de::DeserializeOwned,
+{
+    type Remote = Remote;
+
+    /// Attachments are named by the SHA-256 of their content.
+    fn put_attachment(&mut self, data: &mut dyn Read) -> Result<(AttachmentId, u64)> {
+        let mut writer = self.attachment_writer()?;
+        let mut hashing = crypto::Hashing::sha256(data);
+        let len = io::copy(&mut hashing, &mut writer)?;
+        let id = hashing.finish();
+        writer.finish(&id)?;
+        Ok((id, len))
+    }
+
+    fn has_attachment(&self, id: &AttachmentId) -> Result<bool> {
+        Ok(self.repo.find_reference(&attachment_ref(id)).is_ok())
+    }
+
+    fn fetch_attachment(&self, remote: &Remote, id: &AttachmentId) -> Result<()> {
         let mut git_remote = match self.repo.find_remote(&remote.name) {
             Ok(git_remote) => git_remote,
-            Err(_) => {
-                eprintln!(
-                    "Failed to find remote '{}', adding remote to git",
-                    remote.name
-                );
-                // this remote is not added to git yet, we add it
-                self.repo.remote(&remote.name, &remote.url)?
-            }
+            Err(_) => self.repo.remote(&remote.name, &remote.url)?,
         };
 
-        let mut push_opt = git2::PushOptions::new();
-        push_opt.remote_callbacks(remote.git_callbacks());
+        let name = attachment_ref(id);
+        let refspec = format!("+{}:{}", name, name);
+        let mut fetch_opt = git2::FetchOptions::new();
+        fetch_opt.remote_callbacks(remote.git_callbacks());
+        git_remote
+            .fetch(&[&refspec], Some(&mut fetch_opt), None)
+            .map_err(|e| remote.sync_error(e))?;
 
-        let branches: Vec<String> = self
-            .repo
-            .branches(Some(git2::BranchType::Local))?
-            .map(|b| b.unwrap())
-            .map(|(branch, _)| branch)
-            .map(|b| {
-                let b = b.name().unwrap().unwrap();
-                format!("refs/heads/{}", b)
-            })
-            .collect();
+        if !self.has_attachment(id)? {
+            return Err(Error::State(format!(
+                "'{}' doesn't have attachment {}",
+                remote.name,
+                to_hex(id)
+            )));
+        }
+        // the remote has it, so there's no need to push it back
+        self.track_attachment(&remote.name, id)
+    }
 
-        let borrowed: Vec<&str> = branches.iter().map(|s| s.as_ref()).collect();
+    /// The content is checked against its SHA-256 once it's all been written to `out`.
+    fn read_attachment(&self, id: &AttachmentId, out: &mut dyn Write) -> Result<u64> {
+        let mut reader = self.attachment_reader(id)?;
+        let mut hashing = crypto::Hashing::sha256(out);
+        let len = io::copy(&mut reader, &mut hashing)?;
+        if hashing.finish() != *id {
+            return Err(Error::State(format!(
+                "attachment {} doesn't match its id",
+                to_hex(id)
+            )));
+        }
+        Ok(len)
+    }
+
+    fn attachments(&self) -> Result<Vec<AttachmentId>> {
+        let mut ids = Vec::new();
+        for reference in self.repo.references_glob("refs/attachments/*")? {
+            let reference = reference?;
+            let name = reference.name().ok_or(Error::BranchNameEncodingError)?;
+            let id = from_hex(&name["refs/attachments/".len()..])
+                .and_then(|bytes| AttachmentId::try_from(bytes).ok())
+                .ok_or_else(|| Error::State(format!("{} isn't an attachment id", name)))?;
+            ids.push(id);
+        }
+        Ok(ids)
+    }
 
-        println!("branches to push: {:?}", borrowed);
-        git_remote.push(&borrowed, Some(&mut push_opt))?;
-        eprintln!("Finish push");
+    /// The attachment's objects are left for `git gc` to clean up once nothing refers to them.
+    fn remove_attachment(&mut self, id: &AttachmentId) -> Result<()> {
+        let tracking = format!("refs/remotes/*/attachments/{}", to_hex(id));
+        let mut refs: Vec<git2::Reference> = self
+            .repo
+            .references_glob(&tracking)?
+            .collect::<std::result::Result<_, _>>()?;
+        if let Ok(reference) = self.repo.find_reference(&attachment_ref(id)) {
+            refs.push(reference);
+        }
+        for mut reference in refs {
+            reference.delete()?;
+        }
         Ok(())
     }
 }
@@ -360,11 +871,906 @@ where
 {
     pub fn new(actor: A, repo: git2::Repository) -> Self {
         Log {
-            actor,
+            actor: Some(actor),
             repo,
+            opaque: None,
+            compression: None,
+            signer: None,
+            require_signatures: false,
+            trust: Trust::Metadata(RefCell::new(None)),
             phantom_crdt: PhantomData,
         }
     }
+
+    /// A log that's only read from, it has no actor of its own.
+    ///
+    /// Replicas never commit or push, nor export bundles, doing so is an error.
+    pub fn replica(repo: git2::Repository) -> Self {
+        Log {
+            actor: None,
+            repo,
+            opaque: None,
+            compression: None,
+            signer: None,
+            require_signatures: false,
+            trust: Trust::Metadata(RefCell::new(None)),
+            phantom_crdt: PhantomData,
+        }
+    }
+
+    /// Our actor, replicas have none and panic, see `try_actor`.
+    pub fn actor(&self) -> &A {
+        self.try_actor().expect("Read-only replicas have no actor")
+    }
+
+    /// Our actor, None if we're a replica.
+    pub fn try_actor(&self) -> Option<&A> {
+        self.actor.as_ref()
+    }
+
+    /// Our actor, failing if we're a replica and so can't write.
+    fn writer(&self) -> Result<&A> {
+        self.actor
+            .as_ref()
+            .ok_or_else(|| Error::State("Read-only replicas can't write to the log".into()))
+    }
+
+    /// Hide as much as we can from whoever hosts our remotes.
+    ///
+    /// Actor ids in branch names are encrypted with `names` and commits are
+    /// made with a fixed author and timestamp. Every device sharing the log
+    /// must use the same `names` cipher.
+    pub fn make_opaque(&mut self, names: NameCipher) {
+        self.opaque = Some(names);
+    }
+
+    /// Compress new ops with zstd at `level` before they're committed.
+    ///
+    /// Compressed ops are stored under their own name in the commit's tree, so
+    /// devices can choose for themselves and logs can mix compressed and
+    /// uncompressed ops. Ops larger than 16MiB can't be compressed.
+    pub fn compress_ops(&mut self, level: i32) {
+        self.compression = Some(level);
+    }
+
+    /// Sign new ops with `key`, the signature covers the op and its parent commit.
+    pub fn sign_ops(&mut self, key: SigningKey) {
+        self.signer = Some(key);
+    }
+
+    /// Have `next()` reject ops that aren't signed by the key we trust for their actor.
+    pub fn require_signed_ops(&mut self) {
+        self.require_signatures = true;
+    }
+
+    /// Trust exactly `keys`, keyed by the name of the actor's branch, instead of the trusted_actors metadata.
+    ///
+    /// For logs whose actor keys are vouched for by something the host of our
+    /// remotes can't rewrite, eg. the members of an encrypted vault.
+    pub fn set_trusted_keys(&mut self, keys: BTreeMap<String, VerifyingKey>) {
+        self.trust = Trust::Given(keys);
+    }
+
+    /// Drop our signing key and name cipher, they're given back with `sign_ops` and `make_opaque`.
+    ///
+    /// Signatures are still required if they were before.
+    pub fn forget_keys(&mut self) {
+        self.signer = None;
+        self.opaque = None;
+    }
+
+    /// The signature for new commits.
+    fn signature(&self) -> Result<git2::Signature<'static>> {
+        match self.opaque {
+            Some(_) => Ok(git2::Signature::new(
+                "hermitdb",
+                "hermitdb",
+                &git2::Time::new(0, 0),
+            )?),
+            None => Ok(self.repo.signature()?),
+        }
+    }
+
+    /// Read the metadata stored under `name`, None if it was never written.
+    ///
+    /// Metadata lives on `meta_<name>` branches which are replicated alongside
+    /// the actor logs, a metadata branch only ever moves forward.
+    pub fn read_meta(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let commit = match meta_head(&self.repo, name)? {
+            Some(commit) => commit,
+            None => return Ok(None),
+        };
+        let tree = commit.tree()?;
+        let entry = tree
+            .get_name("meta")
+            .ok_or_else(|| Error::State(format!("meta_{} has no 'meta' entry", name)))?;
+        let blob = self.repo.find_blob(entry.id())?;
+        Ok(Some(blob.content().to_vec()))
+    }
+
+    /// Store `bytes` as the metadata under `name`, it's shared with remotes on the next push.
+    pub fn write_meta(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
+        let branch_ref = format!("refs/heads/meta_{}", name);
+        let parent = meta_head(&self.repo, name)?;
+
+        let meta_oid = self.repo.blob(bytes)?;
+        let mut builder = self.repo.treebuilder(None)?;
+        builder.insert("meta", meta_oid, 0o100_644)?;
+        let tree = self.repo.find_tree(builder.write()?)?;
+        let sig = self.signature()?;
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+
+        self.repo
+            .commit(Some(&branch_ref), &sig, &sig, "db meta", &tree, &parents)?;
+        self.forget_trusted_actors();
+        Ok(())
+    }
+
+    /// Read what this device stored under `name` with `write_local`, None if it never did.
+    pub fn read_local(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let blob_oid = match self.repo.find_reference(&local_ref(name)) {
+            Ok(reference) => reference
+                .target()
+                .ok_or(Error::BranchIsNotADirectReference)?,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(self.repo.find_blob(blob_oid)?.content().to_vec()))
+    }
+
+    /// Store `bytes` under `name` on this device only, unlike metadata it's never pushed.
+    pub fn write_local(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
+        let blob_oid = self.repo.blob(bytes)?;
+        self.repo
+            .reference(&local_ref(name), blob_oid, true, "hermitdb: local data")?;
+        Ok(())
+    }
+
+    /// The tips of the meta branches under a ref prefix, keyed by branch name.
+    fn meta_tips(&self, prefix: &str) -> Result<BTreeMap<String, git2::Oid>> {
+        let mut tips = BTreeMap::new();
+        for reference in self.repo.references_glob(&format!("{}meta_*", prefix))? {
+            let reference = reference?;
+            let name = reference.name().ok_or(Error::BranchNameEncodingError)?;
+            let tip = reference
+                .target()
+                .ok_or(Error::BranchIsNotADirectReference)?;
+            tips.insert(name[prefix.len()..].to_string(), tip);
+        }
+        Ok(tips)
+    }
+
+    /// Move our meta branches forward to what we've fetched from a remote.
+    ///
+    /// A meta branch changed both here and on the remote can't be moved, the
+    /// remote's tip is quarantined until it's released and its name returned.
+    fn fast_forward_meta(&self, remote_name: &str) -> Result<Vec<String>> {
+        let mut diverged = Vec::new();
+        for (branch_name, remote_tip) in self.meta_tips(&format!("refs/remotes/{}/", remote_name))? {
+            let quarantine = quarantine_ref(&branch_name);
+            if self.repo.find_reference(&quarantine).is_ok() {
+                continue;
+            }
+            let branch_ref = format!("refs/heads/{}", branch_name);
+            let local_tip = self
+                .repo
+                .find_reference(&branch_ref)
+                .ok()
+                .and_then(|r| r.target());
+
+            match local_tip {
+                Some(local_tip) if local_tip == remote_tip => (),
+                Some(local_tip) if self.repo.graph_descendant_of(local_tip, remote_tip)? => (),
+                Some(local_tip) if !self.repo.graph_descendant_of(remote_tip, local_tip)? => {
+                    self.repo.reference(
+                        &quarantine,
+                        remote_tip,
+                        true,
+                        "hermitdb: meta changed concurrently",
+                    )?;
+                    diverged.push(branch_name);
+                }
+                _ => {
+                    self.repo
+                        .reference(&branch_ref, remote_tip, true, "hermitdb: fast-forward meta")?;
+                    self.forget_trusted_actors();
+                }
+            }
+        }
+        Ok(diverged)
+    }
+
+    /// Accept the quarantined version of the metadata under `name`, dropping our own changes to it.
+    ///
+    /// Other devices have already taken the version we quarantined, so we
+    /// take it too. Changes we made concurrently are lost and should be made
+    /// again on top of it, until then our version of this metadata isn't pushed.
+    pub fn release_meta_quarantine(&mut self, name: &str) -> Result<()> {
+        let branch_name = format!("meta_{}", name);
+        let mut quarantine = self.repo.find_reference(&quarantine_ref(&branch_name))?;
+        let theirs = quarantine
+            .target()
+            .ok_or(Error::BranchIsNotADirectReference)?;
+        self.repo.reference(
+            &format!("refs/heads/{}", branch_name),
+            theirs,
+            true,
+            "hermitdb: released meta quarantine",
+        )?;
+        quarantine.delete()?;
+        self.forget_trusted_actors();
+        Ok(())
+    }
+
+    /// Drop the cached trusted_actors metadata, it's read again when next needed.
+    fn forget_trusted_actors(&self) {
+        if let Trust::Metadata(cache) = &self.trust {
+            cache.replace(None);
+        }
+    }
+
+    /// Read the op committed in `commit_oid`.
+    pub fn op_at(&self, commit_oid: git2::Oid) -> Result<C::Op> {
+        op_from_commit(&self.repo, &self.repo.find_commit(commit_oid)?)
+    }
+
+    /// Start storing an attachment, it's only kept once the writer is finished.
+    ///
+    /// The caller names the attachment, for logs wrapping this one the id
+    /// need not be a hash of what's written here.
+    pub fn attachment_writer(&self) -> Result<AttachmentWriter<'_>> {
+        Ok(AttachmentWriter {
+            repo: &self.repo,
+            sig: self.signature()?,
+            buf: Vec::new(),
+            chunks: Vec::new(),
+        })
+    }
+
+    /// Read back what was written for an attachment, without checking it against its id.
+    pub fn attachment_reader(&self, id: &AttachmentId) -> Result<AttachmentReader<'_>> {
+        let commit = self
+            .repo
+            .find_reference(&attachment_ref(id))
+            .map_err(|_| Error::State(format!("we don't have attachment {}", to_hex(id))))?
+            .peel_to_commit()?;
+        let chunks: Vec<git2::Oid> = commit.tree()?.iter().map(|entry| entry.id()).collect();
+        Ok(AttachmentReader {
+            repo: &self.repo,
+            chunks: chunks.into_iter(),
+            chunk: None,
+            pos: 0,
+        })
+    }
+
+    /// Attachments we have that we've not pushed to, or fetched from, a remote.
+    fn unpushed_attachments(&self, remote_name: &str) -> Result<Vec<AttachmentId>> {
+        let mut unpushed = Vec::new();
+        for id in self.attachments()? {
+            let tracking = format!("refs/remotes/{}/attachments/{}", remote_name, to_hex(&id));
+            if self.repo.find_reference(&tracking).is_err() {
+                unpushed.push(id);
+            }
+        }
+        Ok(unpushed)
+    }
+
+    /// Record that a remote has an attachment.
+    fn track_attachment(&self, remote_name: &str, id: &AttachmentId) -> Result<()> {
+        let target = self
+            .repo
+            .find_reference(&attachment_ref(id))?
+            .target()
+            .ok_or(Error::BranchIsNotADirectReference)?;
+        let tracking = format!("refs/remotes/{}/attachments/{}", remote_name, to_hex(id));
+        self.repo
+            .reference(&tracking, target, true, "hermitdb: remote has attachment")?;
+        Ok(())
+    }
+}
+
+/// The head of a meta branch, falling back to what we've fetched from remotes
+/// when we've not synced this metadata before (eg. on a new device).
+fn meta_head<'r>(repo: &'r git2::Repository, name: &str) -> Result<Option<git2::Commit<'r>>> {
+    let branch_name = format!("meta_{}", name);
+    let reference = match repo.find_reference(&format!("refs/heads/{}", branch_name)) {
+        Ok(reference) => reference,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            let mut fetched = repo.references_glob(&format!("refs/remotes/*/{}", branch_name))?;
+            match fetched.next() {
+                Some(reference) => reference?,
+                None => return Ok(None),
+            }
+        }
+        Err(e) => return Err(Error::Git(e)),
+    };
+    Ok(Some(reference.peel_to_commit()?))
+}
+
+fn op_from_commit<Op: serde::de::DeserializeOwned>(
+    repo: &git2::Repository,
+    commit: &git2::Commit,
+) -> Result<Op> {
+    let tree = commit.tree()?;
+    let (entry, compressed) = op_entry(&tree)?;
+    let blob = repo.find_blob(entry.id())?;
+    match compressed {
+        true => Ok(bincode::deserialize(&decompress(blob.content())?)?),
+        false => Ok(bincode::deserialize(blob.content())?),
+    }
+}
+
+/// The tree entry holding a commit's op, and whether the op is compressed.
+fn op_entry<'t>(tree: &'t git2::Tree) -> Result<(git2::TreeEntry<'t>, bool)> {
+    match (tree.get_name(OP), tree.get_name(COMPRESSED_OP)) {
+        (Some(entry), None) => Ok((entry, false)),
+        (None, Some(entry)) => Ok((entry, true)),
+        _ => Err(Error::LogCommitDoesNotContainOp),
+    }
+}
+
+/// What an op's signature is made over, the op's bytes as stored, whether
+/// they're compressed and its parent commit.
+fn signed_message(parent: Option<git2::Oid>, compressed: bool, op_bytes: &[u8]) -> Vec<u8> {
+    let mut msg = b"hermitdb op".to_vec();
+    match parent {
+        Some(parent) => msg.extend(parent.as_bytes()),
+        None => msg.extend([0u8; 20]),
+    }
+    msg.push(compressed as u8);
+    msg.extend(op_bytes);
+    msg
+}
+
+pub(crate) fn compress(bytes: &[u8], level: i32) -> Result<Vec<u8>> {
+    if bytes.len() as u64 > MAX_DECOMPRESSED_OP {
+        return Err(too_large_to_compress());
+    }
+    Ok(zstd::bulk::compress(bytes, level)?)
+}
+
+/// Decompress an op, refusing any that decompress past `MAX_DECOMPRESSED_OP`.
+pub(crate) fn decompress(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    zstd::stream::read::Decoder::new(bytes)?
+        .take(MAX_DECOMPRESSED_OP + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_OP {
+        return Err(too_large_to_compress());
+    }
+    Ok(decompressed)
+}
+
+fn too_large_to_compress() -> Error {
+    Error::State(format!(
+        "compressed ops are limited to {} bytes",
+        MAX_DECOMPRESSED_OP
+    ))
+}
+
+impl<A, C: CmRDT> Log<A, C>
+where
+    C::Op: serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + ToString + FromStr,
+{
+    /// The name of the branch holding `actor`'s log.
+    pub(crate) fn actor_branch(&self, actor: &A) -> String {
+        match &self.opaque {
+            Some(names) => format!(
+                "actor_{}",
+                to_hex(&names.encrypt(actor.to_string().as_bytes()))
+            ),
+            None => format!("actor_{}", actor.to_string()),
+        }
+    }
+
+    /// Parse an actor from the `<id>` in an `actor_<id>` branch name.
+    fn parse_actor(&self, id: &str) -> Result<A> {
+        let actor_str = match &self.opaque {
+            Some(names) => {
+                let decrypted = from_hex(id).and_then(|bytes| names.decrypt(&bytes).ok());
+                decrypted
+                    .and_then(|bytes| String::from_utf8(bytes).ok())
+                    .ok_or_else(|| {
+                        Error::Parse(format!("Failed to decrypt actor from branch: {}", id))
+                    })?
+            }
+            None => id.to_string(),
+        };
+        actor_str
+            .parse()
+            .map_err(|_| Error::Parse(format!("Failed to parse actor from branch: {}", actor_str)))
+    }
+
+    /// Trust ops signed by `key` as coming from `actor`.
+    ///
+    /// The trusted keys are shared with remotes as log metadata. An actor's key
+    /// can't be replaced once it's trusted, this is an error. The metadata isn't
+    /// authenticated, whoever can push to our remotes can rewrite it, see `set_trusted_keys`.
+    pub fn trust_actor(&mut self, actor: &A, key: VerifyingKey) -> Result<()> {
+        let mut trusted = self.trusted_actors()?;
+        match trusted.insert(self.actor_branch(actor), key) {
+            Some(old_key) if old_key == key => Ok(()),
+            Some(_) => Err(Error::State(format!(
+                "{} is already trusted with a different key",
+                actor.to_string()
+            ))),
+            None => self.write_meta(TRUSTED_ACTORS, &bincode::serialize(&trusted)?),
+        }
+    }
+
+    /// The verifying keys of trusted actors, keyed by the name of their branch.
+    fn trusted_actors(&self) -> Result<BTreeMap<String, VerifyingKey>> {
+        match self.read_meta(TRUSTED_ACTORS)? {
+            Some(bytes) => Ok(bincode::deserialize(&bytes)?),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// The trusted key of the actor logging to `branch`, the metadata is read once and cached.
+    fn trusted_key(&self, branch: &str) -> Result<Option<VerifyingKey>> {
+        let cache = match &self.trust {
+            Trust::Given(keys) => return Ok(keys.get(branch).copied()),
+            Trust::Metadata(cache) => cache,
+        };
+        if cache.borrow().is_none() {
+            cache.replace(Some(self.trusted_actors()?));
+        }
+        Ok(cache.borrow().as_ref().and_then(|keys| keys.get(branch).copied()))
+    }
+
+    /// Check every commit on the actor branches we know of, see `verify_with`.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        self.verify_with(|_, seq| Ok(seq))
+    }
+
+    /// Check every commit on the actor branches we know of, both ours and those fetched from remotes.
+    ///
+    /// Commits must have at most one parent and hold an op that decodes,
+    /// signed by its actor if we require signatures. Ops that pass are handed
+    /// to `check_op` for any further checks, along with their sequence number.
+    /// `check_op` returns the sequence number the op was written with, the op
+    /// after it in the log follows on from that. Nothing is acked or otherwise changed.
+    pub fn verify_with(
+        &self,
+        mut check_op: impl FnMut(&LoggedOp<A, C>, u64) -> Result<u64>,
+    ) -> Result<VerifyReport> {
+        verify_actor_branches(&self.repo, |id, oid, seq| {
+            let actor = self.parse_actor(id)?;
+            self.verify_commit(&actor, oid).and_then(|op| check_op(&op, seq))
+        })
+    }
+
+    fn verify_commit(&self, actor: &A, oid: git2::Oid) -> Result<LoggedOp<A, C>> {
+        let commit = self.repo.find_commit(oid)?;
+        check_op_commit(&commit)?;
+        let op = LoggedOp::from_commit(actor.clone(), &self.repo, &commit)?;
+        self.check_signature(&op)?;
+        Ok(op)
+    }
+
+    /// Check `op` is signed by its actor's trusted key, if we require signed ops.
+    fn check_signature(&self, op: &LoggedOp<A, C>) -> Result<()> {
+        if !self.require_signatures {
+            return Ok(());
+        }
+        let bad_signature = || Error::BadSignature(op.actor.to_string());
+        let key = self
+            .trusted_key(&self.actor_branch(&op.actor))?
+            .ok_or_else(bad_signature)?;
+
+        let commit = self.repo.find_commit(git2::Oid::from_bytes(&op.oid)?)?;
+        let tree = commit.tree()?;
+        let (op_entry, compressed) = op_entry(&tree)?;
+        let sig_entry = tree.get_name("sig").ok_or_else(bad_signature)?;
+        let op_bytes = self.repo.find_blob(op_entry.id())?;
+        let sig = self.repo.find_blob(sig_entry.id())?;
+
+        let msg = signed_message(commit.parent_id(0).ok(), compressed, op_bytes.content());
+        crypto::verify(&key, &msg, sig.content()).map_err(|_| bad_signature())
+    }
+
+    /// The latest commit on our actor's log, None if we've not committed anything.
+    pub fn tip(&self) -> Result<Option<git2::Oid>> {
+        let branch_name = match &self.actor {
+            Some(actor) => self.actor_branch(actor),
+            None => return Ok(None),
+        };
+        match self.repo.find_branch(&branch_name, git2::BranchType::Local) {
+            Ok(branch) => Ok(Some(
+                branch
+                    .get()
+                    .target()
+                    .ok_or(Error::BranchIsNotADirectReference)?,
+            )),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(Error::Git(e)),
+        }
+    }
+
+    /// Check each remote's copy of our log is part of our own, as far as we've fetched.
+    ///
+    /// Otherwise another device logs as our actor, or our history was
+    /// rewritten, and our next commit would take a position already used.
+    pub fn check_remotes_behind_tip(&self) -> Result<()> {
+        let branch_name = match &self.actor {
+            Some(actor) => self.actor_branch(actor),
+            None => return Ok(()),
+        };
+        let tip = self.tip()?;
+        for reference in self
+            .repo
+            .references_glob(&format!("refs/remotes/*/{}", branch_name))?
+        {
+            let reference = reference?;
+            let remote_tip = reference
+                .target()
+                .ok_or(Error::BranchIsNotADirectReference)?;
+            let behind = match tip {
+                Some(tip) => tip == remote_tip || self.repo.graph_descendant_of(tip, remote_tip)?,
+                None => false,
+            };
+            if !behind {
+                return Err(Error::State(format!(
+                    "{} has commits on {} we don't, another device may be logging as our actor",
+                    reference.name().unwrap_or("a remote"),
+                    branch_name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Actors whose history was rewritten on a remote, their ops are skipped by `next()`.
+    pub fn quarantined(&self) -> Result<Vec<A>> {
+        let mut actors = Vec::new();
+        for reference in self.repo.references_glob("refs/hermitdb/quarantine/actor_*")? {
+            let reference = reference?;
+            let name = reference.name().ok_or(Error::BranchNameEncodingError)?;
+            actors.push(self.parse_actor(&name["refs/hermitdb/quarantine/actor_".len()..])?);
+        }
+        Ok(actors)
+    }
+
+    /// Accept the rewritten history of a quarantined actor.
+    ///
+    /// Our position in that actor's log is rewound to the point where the old
+    /// and new histories diverged, ops after that point will be replayed by `next()`.
+    pub fn release_quarantine(&mut self, actor: &A) -> Result<()> {
+        if Some(actor.to_string()) == self.actor.as_ref().map(|a| a.to_string()) {
+            return Err(Error::State(
+                "Refusing to rewind our own history, it's not quarantined by us".into(),
+            ));
+        }
+
+        let branch_name = self.actor_branch(actor);
+        let mut quarantine = self.repo.find_reference(&quarantine_ref(&branch_name))?;
+        let rewritten_tip = quarantine
+            .target()
+            .ok_or(Error::BranchIsNotADirectReference)?;
+
+        if let Ok(mut tracking) = self.repo.find_branch(&branch_name, git2::BranchType::Local) {
+            let tracking_tip = tracking
+                .get()
+                .target()
+                .ok_or(Error::BranchIsNotADirectReference)?;
+            match self.repo.merge_base(tracking_tip, rewritten_tip) {
+                Ok(base) => {
+                    let base_commit = self.repo.find_commit(base)?;
+                    self.repo.branch(&branch_name, &base_commit, true)?;
+                }
+                Err(_) => tracking.delete()?,
+            }
+        }
+
+        quarantine.delete()?;
+        Ok(())
+    }
+
+    fn is_quarantined(&self, actor: &A) -> Result<bool> {
+        let branch_name = self.actor_branch(actor);
+        match self.repo.find_reference(&quarantine_ref(&branch_name)) {
+            Ok(_) => Ok(true),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(false),
+            Err(e) => Err(Error::Git(e)),
+        }
+    }
+
+    /// The refs of our local branches that have moved since we last synced with a remote.
+    ///
+    /// Only our own actor branch and the meta branches are shared, ack cursors and
+    /// the branches tracking other actors stay private to this device.
+    fn moved_branches(&self, remote_name: &str) -> Result<Vec<String>> {
+        let mut branches = Vec::new();
+        if let (Some(actor), Some(tip)) = (&self.actor, self.tip()?) {
+            branches.push((self.actor_branch(actor), tip));
+        }
+        for (name, tip) in self.meta_tips("refs/heads/")? {
+            // the remote has a different version, ours would be rejected
+            if self.repo.find_reference(&quarantine_ref(&name)).is_err() {
+                branches.push((name, tip));
+            }
+        }
+
+        let mut moved = Vec::new();
+        for (branch_name, local_tip) in branches {
+            let remote_tip = self
+                .repo
+                .find_reference(&format!("refs/remotes/{}/{}", remote_name, branch_name))
+                .ok()
+                .and_then(|r| r.target());
+
+            if remote_tip != Some(local_tip) {
+                moved.push(format!("refs/heads/{}", branch_name));
+            }
+        }
+        Ok(moved)
+    }
+
+    /// The tips of the actor branches we're tracking from a remote, keyed by branch name.
+    fn remote_actor_tips(&self, remote_name: &str) -> Result<BTreeMap<String, git2::Oid>> {
+        let prefix = format!("refs/remotes/{}/", remote_name);
+        let mut tips = BTreeMap::new();
+        for reference in self.repo.references_glob(&format!("{}actor_*", prefix))? {
+            let reference = reference?;
+            let name = reference.name().ok_or(Error::BranchNameEncodingError)?;
+            let tip = reference
+                .target()
+                .ok_or(Error::BranchIsNotADirectReference)?;
+            tips.insert(name[prefix.len()..].to_string(), tip);
+        }
+        Ok(tips)
+    }
+
+    /// Compares freshly fetched actor branches against what we knew before the
+    /// fetch, any branch that did not move forward is quarantined and its actor returned.
+    fn quarantine_rewritten(
+        &self,
+        remote_name: &str,
+        tips_before_fetch: &BTreeMap<String, git2::Oid>,
+    ) -> Result<Vec<String>> {
+        let mut rewritten = Vec::new();
+        for (branch_name, tip) in self.remote_actor_tips(remote_name)? {
+            let quarantine = quarantine_ref(&branch_name);
+            if self.repo.find_reference(&quarantine).is_ok() {
+                continue;
+            }
+
+            // the remote tip must only ever move forward
+            let mut is_rewrite = match tips_before_fetch.get(&branch_name) {
+                Some(before) => *before != tip && !self.repo.graph_descendant_of(tip, *before)?,
+                None => false,
+            };
+
+            // and it must share a line of history with what we've consumed locally,
+            // other remotes may be ahead of or behind this one.
+            if let Ok(local) = self.repo.find_branch(&branch_name, git2::BranchType::Local) {
+                let local_tip = local
+                    .get()
+                    .target()
+                    .ok_or(Error::BranchIsNotADirectReference)?;
+                is_rewrite |= local_tip != tip
+                    && !self.repo.graph_descendant_of(tip, local_tip)?
+                    && !self.repo.graph_descendant_of(local_tip, tip)?;
+            }
+
+            if is_rewrite {
+                self.repo
+                    .reference(&quarantine, tip, true, "hermitdb: history rewritten")?;
+                let id = &branch_name["actor_".len()..];
+                let actor = match self.parse_actor(id) {
+                    Ok(actor) => actor.to_string(),
+                    Err(_) => id.to_string(),
+                };
+                rewritten.push(actor);
+            }
+        }
+        Ok(rewritten)
+    }
+}
+
+/// Check every commit on the actor branches in `repo`, both ours and those fetched from remotes.
+///
+/// Each branch is walked back to its root, or to history already checked
+/// through another ref, and `check` is given the `<id>` of the branch, each
+/// commit on it, oldest first, and how many commits come before it.
+pub(crate) fn verify_actor_branches(
+    repo: &git2::Repository,
+    mut check: impl FnMut(&str, git2::Oid, u64) -> Result<u64>,
+) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let mut seen = BTreeMap::new(); // the sequence number following each commit we've checked
+    let mut refs = Vec::new();
+    for glob in ["refs/heads/actor_*", "refs/remotes/*/actor_*"] {
+        for reference in repo.references_glob(glob)? {
+            let reference = reference?;
+            let name = reference.name().ok_or(Error::BranchNameEncodingError)?;
+            let tip = reference
+                .target()
+                .ok_or(Error::BranchIsNotADirectReference)?;
+            refs.push((name.to_string(), tip));
+        }
+    }
+
+    for (branch, tip) in refs {
+        let mut bad = |commit, error| {
+            report.bad_commits.push(BadCommit {
+                branch: branch.clone(),
+                commit,
+                error,
+            })
+        };
+        let id = branch
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.strip_prefix("actor_"))
+            .unwrap_or_default()
+            .to_string();
+
+        let mut history = Vec::new();
+        let mut next = Some(tip);
+        while let Some(oid) = next.filter(|oid| !seen.contains_key(oid)) {
+            match repo.find_commit(oid) {
+                Ok(commit) => next = commit.parent_id(0).ok(),
+                Err(e) => {
+                    bad(oid, Error::Git(e));
+                    break;
+                }
+            }
+            history.push(oid);
+        }
+
+        // a corrupt op is taken to sit where we expected, so it doesn't throw off those after it
+        let mut seq = next.and_then(|oid| seen.get(&oid)).copied().unwrap_or(0);
+        for oid in history.into_iter().rev() {
+            seq = match check(&id, oid, seq) {
+                Ok(written) => {
+                    report.ops += 1;
+                    written + 1
+                }
+                Err(e) => {
+                    bad(oid, e);
+                    seq + 1
+                }
+            };
+            seen.insert(oid, seq);
+        }
+    }
+    Ok(report)
+}
+
+/// Check an op commit is shaped as we write them, without reading the op.
+///
+/// It must have at most one parent, and only an `op` or `op.zst` blob and maybe a `sig` blob.
+pub(crate) fn check_op_commit(commit: &git2::Commit) -> Result<()> {
+    if commit.parent_count() > 1 {
+        return Err(Error::State(format!(
+            "op commit {} has {} parents",
+            commit.id(),
+            commit.parent_count()
+        )));
+    }
+    let tree = commit.tree()?;
+    op_entry(&tree)?;
+    for entry in tree.iter() {
+        let name = entry.name().unwrap_or_default();
+        if ![OP, COMPRESSED_OP, "sig"].contains(&name)
+            || entry.kind() != Some(git2::ObjectType::Blob)
+        {
+            return Err(Error::State(format!(
+                "op commit {} has an unexpected entry '{}'",
+                commit.id(),
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn attachment_ref(id: &AttachmentId) -> String {
+    format!("refs/attachments/{}", to_hex(id))
+}
+
+impl AttachmentWriter<'_> {
+    /// Store what's been written as attachment `id`, an attachment we already have is kept as is.
+    pub fn finish(mut self, id: &AttachmentId) -> Result<()> {
+        if !self.buf.is_empty() {
+            let chunk = self.repo.blob(&self.buf)?;
+            self.chunks.push(chunk);
+        }
+
+        let mut builder = self.repo.treebuilder(None)?;
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            // zero padded so that the tree lists chunks in order
+            builder.insert(format!("{:08}", i), *chunk, 0o100_644)?;
+        }
+        let tree = self.repo.find_tree(builder.write()?)?;
+        let commit = self
+            .repo
+            .commit(None, &self.sig, &self.sig, "attachment", &tree, &[])?;
+
+        match self
+            .repo
+            .reference(&attachment_ref(id), commit, false, "hermitdb: attachment")
+        {
+            Err(e) if e.code() == git2::ErrorCode::Exists => Ok(()),
+            Err(e) => Err(Error::Git(e)),
+            Ok(_) => Ok(()),
+        }
+    }
+}
+
+impl Write for AttachmentWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(bytes);
+        while self.buf.len() >= ATTACHMENT_CHUNK_LEN {
+            let chunk = self
+                .repo
+                .blob(&self.buf[..ATTACHMENT_CHUNK_LEN])
+                .map_err(io::Error::other)?;
+            self.chunks.push(chunk);
+            self.buf.drain(..ATTACHMENT_CHUNK_LEN);
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for AttachmentReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(chunk) = &self.chunk {
+                let rest = &chunk.content()[self.pos..];
+                if !rest.is_empty() {
+                    let n = rest.len().min(buf.len());
+                    buf[..n].copy_from_slice(&rest[..n]);
+                    self.pos += n;
+                    return Ok(n);
+                }
+            }
+            match self.chunks.next() {
+                Some(oid) => {
+                    self.chunk = Some(self.repo.find_blob(oid).map_err(io::Error::other)?);
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Fails with `Error::HistoryRewritten` if anything was quarantined.
+fn rewritten_error(rewritten: Vec<String>) -> Result<()> {
+    if rewritten.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::HistoryRewritten(rewritten))
+    }
+}
+
+fn quarantine_ref(branch_name: &str) -> String {
+    format!("refs/hermitdb/quarantine/{}", branch_name)
+}
+
+fn local_ref(name: &str) -> String {
+    format!("refs/hermitdb/local/{}", name)
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
 }
 
 impl Remote {
@@ -373,6 +1779,7 @@ impl Remote {
             name,
             url,
             auth: Auth::UserPass { user, pass },
+            progress: None,
         }
     }
 
@@ -381,15 +1788,105 @@ impl Remote {
             name,
             url,
             auth: Auth::None,
+            progress: None,
         }
     }
 
+    /// The name of the remote, its fetched branches are tracked under `refs/remotes/<name>/`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Attach a callback that is fed `Progress` updates while syncing with this remote.
+    pub fn with_progress<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&Progress) -> bool + Send + 'static,
+    {
+        self.progress = Some(ProgressFn {
+            callback: Arc::new(Mutex::new(callback)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        });
+        self
+    }
+
     pub fn git_callbacks(&self) -> git2::RemoteCallbacks<'_> {
         let mut cbs = git2::RemoteCallbacks::new();
         cbs.credentials(move |_, _, _| match self.auth {
             Auth::None => panic!("It's a bug if this is ever called!"),
             Auth::UserPass { ref user, ref pass } => git2::Cred::userpass_plaintext(user, pass),
         });
+
+        if let Some(progress) = &self.progress {
+            progress.cancelled.store(false, Ordering::SeqCst);
+            cbs.transfer_progress(move |stats| {
+                progress.report(&Progress::Fetch {
+                    received_objects: stats.received_objects(),
+                    indexed_objects: stats.indexed_objects(),
+                    total_objects: stats.total_objects(),
+                    received_bytes: stats.received_bytes(),
+                })
+            });
+            cbs.push_transfer_progress(move |current, total, bytes| {
+                progress.report(&Progress::Push {
+                    current,
+                    total,
+                    bytes,
+                });
+            });
+        }
         cbs
     }
 }
+
+impl Remote {
+    fn report(&self, progress: &Progress) -> bool {
+        match &self.progress {
+            Some(callback) => callback.report(progress),
+            None => true,
+        }
+    }
+
+    /// libgit2 reports any callback failing as a `User` or `Callback` error,
+    /// only our progress callback asking to abort is a cancelled sync.
+    pub(crate) fn sync_error(&self, err: git2::Error) -> Error {
+        let aborted =
+            err.code() == git2::ErrorCode::User || err.class() == git2::ErrorClass::Callback;
+        let cancelled = self
+            .progress
+            .as_ref()
+            .is_some_and(|progress| progress.cancelled.load(Ordering::SeqCst));
+        if aborted && cancelled {
+            Error::SyncCancelled
+        } else {
+            Error::Git(err)
+        }
+    }
+}
+
+impl ProgressFn {
+    fn report(&self, progress: &Progress) -> bool {
+        // a poisoned lock means the callback panicked, we stop reporting to it.
+        let keep_going = match self.callback.lock() {
+            Ok(mut callback) => callback(progress),
+            Err(_) => true,
+        };
+        if !keep_going && matches!(progress, Progress::Fetch { .. }) {
+            self.cancelled.store(true, Ordering::SeqCst);
+        }
+        keep_going
+    }
+}
+
+impl Debug for ProgressFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProgressFn")
+    }
+}
+
+impl PartialEq for ProgressFn {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.callback, &other.callback)
+    }
+}
+
+impl Eq for ProgressFn {}
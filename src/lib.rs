@@ -7,6 +7,7 @@ pub mod log;
 pub mod memory_log;
 pub mod git_log;
 pub mod encrypted_git_log;
+pub mod relay;
 
 pub use crdts;
 pub use crate::db::DB;
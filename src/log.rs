@@ -1,7 +1,10 @@
 use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::path::Path;
 
 use crdts::{CmRDT, Actor};
 
+use crate::crypto::StorageCipher;
 use crate::error::Result;
 
 pub trait TaggedOp<C: CmRDT> {
@@ -11,14 +14,20 @@ pub trait TaggedOp<C: CmRDT> {
     fn op(&self) -> &C::Op;
 }
 
-pub trait LogReplicable<A: Actor, C: CmRDT> {
+/// Logs we can read ops from, but not necessarily write to.
+///
+/// Read-only replicas are only readable, see `LogReplicable` for logs we can write to.
+pub trait LogReadable<A: Actor, C: CmRDT> {
     type LoggedOp: Debug + TaggedOp<C>;
     type Remote;
 
     fn next(&self) -> Result<Option<Self::LoggedOp>>;
     fn ack(&mut self, logged_op: &Self::LoggedOp) -> Result<()>;
-    fn commit(&mut self, op: C::Op) -> Result<Self::LoggedOp>;
     fn pull(&mut self, remote: &Self::Remote) -> Result<()>;
+}
+
+pub trait LogReplicable<A: Actor, C: CmRDT>: LogReadable<A, C> {
+    fn commit(&mut self, op: C::Op) -> Result<Self::LoggedOp>;
     fn push(&self, remote: &mut Self::Remote) -> Result<()>;
 
     fn sync(&mut self, remote: &mut Self::Remote) -> Result<()> {
@@ -26,3 +35,48 @@ pub trait LogReplicable<A: Actor, C: CmRDT> {
         self.push(remote)
     }
 }
+
+/// Logs that can be carried between devices in a file, for when there's no remote to sync through.
+pub trait BundleReplicable {
+    /// Write our new ops into a bundle file at `path`.
+    fn export_bundle(&mut self, path: &Path) -> Result<()>;
+
+    /// Read in a bundle exported by another device, as if it were fetched from a remote.
+    fn import_bundle(&mut self, path: &Path) -> Result<()>;
+}
+
+/// Attachments are named by a hash of their content.
+pub type AttachmentId = [u8; 256 / 8];
+
+/// Logs that store large attachments outside of their ops.
+///
+/// Ops refer to attachments by id (see `data::BlobRef`), so an attachment is
+/// stored once no matter how many ops refer to it. Attachments aren't pulled
+/// with ops, they're fetched from a remote when they're first read.
+pub trait AttachmentStore {
+    type Remote;
+
+    /// Store everything read from `data`, returning its id and length.
+    fn put_attachment(&mut self, data: &mut dyn Read) -> Result<(AttachmentId, u64)>;
+    fn has_attachment(&self, id: &AttachmentId) -> Result<bool>;
+    /// Fetch an attachment that we don't have from `remote`.
+    fn fetch_attachment(&self, remote: &Self::Remote, id: &AttachmentId) -> Result<()>;
+    /// Write out the attachment's content, checking it against its id as we go.
+    fn read_attachment(&self, id: &AttachmentId, out: &mut dyn Write) -> Result<u64>;
+    fn attachments(&self) -> Result<Vec<AttachmentId>>;
+    /// Drop our copy of an attachment, remotes keep theirs.
+    fn remove_attachment(&mut self, id: &AttachmentId) -> Result<()>;
+}
+
+/// Logs that can drop their key material while they're not in use.
+pub trait Lockable {
+    type Credential;
+
+    /// Forget every key we hold, until we're unlocked ops can't be read or written.
+    fn lock(&mut self);
+    fn is_locked(&self) -> bool;
+    /// Recover our keys from `credential`, which must be for whoever we were before we locked.
+    fn unlock(&mut self, credential: Self::Credential) -> Result<()>;
+    /// The cipher that keeps the log's data encrypted on disk, see `map::Map::encrypted`.
+    fn storage_cipher(&self) -> Result<StorageCipher>;
+}
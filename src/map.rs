@@ -1,6 +1,7 @@
 use std::collections::{BTreeSet, HashMap};
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::sync::Arc;
 
 use bincode;
 use crdts::ctx::{AddCtx, ReadCtx, RmCtx};
@@ -8,6 +9,7 @@ use crdts::{Actor, CmRDT, CvRDT, Dot, ResetRemove, VClock};
 use serde_derive::{Deserialize, Serialize};
 use sled;
 
+use crate::crypto::StorageCipher;
 use crate::error::{Error, Result};
 
 /// Key Trait alias to reduce redundancy in type decl.
@@ -24,6 +26,7 @@ pub struct Map<K: Key, V: Val<A>, A: Actor> {
     // This clock stores the current version of the Map, it should
     // be greator or equal to all Entry clock's in the Map.
     sled: sled::Db,
+    storage: Storage,
     phantom_key: PhantomData<K>,
     phantom_val: PhantomData<V>,
     phantom_actor: PhantomData<A>,
@@ -40,12 +43,23 @@ pub struct Entry<V: Val<A>, A: Actor> {
 
 pub struct Iter<K: Key, V: Val<A>, A: Actor> {
     iter: sled::Iter,
+    storage: Storage,
     clock: VClock<A>,
     phantom_key: PhantomData<K>,
     phantom_val: PhantomData<V>,
     phantom_actor: PhantomData<A>,
 }
 
+/// How a Map stores its entries on disk.
+#[derive(Debug, Clone)]
+enum Storage {
+    Plain,
+    /// Keys are HMAC'd and values sealed, see `Map::encrypted`.
+    Encrypted(Arc<StorageCipher>),
+    /// Encrypted, but we've dropped the keys, see `Map::lock`.
+    Locked,
+}
+
 /// Operations which can be applied to the Map CRDT
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Op<K: Key, V: Val<A>, A: Actor> {
@@ -80,19 +94,22 @@ where
     fn next(&mut self) -> Option<Self::Item> {
         match self.iter.next() {
             Some(Ok((k, v))) => {
-                let res = bincode::deserialize(&k[KEY_PREFIX.len()..]).and_then(|key: K| {
-                    let entry: Entry<V, A> = bincode::deserialize(&v)?;
-                    Ok((
-                        key,
-                        ReadCtx {
-                            add_clock: self.clock.clone(),
-                            rm_clock: entry.clock,
-                            val: entry.val,
-                        },
-                    ))
-                });
-
-                Some(res.map_err(Error::from))
+                let res = self
+                    .storage
+                    .cipher()
+                    .and_then(|cipher| decode_entry(cipher, &k, &v))
+                    .map(|(key, entry)| {
+                        (
+                            key,
+                            ReadCtx {
+                                add_clock: self.clock.clone(),
+                                rm_clock: entry.clock,
+                                val: entry.val,
+                            },
+                        )
+                    });
+
+                Some(res)
             }
             Some(Err(e)) => Some(Err(Error::from(e))),
             None => None,
@@ -113,66 +130,175 @@ where
         Ok(())
     }
 
+    /// Panics if the op can't be applied, e.g. to a locked map, see `Map::apply`.
     fn apply(&mut self, op: Self::Op) {
-        match op {
-            Op::Nop => { /* do nothing */ }
-            Op::Rm { clock, key } => {
-                self.apply_rm(key, &clock).unwrap();
-                self.sled.flush().unwrap();
-            }
-            Op::Up { dot, key, op } => {
-                let mut map_clock = self.get_clock().unwrap();
-                if map_clock.get(&dot.actor) >= dot.counter {
-                    // we've seen this op already
-                    return;
-                }
+        Map::apply(self, op).unwrap()
+    }
+}
 
-                let key_bytes = self.key_bytes(&key).unwrap();
+/// Key prefix is added to the front of all user added keys
+const KEY_PREFIX: [u8; 1] = [1];
 
-                let mut entry = match self.sled.get(&key_bytes).unwrap() {
-                    Some(bytes) => bincode::deserialize(&bytes).unwrap(),
-                    None => Entry {
-                        clock: VClock::new(),
-                        val: V::default(),
-                    },
-                };
+/// Meta prefix is added to the front of all housekeeping keys created by the database
+const META_PREFIX: [u8; 1] = [0];
 
-                entry.clock.apply(dot.clone());
-                entry.val.apply(op);
-                let entry_bytes = bincode::serialize(&entry).unwrap();
-                self.sled.insert(key_bytes, entry_bytes).unwrap();
+/// Encrypted storage records the key check of its storage keys under this meta key.
+const STORAGE_CHECK: &[u8] = b"storage_check";
 
-                map_clock.apply(dot);
-                self.put_clock(map_clock).unwrap();
-                self.apply_deferred().unwrap();
-                self.sled.flush().unwrap();
-            }
+impl Storage {
+    /// The cipher for encrypted storage, None if entries are stored as is.
+    fn cipher(&self) -> Result<Option<&StorageCipher>> {
+        match self {
+            Storage::Plain => Ok(None),
+            Storage::Encrypted(cipher) => Ok(Some(cipher)),
+            Storage::Locked => Err(Error::Locked),
         }
     }
 }
 
-/// Key prefix is added to the front of all user added keys
-const KEY_PREFIX: [u8; 1] = [1];
+/// Check that storage in `sled` was encrypted with `storage`, marking new storage as ours.
+fn check_storage(sled: &sled::Db, storage: &StorageCipher) -> Result<()> {
+    let mut check_key = META_PREFIX.to_vec();
+    check_key.extend(STORAGE_CHECK);
+    let check = storage.key_check();
+    match sled.get(&check_key)? {
+        Some(stored) if stored.as_ref() == check => (),
+        Some(_) => {
+            return Err(Error::Crypto(
+                "This storage was encrypted with different keys".into(),
+            ));
+        }
+        None if sled.range(KEY_PREFIX..).next().is_some() => {
+            return Err(Error::State(
+                "This storage already holds unencrypted entries".into(),
+            ));
+        }
+        None => {
+            sled.insert(check_key, &check[..])?;
+            sled.flush()?;
+        }
+    }
+    Ok(())
+}
 
-/// Meta prefix is added to the front of all housekeeping keys created by the database
-const META_PREFIX: [u8; 1] = [0];
+/// Decode a stored entry, encrypted entries carry their key since it's HMAC'd on disk.
+fn decode_entry<K, V, A>(
+    storage: Option<&StorageCipher>,
+    stored_key: &[u8],
+    stored: &[u8],
+) -> Result<(K, Entry<V, A>)>
+where
+    K: Key + serde::de::DeserializeOwned,
+    A: Actor + serde::de::DeserializeOwned,
+    V: Val<A> + serde::de::DeserializeOwned,
+{
+    match storage {
+        Some(storage) => Ok(bincode::deserialize(&storage.open(stored_key, stored)?)?),
+        None => {
+            let key = bincode::deserialize(&stored_key[KEY_PREFIX.len()..])?;
+            Ok((key, bincode::deserialize(stored)?))
+        }
+    }
+}
 
 impl<K, V, A> Map<K, V, A>
 where
     K: Key + Debug + serde::Serialize + serde::de::DeserializeOwned,
-    A: Actor + serde::Serialize + serde::de::DeserializeOwned,
+    A: Actor + Debug + serde::Serialize + serde::de::DeserializeOwned,
     V: Val<A> + Debug + serde::Serialize + serde::de::DeserializeOwned,
 {
     /// Constructs an empty Map
     pub fn new(sled: sled::Db) -> Map<K, V, A> {
         Map {
             sled,
+            storage: Storage::Plain,
+            phantom_key: PhantomData,
+            phantom_val: PhantomData,
+            phantom_actor: PhantomData,
+        }
+    }
+
+    /// Constructs a Map that encrypts everything it stores in `sled`.
+    ///
+    /// Keys are stored as HMACs, so iteration order doesn't follow key order.
+    /// A sled that's been used without encryption, or with other storage keys,
+    /// is refused. Anyone holding `storage`'s keys can read a copy of the sled,
+    /// `encrypted_git_log::Log::storage_cipher` gives keys only this device holds.
+    pub fn encrypted(sled: sled::Db, storage: StorageCipher) -> Result<Map<K, V, A>> {
+        check_storage(&sled, &storage)?;
+        Ok(Map {
+            sled,
+            storage: Storage::Encrypted(Arc::new(storage)),
             phantom_key: PhantomData,
             phantom_val: PhantomData,
             phantom_actor: PhantomData,
+        })
+    }
+
+    /// Drop the keys of encrypted storage, reads and writes fail until we're unlocked.
+    ///
+    /// Maps that aren't encrypted have no keys to drop and stay readable.
+    pub fn lock(&mut self) {
+        if let Storage::Encrypted(_) = self.storage {
+            self.storage = Storage::Locked;
         }
     }
 
+    /// Take back the keys of a locked map, they must be the keys it was encrypted with.
+    pub fn unlock(&mut self, storage: StorageCipher) -> Result<()> {
+        if let Storage::Locked = self.storage {
+            check_storage(&self.sled, &storage)?;
+            self.storage = Storage::Encrypted(Arc::new(storage));
+        }
+        Ok(())
+    }
+
+    /// Apply an op to the map.
+    ///
+    /// A locked map can't apply ops, they fail with `Error::Locked` and
+    /// must be applied again once the map is unlocked.
+    pub fn apply(&mut self, op: Op<K, V, A>) -> Result<()> {
+        if self.is_locked() {
+            return Err(Error::Locked);
+        }
+        match op {
+            Op::Nop => { /* do nothing */ }
+            Op::Rm { clock, key } => {
+                self.apply_rm(key, &clock)?;
+                self.sled.flush()?;
+            }
+            Op::Up { dot, key, op } => {
+                let mut map_clock = self.get_clock()?;
+                if map_clock.get(&dot.actor) >= dot.counter {
+                    // we've seen this op already
+                    return Ok(());
+                }
+
+                let mut entry = match self.read_entry(&key)? {
+                    Some(entry) => entry,
+                    None => Entry {
+                        clock: VClock::new(),
+                        val: V::default(),
+                    },
+                };
+
+                entry.clock.apply(dot.clone());
+                entry.val.apply(op);
+                self.write_entry(&key, &entry)?;
+
+                map_clock.apply(dot);
+                self.put_clock(map_clock)?;
+                self.apply_deferred()?;
+                self.sled.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_locked(&self) -> bool {
+        matches!(self.storage, Storage::Locked)
+    }
+
     pub fn key_bytes(&self, key: &K) -> Result<Vec<u8>> {
         let mut bytes = bincode::serialize(&key)?;
         bytes.splice(0..0, KEY_PREFIX.iter().cloned());
@@ -184,16 +310,75 @@ where
         key
     }
 
-    /// Get a value stored under a key
-    pub fn get(&self, key: &K) -> Result<ReadCtx<Option<V>, A>> {
+    /// The sled key an entry is stored under.
+    fn stored_key(&self, key: &K) -> Result<Vec<u8>> {
         let key_bytes = self.key_bytes(key)?;
+        match self.storage.cipher()? {
+            Some(storage) => {
+                let mut stored = storage.mac_key(&key_bytes);
+                stored.splice(0..0, KEY_PREFIX.iter().cloned());
+                Ok(stored)
+            }
+            None => Ok(key_bytes),
+        }
+    }
 
-        let entry_opt = if let Some(val_bytes) = self.sled.get(&key_bytes)? {
-            let entry: Entry<V, A> = bincode::deserialize(&val_bytes)?;
-            Some(entry)
-        } else {
-            None
+    fn read_entry(&self, key: &K) -> Result<Option<Entry<V, A>>> {
+        let stored_key = self.stored_key(key)?;
+        match self.sled.get(&stored_key)? {
+            Some(stored) => {
+                let (_, entry): (K, _) =
+                    decode_entry(self.storage.cipher()?, &stored_key, &stored)?;
+                Ok(Some(entry))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn write_entry(&self, key: &K, entry: &Entry<V, A>) -> Result<()> {
+        let stored_key = self.stored_key(key)?;
+        let stored = match self.storage.cipher()? {
+            Some(storage) => storage.seal(&stored_key, &bincode::serialize(&(key, entry))?)?,
+            None => bincode::serialize(entry)?,
         };
+        self.sled.insert(stored_key, stored)?;
+        Ok(())
+    }
+
+    fn remove_entry(&self, key: &K) -> Result<Option<Entry<V, A>>> {
+        let stored_key = self.stored_key(key)?;
+        match self.sled.remove(&stored_key)? {
+            Some(stored) => {
+                let (_, entry): (K, _) =
+                    decode_entry(self.storage.cipher()?, &stored_key, &stored)?;
+                Ok(Some(entry))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn read_meta(&self, name: &[u8]) -> Result<Option<Vec<u8>>> {
+        let meta_key = self.meta_key_bytes(name.to_vec());
+        match (self.sled.get(&meta_key)?, self.storage.cipher()?) {
+            (Some(stored), Some(storage)) => Ok(Some(storage.open(&meta_key, &stored)?.to_vec())),
+            (Some(stored), None) => Ok(Some(stored.to_vec())),
+            (None, _) => Ok(None),
+        }
+    }
+
+    fn write_meta(&self, name: &[u8], bytes: &[u8]) -> Result<()> {
+        let meta_key = self.meta_key_bytes(name.to_vec());
+        let stored = match self.storage.cipher()? {
+            Some(storage) => storage.seal(&meta_key, bytes)?,
+            None => bytes.to_vec(),
+        };
+        self.sled.insert(meta_key, stored)?;
+        Ok(())
+    }
+
+    /// Get a value stored under a key
+    pub fn get(&self, key: &K) -> Result<ReadCtx<Option<V>, A>> {
+        let entry_opt = self.read_entry(key)?;
 
         Ok(ReadCtx {
             add_clock: self.get_clock()?,
@@ -241,6 +426,7 @@ where
     pub fn iter(&self) -> Result<Iter<K, V, A>> {
         Ok(Iter {
             iter: self.sled.range(KEY_PREFIX..),
+            storage: self.storage.clone(),
             clock: self.get_clock()?,
             phantom_key: PhantomData,
             phantom_val: PhantomData,
@@ -279,22 +465,18 @@ where
             self.put_deferred(deferred)?;
         }
 
-        let key_bytes = self.key_bytes(&key)?;
-        if let Some(entry_bytes) = self.sled.remove(&key_bytes)? {
-            let mut entry: Entry<V, A> = bincode::deserialize(&entry_bytes)?;
+        if let Some(mut entry) = self.remove_entry(&key)? {
             entry.clock = entry.clock.clone_without(clock);
             if !entry.clock.is_empty() {
                 entry.val.reset_remove(clock);
-                let new_entry_bytes = bincode::serialize(&entry)?;
-                self.sled.insert(key_bytes, new_entry_bytes)?;
+                self.write_entry(&key, &entry)?;
             }
         }
         Ok(())
     }
 
     fn get_clock(&self) -> Result<VClock<A>> {
-        let clock_key = self.meta_key_bytes(b"clock".to_vec());
-        let clock = if let Some(clock_bytes) = self.sled.get(&clock_key)? {
+        let clock = if let Some(clock_bytes) = self.read_meta(b"clock")? {
             bincode::deserialize(&clock_bytes)?
         } else {
             VClock::new()
@@ -303,15 +485,12 @@ where
     }
 
     fn put_clock(&self, clock: VClock<A>) -> Result<()> {
-        let clock_key = self.meta_key_bytes(b"clock".to_vec());
         let clock_bytes = bincode::serialize(&clock)?;
-        self.sled.insert(clock_key, clock_bytes)?;
-        Ok(())
+        self.write_meta(b"clock", &clock_bytes)
     }
 
     fn get_deferred(&self) -> Result<HashMap<VClock<A>, BTreeSet<K>>> {
-        let deferred_key = self.meta_key_bytes(b"deferred".to_vec());
-        if let Some(deferred_bytes) = self.sled.get(&deferred_key)? {
+        if let Some(deferred_bytes) = self.read_meta(b"deferred")? {
             let deferred = bincode::deserialize(&deferred_bytes)?;
             Ok(deferred)
         } else {
@@ -320,16 +499,15 @@ where
     }
 
     fn put_deferred(&mut self, deferred: HashMap<VClock<A>, BTreeSet<K>>) -> Result<()> {
-        let deferred_key = self.meta_key_bytes(b"deferred".to_vec());
         let deferred_bytes = bincode::serialize(&deferred)?;
-        self.sled.insert(deferred_key, deferred_bytes)?;
-        Ok(())
+        self.write_meta(b"deferred", &deferred_bytes)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::crypto::KeyHierarchy;
     use crdts::{self, map, mvreg, MVReg};
 
     type TestActor = u8;
@@ -393,16 +571,16 @@ mod test {
         let mut m1: TestMap = mk_map();
         let mut m2: TestMap = mk_map();
 
-        m1.apply(op_actor1.clone());
-        m2.apply(op_1_actor2.clone());
-        m2.apply(op_2_actor2.clone());
+        m1.apply(op_actor1.clone()).unwrap();
+        m2.apply(op_1_actor2.clone()).unwrap();
+        m2.apply(op_2_actor2.clone()).unwrap();
 
         // m1 <- m2
-        m1.apply(op_1_actor2);
-        m1.apply(op_2_actor2);
+        m1.apply(op_1_actor2).unwrap();
+        m1.apply(op_2_actor2).unwrap();
 
         // m2 <- m1
-        m2.apply(op_actor1);
+        m2.apply(op_actor1).unwrap();
 
         // m1 <- m2 == m2 <- m1
         assert_eq!(
@@ -410,4 +588,94 @@ mod test {
             m2.iter().unwrap().map(|e| e.unwrap()).collect::<Vec<_>>()
         );
     }
+
+    #[test]
+    fn test_encrypted_storage_hides_keys_and_values() {
+        type SecretMap = Map<String, MVReg<String, u8>, u8>;
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let keys = KeyHierarchy::from_secret(b"vault data key");
+        let mut m = SecretMap::encrypted(sled.clone(), StorageCipher::new(&keys)).unwrap();
+
+        let ctx = m.get(&"username".to_string()).unwrap().derive_add_ctx(1);
+        let op = m
+            .update("username".to_string(), ctx, |reg, ctx| {
+                reg.write("alice@example.com".to_string(), ctx)
+            })
+            .unwrap();
+        m.apply(op).unwrap();
+
+        assert_eq!(
+            m.get(&"username".to_string())
+                .unwrap()
+                .val
+                .map(|reg| reg.read().val),
+            Some(vec!["alice@example.com".to_string()])
+        );
+        assert_eq!(
+            m.iter().unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>(),
+            vec!["username".to_string()]
+        );
+        for stored in sled.iter() {
+            let (k, v) = stored.unwrap();
+            for plaintext in [&b"username"[..], &b"alice"[..]] {
+                assert!(!k.windows(plaintext.len()).any(|w| w == plaintext));
+                assert!(!v.windows(plaintext.len()).any(|w| w == plaintext));
+            }
+        }
+
+        // the same keys open it again, other keys and unencrypted maps don't
+        let m = SecretMap::encrypted(sled.clone(), StorageCipher::new(&keys)).unwrap();
+        assert_eq!(m.iter().unwrap().count(), 1);
+        let other_keys = KeyHierarchy::from_secret(b"another data key");
+        assert!(matches!(
+            SecretMap::encrypted(sled, StorageCipher::new(&other_keys)),
+            Err(Error::Crypto(_))
+        ));
+
+        let plain_sled = sled::Config::new().temporary(true).open().unwrap();
+        let mut plain = SecretMap::new(plain_sled.clone());
+        let ctx = plain
+            .get(&"username".to_string())
+            .unwrap()
+            .derive_add_ctx(1);
+        let op = plain
+            .update("username".to_string(), ctx, |reg, ctx| {
+                reg.write("alice".to_string(), ctx)
+            })
+            .unwrap();
+        plain.apply(op).unwrap();
+        assert!(matches!(
+            SecretMap::encrypted(plain_sled, StorageCipher::new(&keys)),
+            Err(Error::State(_))
+        ));
+    }
+
+    #[test]
+    fn test_locked_map_refuses_ops_until_unlocked() {
+        type SecretMap = Map<String, MVReg<String, u8>, u8>;
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let keys = KeyHierarchy::from_secret(b"vault data key");
+        let mut m = SecretMap::encrypted(sled, StorageCipher::new(&keys)).unwrap();
+
+        let ctx = m.get(&"username".to_string()).unwrap().derive_add_ctx(1);
+        let op = m
+            .update("username".to_string(), ctx, |reg, ctx| {
+                reg.write("alice".to_string(), ctx)
+            })
+            .unwrap();
+        m.lock();
+        assert!(matches!(m.apply(op.clone()), Err(Error::Locked)));
+        assert!(matches!(m.get(&"username".to_string()), Err(Error::Locked)));
+
+        m.unlock(StorageCipher::new(&keys)).unwrap();
+        assert_eq!(m.get(&"username".to_string()).unwrap().val, None);
+        m.apply(op).unwrap();
+        assert_eq!(
+            m.get(&"username".to_string())
+                .unwrap()
+                .val
+                .map(|reg| reg.read().val),
+            Some(vec!["alice".to_string()])
+        );
+    }
 }
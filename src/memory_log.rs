@@ -4,7 +4,7 @@ use std::fmt::{self, Debug};
 use crdts::{Actor, CmRDT};
 
 use crate::error::Result;
-use crate::log::{LogReplicable, TaggedOp};
+use crate::log::{LogReadable, LogReplicable, TaggedOp};
 
 pub struct Log<A: Actor, C: CmRDT> {
     actor: A,
@@ -42,7 +42,7 @@ impl<A: Actor, C: CmRDT> TaggedOp<C> for LoggedOp<A, C> {
     }
 }
 
-impl<A: Actor + Debug, C: CmRDT> LogReplicable<A, C> for Log<A, C>
+impl<A: Actor + Debug, C: CmRDT> LogReadable<A, C> for Log<A, C>
 where
     C::Op: Debug + Clone,
 {
@@ -81,21 +81,6 @@ where
         Ok(())
     }
 
-    fn commit(&mut self, op: C::Op) -> Result<Self::LoggedOp> {
-        let log = self
-            .logs
-            .entry(self.actor.clone())
-            .or_insert_with(|| (0, Vec::new()));
-
-        log.1.push(op.clone());
-
-        Ok(LoggedOp {
-            actor: self.actor.clone(),
-            index: log.0,
-            op,
-        })
-    }
-
     fn pull(&mut self, remote: &Self::Remote) -> Result<()> {
         for (actor, (_, log)) in remote.logs.iter() {
             let entry = self
@@ -111,6 +96,26 @@ where
         }
         Ok(())
     }
+}
+
+impl<A: Actor + Debug, C: CmRDT> LogReplicable<A, C> for Log<A, C>
+where
+    C::Op: Debug + Clone,
+{
+    fn commit(&mut self, op: C::Op) -> Result<Self::LoggedOp> {
+        let log = self
+            .logs
+            .entry(self.actor.clone())
+            .or_insert_with(|| (0, Vec::new()));
+
+        log.1.push(op.clone());
+
+        Ok(LoggedOp {
+            actor: self.actor.clone(),
+            index: log.0,
+            op,
+        })
+    }
 
     fn push(&self, remote: &mut Self::Remote) -> Result<()> {
         remote.pull(self)
@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+
+use crate::error::{Error, Result};
+use crate::git_log::{self, Remote, VerifyReport};
+
+/// A backup node that stores a log and relays it between remotes, without being able to read it.
+///
+/// Relays have no actor and hold no keys, so they work just as well for
+/// encrypted vaults. Ops are never decoded, new commits are only checked to
+/// be shaped like the commits logs write and to move their branch forward.
+pub struct Relay {
+    repo: git2::Repository,
+}
+
+impl Relay {
+    pub fn new(repo: git2::Repository) -> Self {
+        Relay { repo }
+    }
+
+    /// Fetch everything on `remote`, taking in the branches that moved forward.
+    ///
+    /// A branch with a malformed new commit, or whose history was rewritten,
+    /// is left where it was. Once every branch has been looked at, those we
+    /// refused are named in an `Error::PullRefused` along with why.
+    pub fn pull(&mut self, remote: &Remote) -> Result<()> {
+        git_log::fetch(&self.repo, remote)?;
+        self.fetch_attachments(remote)?;
+
+        let mut refused = Vec::new();
+        for (branch, remote_tip) in self.tips(&format!("refs/remotes/{}/", remote.name()))? {
+            if let Err(e) = self.advance(&branch, remote_tip) {
+                refused.push((branch, e));
+            }
+        }
+        match refused.is_empty() {
+            true => Ok(()),
+            false => Err(Error::PullRefused(refused)),
+        }
+    }
+
+    /// Push every branch and attachment that `remote` is missing or behind on.
+    pub fn push(&self, remote: &mut Remote) -> Result<()> {
+        let mut refs = Vec::new();
+        for (branch, tip) in self.tips("refs/heads/")? {
+            let tracking = format!("refs/remotes/{}/{}", remote.name(), branch);
+            let remote_tip = self
+                .repo
+                .find_reference(&tracking)
+                .ok()
+                .and_then(|r| r.target());
+            let behind = match remote_tip {
+                Some(remote_tip) => {
+                    remote_tip != tip && self.repo.graph_descendant_of(tip, remote_tip)?
+                }
+                None => true,
+            };
+            if behind {
+                refs.push(format!("refs/heads/{}", branch));
+            }
+        }
+        let mut attachments = Vec::new();
+        for (hex, _) in self.attachment_tips("refs/attachments/")? {
+            let tracking = format!("refs/remotes/{}/attachments/{}", remote.name(), hex);
+            if self.repo.find_reference(&tracking).is_err() {
+                attachments.push(hex);
+            }
+        }
+        refs.extend(
+            attachments
+                .iter()
+                .map(|hex| format!("refs/attachments/{}", hex)),
+        );
+        if refs.is_empty() {
+            return Ok(());
+        }
+
+        let rejected = git_log::push_refs(&self.repo, remote, &refs)?;
+        for hex in attachments {
+            let name = format!("refs/attachments/{}", hex);
+            if !rejected.iter().any(|(refname, _)| *refname == name) {
+                let target = self.repo.refname_to_id(&name)?;
+                self.repo.reference(
+                    &format!("refs/remotes/{}/attachments/{}", remote.name(), hex),
+                    target,
+                    true,
+                    "hermitdb: remote has attachment",
+                )?;
+            }
+        }
+        if rejected.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::PushRejected(rejected))
+        }
+    }
+
+    /// Pull from every remote, then push to each of them whatever they're missing.
+    ///
+    /// A remote failing doesn't stop us relaying between the others. Once all
+    /// remotes have been tried, each failed pull and push is named by its
+    /// remote in an `Error::SyncFailed`, as `DB::sync_all` does.
+    pub fn relay(&mut self, remotes: &mut [Remote]) -> Result<()> {
+        let mut failed = Vec::new();
+        for remote in remotes.iter() {
+            if let Err(e) = self.pull(remote) {
+                failed.push((remote.name().to_string(), e));
+            }
+        }
+        for remote in remotes.iter_mut() {
+            if let Err(e) = self.push(remote) {
+                failed.push((remote.name().to_string(), e));
+            }
+        }
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(Error::SyncFailed(failed)),
+        }
+    }
+
+    /// Check that every commit on the actor branches we hold is shaped like an op commit.
+    ///
+    /// This is as far as `git_log::Log::verify` goes without reading the ops.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        git_log::verify_actor_branches(&self.repo, |_, oid, seq| {
+            git_log::check_op_commit(&self.repo.find_commit(oid)?)?;
+            Ok(seq)
+        })
+    }
+
+    /// Move `branch` forward to `remote_tip`, checking each commit we've not relayed before.
+    fn advance(&self, branch: &str, remote_tip: git2::Oid) -> Result<()> {
+        let branch_ref = format!("refs/heads/{}", branch);
+        let local_tip = self
+            .repo
+            .find_reference(&branch_ref)
+            .ok()
+            .and_then(|r| r.target());
+        if let Some(local_tip) = local_tip {
+            if local_tip == remote_tip || self.repo.graph_descendant_of(local_tip, remote_tip)? {
+                return Ok(()); // the remote is behind us
+            }
+            if !self.repo.graph_descendant_of(remote_tip, local_tip)? {
+                return Err(Error::HistoryRewritten(vec![branch.to_string()]));
+            }
+        }
+
+        let mut next = Some(remote_tip);
+        while let Some(oid) = next.filter(|oid| Some(*oid) != local_tip) {
+            let commit = self.repo.find_commit(oid)?;
+            if branch.starts_with("actor_") {
+                git_log::check_op_commit(&commit)?;
+            } else {
+                check_meta_commit(&commit)?;
+            }
+            next = commit.parent_id(0).ok();
+        }
+        self.repo
+            .reference(&branch_ref, remote_tip, true, "hermitdb: relay")?;
+        Ok(())
+    }
+
+    /// Fetch the attachments we don't have yet from `remote`.
+    fn fetch_attachments(&self, remote: &Remote) -> Result<()> {
+        let mut git_remote = self.repo.find_remote(remote.name())?;
+        let refspec = format!(
+            "+refs/attachments/*:refs/remotes/{}/attachments/*",
+            remote.name()
+        );
+        let mut fetch_opt = git2::FetchOptions::new();
+        fetch_opt.remote_callbacks(remote.git_callbacks());
+        git_remote
+            .fetch(&[&refspec], Some(&mut fetch_opt), None)
+            .map_err(|e| remote.sync_error(e))?;
+
+        let tracking = format!("refs/remotes/{}/attachments/", remote.name());
+        for (hex, oid) in self.attachment_tips(&tracking)? {
+            let name = format!("refs/attachments/{}", hex);
+            if self.repo.find_reference(&name).is_ok() {
+                continue; // attachments never change, we keep the copy we have
+            }
+            check_attachment_commit(&self.repo.find_commit(oid)?)?;
+            self.repo
+                .reference(&name, oid, false, "hermitdb: relay attachment")?;
+        }
+        Ok(())
+    }
+
+    /// The tips of the actor and meta branches under a ref prefix, keyed by branch name.
+    fn tips(&self, prefix: &str) -> Result<BTreeMap<String, git2::Oid>> {
+        let mut tips = BTreeMap::new();
+        for glob in ["actor_*", "meta_*"] {
+            for reference in self.repo.references_glob(&format!("{}{}", prefix, glob))? {
+                let reference = reference?;
+                let name = reference.name().ok_or(Error::BranchNameEncodingError)?;
+                let tip = reference
+                    .target()
+                    .ok_or(Error::BranchIsNotADirectReference)?;
+                tips.insert(name[prefix.len()..].to_string(), tip);
+            }
+        }
+        Ok(tips)
+    }
+
+    /// The attachments under a ref prefix, keyed by the hex of their id.
+    fn attachment_tips(&self, prefix: &str) -> Result<BTreeMap<String, git2::Oid>> {
+        let mut tips = BTreeMap::new();
+        for reference in self.repo.references_glob(&format!("{}*", prefix))? {
+            let reference = reference?;
+            let name = reference.name().ok_or(Error::BranchNameEncodingError)?;
+            let tip = reference
+                .target()
+                .ok_or(Error::BranchIsNotADirectReference)?;
+            tips.insert(name[prefix.len()..].to_string(), tip);
+        }
+        Ok(tips)
+    }
+}
+
+/// Meta commits hold a single `meta` blob and have at most one parent.
+fn check_meta_commit(commit: &git2::Commit) -> Result<()> {
+    let tree = commit.tree()?;
+    let is_blob = |entry: git2::TreeEntry| entry.kind() == Some(git2::ObjectType::Blob);
+    if commit.parent_count() > 1 || tree.len() != 1 || !tree.get_name("meta").is_some_and(is_blob) {
+        return Err(Error::State(format!(
+            "{} is not a meta commit",
+            commit.id()
+        )));
+    }
+    Ok(())
+}
+
+/// Attachment commits have no parents and hold only chunk blobs.
+fn check_attachment_commit(commit: &git2::Commit) -> Result<()> {
+    let tree = commit.tree()?;
+    let all_blobs = tree
+        .iter()
+        .all(|entry| entry.kind() == Some(git2::ObjectType::Blob));
+    if commit.parent_count() > 0 || !all_blobs {
+        return Err(Error::State(format!(
+            "{} is not an attachment commit",
+            commit.id()
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,65 @@
+//! Helpers shared by the integration tests, each test crate uses some of them.
+#![allow(dead_code)]
+
+use hermitdb::{
+    crdts::{orswot, Orswot},
+    crypto::{Aead, KdfAlgorithm, KDF},
+    encrypted_git_log::{self, Credential, Settings},
+    git_log,
+    log::{LogReadable, LogReplicable, TaggedOp},
+};
+
+pub type TActor = u8;
+pub type TSet = Orswot<u8, TActor>;
+pub type TLog = encrypted_git_log::Log<TActor, TSet>;
+
+pub fn mk_repo() -> (tempfile::TempDir, git2::Repository) {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = git2::Repository::init_bare(dir.path()).unwrap();
+    (dir, repo)
+}
+
+pub fn mk_remote(dir: &tempfile::TempDir) -> git_log::Remote {
+    mk_named_remote("remote", dir)
+}
+
+pub fn mk_named_remote(name: &str, dir: &tempfile::TempDir) -> git_log::Remote {
+    git_log::Remote::no_auth(name.into(), dir.path().to_str().unwrap().to_string())
+}
+
+/// Settings for a vault that isn't opaque, with a KDF cheap enough for tests.
+pub fn settings() -> Settings {
+    Settings {
+        kdf: KDF::generate(KdfAlgorithm::Argon2id {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        })
+        .unwrap(),
+        aead: Aead::default(),
+        opaque: false,
+    }
+}
+
+pub fn password(password: &[u8]) -> Credential {
+    Credential::Password(password.to_vec().into())
+}
+
+pub fn commit_add(log: &mut impl LogReplicable<TActor, TSet>, actor: TActor, member: u8) {
+    let set = TSet::new();
+    let op = set.add(member, set.read().derive_add_ctx(actor));
+    let tagged_op = log.commit(op).unwrap();
+    log.ack(&tagged_op).unwrap();
+}
+
+/// Read and ack every op we've not acked yet, returning the members they added.
+pub fn drain(log: &mut impl LogReadable<TActor, TSet>) -> Vec<u8> {
+    let mut members = Vec::new();
+    while let Some(op) = log.next().unwrap() {
+        if let orswot::Op::Add { members: added, .. } = op.op() {
+            members.extend(added);
+        }
+        log.ack(&op).unwrap();
+    }
+    members
+}
@@ -1,16 +1,23 @@
 use std::collections::BTreeMap;
+use std::time::Duration;
 
-use sled;
 use assert_matches::assert_matches;
 use hermitdb::{
-    data::{Prim, Data, Kind, Actor},
+    data::{Prim, Data, Kind, Actor, BlobRef},
     crdts,
+    encrypted_git_log::{self, MemberKey, Settings},
+    error::Error,
     memory_log,
+    git_log,
     map,
     db,
     DB
 };
 
+mod common;
+
+use common::password;
+
 fn mk_db(actor: Actor) -> DB<memory_log::Log<Actor, db::Map>> {
     let sled = sled::Config::new().temporary(true).open().unwrap();
     DB::new(memory_log::Log::new(actor), map::Map::new(sled))
@@ -134,3 +141,293 @@ fn test_sync() {
         Some(vec!["this is a reg for value 'y'".into()])
     );
 }
+
+#[test]
+fn test_sync_with_remote_set() {
+    let mut db_1 = mk_db(1);
+    let mut db_2 = mk_db(2);
+
+    assert!(db_1.add_remote("server", memory_log::Log::new(0)).is_none());
+    assert!(db_1.add_remote("usb", memory_log::Log::new(0)).is_none());
+    assert_eq!(db_1.remotes().collect::<Vec<_>>(), vec!["server", "usb"]);
+    assert_matches!(db_1.sync_remote("not a remote"), Err(_));
+
+    let add_ctx = db_1.get(&("x".into(), Kind::Reg)).unwrap().derive_add_ctx(1);
+    db_1.update(("x", Kind::Reg), add_ctx, |d, ctx| {
+        let reg = d.to_reg().unwrap();
+        reg.write("x".into(), ctx)
+    }).unwrap();
+
+    db_1.sync_all().unwrap();
+
+    // carry both remotes over to the second db, it sees the op through both
+    let server = db_1.remove_remote("server").unwrap();
+    let usb = db_1.remove_remote("usb").unwrap();
+    assert_eq!(db_1.remotes().count(), 0);
+
+    db_2.add_remote("server", server);
+    db_2.add_remote("usb", usb);
+    db_2.sync_remote("usb").unwrap();
+    db_2.sync_all().unwrap();
+
+    assert_eq!(
+        db_2.get(&("x".into(), Kind::Reg)).unwrap().val
+            .and_then(|data| data.to_reg().ok())
+            .map(|reg| reg.read().val),
+        Some(vec!["x".into()])
+    );
+}
+
+#[test]
+fn test_sync_bundle() {
+    let mk_git_db = |actor: Actor, dir: &tempfile::TempDir| {
+        let repo = git2::Repository::init_bare(dir.path()).unwrap();
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        DB::new(git_log::Log::new(actor, repo), map::Map::new(sled))
+    };
+    let (a_dir, b_dir, stick) = (
+        tempfile::tempdir().unwrap(),
+        tempfile::tempdir().unwrap(),
+        tempfile::tempdir().unwrap(),
+    );
+    let bundle = stick.path().join("vault.bundle");
+    let mut db_1 = mk_git_db(1, &a_dir);
+    let mut db_2 = mk_git_db(2, &b_dir);
+
+    let add_ctx = db_1.get(&("x".into(), Kind::Reg)).unwrap().derive_add_ctx(1);
+    db_1.update(("x", Kind::Reg), add_ctx, |d, ctx| {
+        let reg = d.to_reg().unwrap();
+        reg.write("x".into(), ctx)
+    }).unwrap();
+
+    db_1.sync_bundle(&bundle).unwrap();
+    db_2.sync_bundle(&bundle).unwrap();
+
+    assert_eq!(
+        db_2.get(&("x".into(), Kind::Reg)).unwrap().val
+            .and_then(|data| data.to_reg().ok())
+            .map(|reg| reg.read().val),
+        Some(vec!["x".into()])
+    );
+}
+
+#[test]
+fn test_sync_all_names_every_remote_that_failed() {
+    let (dir, remote_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    git2::Repository::init_bare(remote_dir.path()).unwrap();
+    let repo = git2::Repository::init_bare(dir.path()).unwrap();
+    let sled = sled::Config::new().temporary(true).open().unwrap();
+    let mut db = DB::new(git_log::Log::new(1, repo), map::Map::new(sled));
+    let missing = |name: &str| git_log::Remote::no_auth(
+        name.into(),
+        remote_dir.path().join(name).to_str().unwrap().to_string()
+    );
+    db.add_remote("lost usb", missing("lost usb"));
+    db.add_remote("remote", common::mk_remote(&remote_dir));
+    db.add_remote("unplugged usb", missing("unplugged usb"));
+
+    let add_ctx = db.get(&("x".into(), Kind::Reg)).unwrap().derive_add_ctx(1);
+    db.update(("x", Kind::Reg), add_ctx, |d, ctx| {
+        let reg = d.to_reg().unwrap();
+        reg.write("x".into(), ctx)
+    }).unwrap();
+
+    match db.sync_all() {
+        Err(Error::SyncFailed(failed)) => {
+            let names: Vec<_> = failed.iter().map(|(name, _)| name.as_str()).collect();
+            assert_eq!(names, vec!["lost usb", "unplugged usb"]);
+        }
+        other => panic!("expected the missing remotes to fail, got {:?}", other),
+    }
+
+    // the remote that's there was still synced
+    let remote_repo = git2::Repository::open(remote_dir.path()).unwrap();
+    assert!(remote_repo.find_reference("refs/heads/actor_1").is_ok());
+
+    db.remove_remote("unplugged usb");
+    assert_matches!(
+        db.pull_all(),
+        Err(Error::SyncFailed(failed)) if failed.len() == 1 && failed[0].0 == "lost usb"
+    );
+}
+
+#[test]
+fn test_attachments_are_fetched_when_read_and_gced_when_unreferenced() {
+    let mk_git_db = |actor: Actor, dir: &tempfile::TempDir| {
+        let repo = git2::Repository::init_bare(dir.path()).unwrap();
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        DB::new(git_log::Log::new(actor, repo), map::Map::new(sled))
+    };
+    let (a_dir, b_dir, remote_dir) = (
+        tempfile::tempdir().unwrap(),
+        tempfile::tempdir().unwrap(),
+        tempfile::tempdir().unwrap(),
+    );
+    git2::Repository::init_bare(remote_dir.path()).unwrap();
+    let remote = git_log::Remote::no_auth(
+        "remote".into(),
+        remote_dir.path().to_str().unwrap().to_string()
+    );
+    let mut db_1 = mk_git_db(1, &a_dir);
+    let mut db_2 = mk_git_db(2, &b_dir);
+    db_1.add_remote("remote", remote.clone());
+    db_2.add_remote("remote", remote);
+
+    // big enough to be split across a few chunks
+    let photo: Vec<u8> = (0..(2 * git_log::ATTACHMENT_CHUNK_LEN + 7)).map(|i| i as u8).collect();
+    let blob_ref = db_1.attach(&mut &photo[..]).unwrap();
+    assert_eq!(blob_ref.to_blob_ref().unwrap().len, photo.len() as u64);
+
+    let add_ctx = db_1.get(&("photo".into(), Kind::Reg)).unwrap().derive_add_ctx(1);
+    db_1.update(("photo", Kind::Reg), add_ctx, |d, ctx| {
+        let reg = d.to_reg().unwrap();
+        reg.write(blob_ref.clone(), ctx)
+    }).unwrap();
+    db_1.sync_all().unwrap();
+    db_2.sync_all().unwrap();
+
+    let stored = db_2.get(&("photo".into(), Kind::Reg)).unwrap().val
+        .and_then(|data| data.to_reg().ok())
+        .map(|reg| reg.read().val)
+        .unwrap();
+    assert_eq!(stored, vec![blob_ref.clone()]);
+
+    let mut read = Vec::new();
+    db_2.read_attachment(&stored[0].to_blob_ref().unwrap(), &mut read).unwrap();
+    assert_eq!(read, photo);
+
+    // once nothing refers to the photo, it's dropped
+    assert_eq!(db_1.gc_attachments().unwrap(), 0);
+    let add_ctx = db_1.get(&("photo".into(), Kind::Reg)).unwrap().derive_add_ctx(1);
+    db_1.update(("photo", Kind::Reg), add_ctx, |d, ctx| {
+        let reg = d.to_reg().unwrap();
+        reg.write(Prim::Blob(vec![1, 2, 3]), ctx)
+    }).unwrap();
+    assert_eq!(db_1.gc_attachments().unwrap(), 1);
+    assert_eq!(db_1.gc_attachments().unwrap(), 0);
+
+    // and is fetched back again if an older version is read
+    let mut read = Vec::new();
+    db_1.read_attachment(&blob_ref.to_blob_ref().unwrap(), &mut read).unwrap();
+    assert_eq!(read, photo);
+
+    // an attachment that's not written to an entry yet isn't dropped, unlike the photo
+    let sketch = db_1.attach(&mut &b"a sketch"[..]).unwrap();
+    assert_eq!(db_1.gc_attachments().unwrap(), 1);
+    let mut read = Vec::new();
+    db_1.read_attachment(&sketch.to_blob_ref().unwrap(), &mut read).unwrap();
+    assert_eq!(read, b"a sketch");
+
+    // the remotes we failed to fetch from are named
+    let missing = BlobRef { id: [0u8; 32], len: 1 };
+    let failed = db_2.read_attachment(&missing, &mut Vec::new());
+    assert_matches!(failed, Err(Error::State(msg)) if msg.contains("'remote'"));
+}
+
+#[test]
+fn test_locked_db_drops_its_keys_until_unlocked() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = git2::Repository::init_bare(dir.path()).unwrap();
+    let settings = Settings { opaque: true, ..common::settings() };
+    let log = encrypted_git_log::Log::init(1, repo, "alice", password(b"password"), settings).unwrap();
+    let sled = sled::Config::new().temporary(true).open().unwrap();
+    let map = map::Map::encrypted(sled, log.storage_cipher().unwrap()).unwrap();
+    let mut db = DB::new(log, map);
+
+    let add_ctx = db.get(&("x".into(), Kind::Reg)).unwrap().derive_add_ctx(1);
+    db.update(("x", Kind::Reg), add_ctx, |d, ctx| {
+        let reg = d.to_reg().unwrap();
+        reg.write("x".into(), ctx)
+    }).unwrap();
+
+    let rm_ctx = db.get(&("x".into(), Kind::Reg)).unwrap().derive_rm_ctx();
+    db.lock();
+    assert!(db.is_locked());
+    assert_matches!(db.get(&("x".into(), Kind::Reg)), Err(Error::Locked));
+    assert!(matches!(db.iter(), Err(Error::Locked)));
+    assert_matches!(db.rm(("x", Kind::Reg), rm_ctx), Err(Error::Locked));
+    assert_matches!(db.unlock(password(b"wrong password")), Err(Error::Crypto(_)));
+
+    db.unlock(password(b"password")).unwrap();
+    let read_x = |db: &DB<_>| db.get(&("x".into(), Kind::Reg)).unwrap().val
+        .and_then(|data: Data| data.to_reg().ok())
+        .map(|reg| reg.read().val);
+    assert_eq!(read_x(&db), Some(vec!["x".into()]));
+
+    // idle dbs lock themselves
+    db.lock_when_idle(Duration::from_millis(50));
+    assert!(!db.lock_if_idle());
+    std::thread::sleep(Duration::from_millis(100));
+    assert_matches!(db.get(&("x".into(), Kind::Reg)), Err(Error::Locked));
+    assert!(db.lock_if_idle());
+    db.unlock(password(b"password")).unwrap();
+    assert_eq!(read_x(&db), Some(vec!["x".into()]));
+}
+
+#[test]
+fn test_read_only_replica_pulls_without_an_actor_branch() {
+    let (a_dir, reader_dir, remote_dir) = (
+        tempfile::tempdir().unwrap(),
+        tempfile::tempdir().unwrap(),
+        tempfile::tempdir().unwrap(),
+    );
+    git2::Repository::init_bare(remote_dir.path()).unwrap();
+    let remote = git_log::Remote::no_auth(
+        "remote".into(),
+        remote_dir.path().to_str().unwrap().to_string()
+    );
+    let settings = common::settings();
+
+    let a_repo = git2::Repository::init_bare(a_dir.path()).unwrap();
+    let mut a_log = encrypted_git_log::Log::init(1, a_repo, "alice", password(b"alice pw"), settings).unwrap();
+    a_log.add_member("reader", MemberKey::Password(b"reader pw".to_vec().into())).unwrap();
+    let sled = sled::Config::new().temporary(true).open().unwrap();
+    let mut db_a = DB::new(a_log, map::Map::new(sled));
+    db_a.add_remote("remote", remote.clone());
+
+    let write_x = |db: &mut DB<_>, val: &str| {
+        let add_ctx = db.get(&("x".into(), Kind::Reg)).unwrap().derive_add_ctx(1);
+        db.update(("x", Kind::Reg), add_ctx, |d, ctx| {
+            let reg = d.to_reg().unwrap();
+            reg.write(val.into(), ctx)
+        }).unwrap();
+    };
+    write_x(&mut db_a, "x");
+    db_a.sync_all().unwrap();
+
+    let reader_repo = git2::Repository::init_bare(reader_dir.path()).unwrap();
+    git_log::fetch(&reader_repo, &remote).unwrap();
+    let reader_log = encrypted_git_log::ReadOnlyLog::open(reader_repo, password(b"reader pw")).unwrap();
+    let sled = sled::Config::new().temporary(true).open().unwrap();
+    let mut reader = DB::new(reader_log, map::Map::new(sled));
+    reader.add_remote("remote", remote);
+
+    let read_x = |db: &DB<encrypted_git_log::ReadOnlyLog<Actor, db::Map>>| {
+        db.get(&("x".into(), Kind::Reg)).unwrap().val
+            .and_then(|data: Data| data.to_reg().ok())
+            .map(|reg| reg.read().val)
+    };
+    reader.pull_all().unwrap();
+    assert_eq!(read_x(&reader), Some(vec!["x".into()]));
+
+    write_x(&mut db_a, "y");
+    db_a.sync_all().unwrap();
+    reader.pull_remote("remote").unwrap();
+    assert_eq!(read_x(&reader), Some(vec!["y".into()]));
+
+    // the reader only tracks alice's branch, it never made one of its own or trusted a signing key
+    let reader_repo = git2::Repository::open(reader_dir.path()).unwrap();
+    let remote_repo = git2::Repository::open(remote_dir.path()).unwrap();
+    let local_branches: Vec<String> = reader_repo.references_glob("refs/heads/*").unwrap()
+        .map(|r| r.unwrap().name().unwrap().to_string())
+        .filter(|name| !name.starts_with("refs/heads/meta_"))
+        .collect();
+    assert_eq!(local_branches, vec!["refs/heads/actor_1".to_string()]);
+    let trusted = |repo: &git2::Repository| repo.refname_to_id("refs/heads/meta_actor_keys").unwrap();
+    assert_eq!(trusted(&reader_repo), trusted(&remote_repo));
+
+    reader.lock();
+    assert_matches!(reader.pull_all(), Err(Error::Locked));
+    reader.unlock(password(b"reader pw")).unwrap();
+    assert_eq!(read_x(&reader), Some(vec!["y".into()]));
+}
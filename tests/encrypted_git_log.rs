@@ -0,0 +1,781 @@
+use std::num::NonZeroU32;
+
+use assert_matches::assert_matches;
+use hermitdb::{
+    crypto::{self, Aead, KdfAlgorithm, KeyHierarchy, SecretKey},
+    encrypted_git_log::{Credential, MemberKey, Settings},
+    error::Error,
+    git_log,
+    log::{AttachmentStore, Lockable, LogReadable, LogReplicable},
+};
+
+mod common;
+
+use common::*;
+
+fn root_key() -> KeyHierarchy {
+    crypto::KDF {
+        algorithm: KdfAlgorithm::Pbkdf2HmacSha256 {
+            iters: NonZeroU32::new(1).unwrap(),
+        },
+        salt: [0u8; 256 / 8],
+        requires_keyfile: false,
+    }
+    .derive_root(b"password", None)
+    .unwrap()
+}
+
+#[test]
+fn test_replayed_op_is_rejected() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, remote_repo) = mk_repo();
+
+    let mut a_log = TLog::new(1, a_repo, root_key());
+    let mut b_log = TLog::new(2, b_repo, root_key());
+
+    commit_add(&mut a_log, 1, 1);
+    commit_add(&mut a_log, 1, 2);
+    a_log.push(&mut mk_remote(&remote_dir)).unwrap();
+
+    // someone with write access to the remote replays the first op on top of the log
+    let tip = remote_repo
+        .find_reference("refs/heads/actor_1")
+        .unwrap()
+        .peel_to_commit()
+        .unwrap();
+    let first_op_tree = tip.parent(0).unwrap().tree().unwrap();
+    let sig = git2::Signature::now("mallory", "mallory@example.com").unwrap();
+    remote_repo
+        .commit(
+            Some("refs/heads/actor_1"),
+            &sig,
+            &sig,
+            "db op",
+            &first_op_tree,
+            &[&tip],
+        )
+        .unwrap();
+
+    b_log.pull(&mk_remote(&remote_dir)).unwrap();
+    for _ in 0..2 {
+        let op = b_log.next().unwrap().unwrap();
+        b_log.ack(&op).unwrap();
+    }
+    assert_matches!(b_log.next(), Err(Error::Crypto(_)));
+}
+
+#[test]
+fn test_change_password_and_rotate_data_key() {
+    let (a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+
+    let mut a_log = TLog::init(1, a_repo, "alice", password(b"old"), settings()).unwrap();
+    commit_add(&mut a_log, 1, 1);
+    let a_repo = || git2::Repository::open(a_dir.path()).unwrap();
+    assert_matches!(
+        TLog::init(1, a_repo(), "alice", password(b"old"), settings()).err(),
+        Some(Error::State(_))
+    );
+
+    a_log.change_password(b"new").unwrap();
+    assert_matches!(
+        TLog::open(1, a_repo(), password(b"old")).err(),
+        Some(Error::Crypto(_))
+    );
+
+    assert_eq!(a_log.rotate_data_key().unwrap(), 1);
+    commit_add(&mut a_log, 1, 2);
+    a_log.push(&mut mk_remote(&remote_dir)).unwrap();
+
+    // a fresh clone and the password is all a new device needs to open the vault
+    git_log::fetch(&b_repo, &mk_remote(&remote_dir)).unwrap();
+    let mut b_log = TLog::open(2, b_repo, password(b"new")).unwrap();
+    b_log.pull(&mk_remote(&remote_dir)).unwrap();
+
+    let mut seen = 0;
+    while let Some(op) = b_log.next().unwrap() {
+        b_log.ack(&op).unwrap();
+        seen += 1;
+    }
+    assert_eq!(seen, 2);
+}
+
+#[test]
+fn test_removed_members_cant_read_new_ops() {
+    let (_alice_dir, alice_repo) = mk_repo();
+    let (_bob_dir, bob_repo) = mk_repo();
+    let (_carol_dir, carol_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+    let mut remote = mk_remote(&remote_dir);
+
+    let carol_secret = SecretKey::generate().unwrap();
+
+    let mut alice =
+        TLog::init(1, alice_repo, "alice", password(b"alice pass"), settings()).unwrap();
+    commit_add(&mut alice, 1, 1);
+    alice
+        .add_member("bob", MemberKey::Password(b"bob pass".to_vec().into()))
+        .unwrap();
+    alice
+        .add_member("carol", MemberKey::public(&carol_secret).unwrap())
+        .unwrap();
+    assert_eq!(alice.members().unwrap(), vec!["alice", "bob", "carol"]);
+    alice.push(&mut remote).unwrap();
+
+    git_log::fetch(&bob_repo, &remote).unwrap();
+    let mut bob = TLog::open(2, bob_repo, password(b"bob pass")).unwrap();
+    bob.pull(&remote).unwrap();
+    assert_eq!(drain(&mut bob), vec![1]);
+
+    git_log::fetch(&carol_repo, &remote).unwrap();
+    let mut carol = TLog::open(3, carol_repo, Credential::SecretKey(carol_secret)).unwrap();
+    carol.pull(&remote).unwrap();
+    assert_eq!(drain(&mut carol), vec![1]);
+
+    assert_eq!(alice.remove_member("bob").unwrap(), 1);
+    commit_add(&mut alice, 1, 2);
+    alice.push(&mut remote).unwrap();
+
+    // bob is locked out of the new epoch
+    assert_matches!(bob.pull(&remote), Err(Error::Crypto(_)));
+    assert_matches!(bob.next().err(), Some(Error::Crypto(_)));
+
+    carol.pull(&remote).unwrap();
+    assert_eq!(drain(&mut carol), vec![2]);
+}
+
+#[test]
+fn test_keyring_written_without_the_data_keys_is_rejected() {
+    let (_alice_dir, alice_repo) = mk_repo();
+    let (carol_dir, carol_repo) = mk_repo();
+    let (mallory_dir, mallory_repo) = mk_repo();
+    let (remote_dir, remote_repo) = mk_repo();
+    let mut remote = mk_remote(&remote_dir);
+
+    let carol_secret = SecretKey::generate().unwrap();
+
+    let mut alice =
+        TLog::init(1, alice_repo, "alice", password(b"alice pass"), settings()).unwrap();
+    alice
+        .add_member("carol", MemberKey::public(&carol_secret).unwrap())
+        .unwrap();
+    commit_add(&mut alice, 1, 1);
+    alice.push(&mut remote).unwrap();
+
+    git_log::fetch(&carol_repo, &remote).unwrap();
+    let carol_credential =
+        || Credential::SecretKey(SecretKey::from_bytes(&carol_secret.to_bytes()));
+    let mut carol = TLog::open(3, carol_repo, carol_credential()).unwrap();
+    carol.pull(&remote).unwrap();
+    assert_eq!(drain(&mut carol), vec![1]);
+
+    // mallory can push to the remote but holds none of the vault's keys, she
+    // swaps in the keyring of a vault of her own that seals a new epoch to carol
+    let mut mallory = TLog::init(
+        9,
+        mallory_repo,
+        "mallory",
+        password(b"mallory pass"),
+        settings(),
+    )
+    .unwrap();
+    mallory
+        .add_member("carol", MemberKey::public(&carol_secret).unwrap())
+        .unwrap();
+    mallory.rotate_data_key().unwrap();
+    let mallory_meta =
+        git_log::Log::<TActor, TSet>::replica(git2::Repository::open(mallory_dir.path()).unwrap());
+    let mut forger = git_log::Log::<TActor, TSet>::replica(remote_repo);
+    for name in ["header", "keyring"] {
+        let forged = mallory_meta.read_meta(name).unwrap().unwrap();
+        forger.write_meta(name, &forged).unwrap();
+    }
+
+    assert_matches!(carol.pull(&remote), Err(Error::Crypto(_)));
+    assert_matches!(alice.pull(&remote), Err(Error::Crypto(_)));
+
+    // this device remembers the vault's first data key, reopening doesn't help
+    let carol_repo = git2::Repository::open(carol_dir.path()).unwrap();
+    assert_matches!(
+        TLog::open(3, carol_repo, carol_credential()).err(),
+        Some(Error::Crypto(_))
+    );
+}
+
+#[test]
+fn test_vault_without_header_cant_be_opened() {
+    let (a_dir, a_repo) = mk_repo();
+
+    let mut a_log = TLog::new(1, a_repo, root_key());
+    commit_add(&mut a_log, 1, 1);
+
+    let a_repo = git2::Repository::open(a_dir.path()).unwrap();
+    assert_matches!(
+        TLog::open(1, a_repo, password(b"password")).err(),
+        Some(Error::State(_))
+    );
+}
+
+#[test]
+fn test_vault_with_keyfile_needs_the_keyfile_to_open() {
+    let (a_dir, a_repo) = mk_repo();
+    let keyfile_dir = tempfile::tempdir().unwrap();
+    let keyfile_path = keyfile_dir.path().join("vault.key");
+    crypto::generate_keyfile(&keyfile_path).unwrap();
+    let keyfile = std::fs::read(&keyfile_path).unwrap();
+
+    let credential =
+        Credential::PasswordAndKeyfile(b"password".to_vec().into(), keyfile.clone().into());
+    let mut a_log = TLog::init(
+        1,
+        a_repo,
+        "alice",
+        credential,
+        Settings {
+            kdf: settings().kdf.with_keyfile(),
+            ..settings()
+        },
+    )
+    .unwrap();
+    commit_add(&mut a_log, 1, 1);
+
+    let a_repo = || git2::Repository::open(a_dir.path()).unwrap();
+    assert_matches!(
+        TLog::open(1, a_repo(), password(b"password")).err(),
+        Some(Error::MissingKeyfile)
+    );
+    let wrong_keyfile =
+        Credential::PasswordAndKeyfile(b"password".to_vec().into(), b"guess".to_vec().into());
+    assert_matches!(
+        TLog::open(1, a_repo(), wrong_keyfile).err(),
+        Some(Error::Crypto(_))
+    );
+    let credential = Credential::PasswordAndKeyfile(b"password".to_vec().into(), keyfile.into());
+    assert!(TLog::open(1, a_repo(), credential).is_ok());
+}
+
+#[test]
+fn test_opaque_vault_hides_actors_members_and_op_sizes() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, remote_repo) = mk_repo();
+    let mut remote = mk_remote(&remote_dir);
+
+    let settings = Settings {
+        opaque: true,
+        ..settings()
+    };
+    let mut a_log = TLog::init(42, a_repo, "alice", password(b"password"), settings).unwrap();
+    a_log
+        .add_member("bob", MemberKey::Password(b"bob pass".to_vec().into()))
+        .unwrap();
+    commit_add(&mut a_log, 42, 1);
+    let set = TSet::new();
+    let ctx = set.read().derive_add_ctx(42);
+    let big_op = set.add_all(2..20, ctx);
+    let tagged_op = a_log.commit(big_op).unwrap();
+    a_log.ack(&tagged_op).unwrap();
+    a_log.push(&mut remote).unwrap();
+
+    let branches: Vec<String> = remote_repo
+        .branches(None)
+        .unwrap()
+        .map(|b| b.unwrap().0.name().unwrap().unwrap().to_string())
+        .collect();
+    let actor_branches: Vec<&String> = branches
+        .iter()
+        .filter(|b| b.starts_with("actor_"))
+        .collect();
+    assert_eq!(actor_branches.len(), 1);
+    assert_ne!(actor_branches[0], "actor_42");
+
+    let tip = remote_repo
+        .find_branch(actor_branches[0], git2::BranchType::Local)
+        .unwrap()
+        .get()
+        .peel_to_commit()
+        .unwrap();
+    assert_eq!(tip.author().name(), Some("hermitdb"));
+    assert_eq!(tip.time().seconds(), 0);
+
+    let op_size = |commit: &git2::Commit| {
+        let tree = commit.tree().unwrap();
+        let entry = tree.get_name("op").unwrap();
+        remote_repo.find_blob(entry.id()).unwrap().size()
+    };
+    assert_eq!(op_size(&tip), op_size(&tip.parent(0).unwrap()));
+
+    let remote_meta =
+        git_log::Log::<TActor, TSet>::replica(git2::Repository::open(remote_dir.path()).unwrap());
+    for name in ["header", "keyring"] {
+        let meta = remote_meta.read_meta(name).unwrap().unwrap();
+        for member in [&b"alice"[..], &b"bob"[..]] {
+            assert!(!meta.windows(member.len()).any(|w| w == member));
+        }
+    }
+
+    git_log::fetch(&b_repo, &remote).unwrap();
+    let mut b_log = TLog::open(7, b_repo, password(b"bob pass")).unwrap();
+    b_log.pull(&remote).unwrap();
+    assert_eq!(drain(&mut b_log), (1..20).collect::<Vec<u8>>());
+    let mut members = b_log.members().unwrap();
+    members.sort();
+    assert_eq!(members, vec!["alice", "bob"]);
+    assert_eq!(b_log.remove_member("alice").unwrap(), 1);
+    assert_eq!(b_log.members().unwrap(), vec!["bob"]);
+}
+
+/// Commit an op onto actor 1's log as ops were written before they carried
+/// a version: a salt and the ciphertext.
+fn commit_legacy_op(repo: &git2::Repository, member: u8, salt: [u8; 32]) {
+    let set = TSet::new();
+    let op = set.add(member, set.read().derive_add_ctx(1));
+    let actor_bytes = bincode::serialize(&1u8).unwrap();
+    let encrypted = root_key()
+        .derive_child(&actor_bytes)
+        .key_for(&salt)
+        .encrypt(&bincode::serialize(&op).unwrap())
+        .unwrap();
+    let nonce: [u8; 12] = encrypted.nonce[..].try_into().unwrap();
+    let legacy_bytes = bincode::serialize(&(salt, nonce, encrypted.ciphertext)).unwrap();
+
+    let blob = repo.blob(&legacy_bytes).unwrap();
+    let mut builder = repo.treebuilder(None).unwrap();
+    builder.insert("op", blob, 0o100_644).unwrap();
+    let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+    let sig = git2::Signature::now("a", "a@example.com").unwrap();
+    let parent = repo
+        .find_reference("refs/heads/actor_1")
+        .ok()
+        .map(|tip| tip.peel_to_commit().unwrap());
+    let parents: Vec<_> = parent.iter().collect();
+    repo.commit(Some("refs/heads/actor_1"), &sig, &sig, "db op", &tree, &parents)
+        .unwrap();
+}
+
+#[test]
+fn test_ops_from_before_ops_were_versioned_still_decode() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+
+    commit_legacy_op(&a_repo, 7, [3u8; 32]);
+    commit_legacy_op(&a_repo, 6, [4u8; 32]);
+
+    let mut a_log = TLog::new(1, a_repo, root_key());
+    assert_eq!(drain(&mut a_log), vec![7, 6]);
+
+    // new ops are versioned and carry on the legacy log
+    commit_add(&mut a_log, 1, 8);
+    commit_add(&mut a_log, 1, 9);
+    a_log.push(&mut mk_remote(&remote_dir)).unwrap();
+
+    let report = a_log.verify().unwrap();
+    assert!(report.bad_commits.is_empty(), "{:?}", report.bad_commits);
+    assert_eq!(report.ops, 4);
+
+    let mut b_log = TLog::new(2, b_repo, root_key());
+    b_log.pull(&mk_remote(&remote_dir)).unwrap();
+    assert_eq!(drain(&mut b_log), vec![7, 6, 8, 9]);
+    let report = b_log.verify().unwrap();
+    assert!(report.bad_commits.is_empty(), "{:?}", report.bad_commits);
+}
+
+#[test]
+fn test_legacy_ops_cant_follow_versioned_ops() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, remote_repo) = mk_repo();
+
+    let mut a_log = TLog::new(1, a_repo, root_key());
+    commit_add(&mut a_log, 1, 8);
+    a_log.push(&mut mk_remote(&remote_dir)).unwrap();
+
+    // someone who can push splices an old style op onto the versioned log
+    commit_legacy_op(&remote_repo, 7, [3u8; 32]);
+
+    let mut b_log = TLog::new(2, b_repo, root_key());
+    b_log.pull(&mk_remote(&remote_dir)).unwrap();
+    let op = b_log.next().unwrap().unwrap();
+    b_log.ack(&op).unwrap();
+    assert_matches!(b_log.next(), Err(Error::Crypto(_)));
+
+    let report = b_log.verify().unwrap();
+    assert_eq!(report.ops, 1);
+    assert_eq!(report.bad_commits.len(), 1);
+}
+
+#[test]
+fn test_vaults_can_use_any_aead() {
+    for aead in [Aead::Aes256Gcm, Aead::XChaCha20Poly1305] {
+        let (_a_dir, a_repo) = mk_repo();
+        let (_b_dir, b_repo) = mk_repo();
+        let (remote_dir, _remote_repo) = mk_repo();
+        let mut remote = mk_remote(&remote_dir);
+
+        let settings = Settings { aead, ..settings() };
+        let mut a_log = TLog::init(1, a_repo, "alice", password(b"password"), settings).unwrap();
+        commit_add(&mut a_log, 1, 1);
+        a_log.push(&mut remote).unwrap();
+
+        git_log::fetch(&b_repo, &remote).unwrap();
+        let mut b_log = TLog::open(2, b_repo, password(b"password")).unwrap();
+        b_log.pull(&remote).unwrap();
+        assert_eq!(drain(&mut b_log), vec![1]);
+    }
+}
+
+#[test]
+fn test_compressed_and_uncompressed_ops_mix_in_a_vault() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+    let mut remote = mk_remote(&remote_dir);
+
+    let mut a_log = TLog::init(1, a_repo, "alice", password(b"password"), settings()).unwrap();
+    commit_add(&mut a_log, 1, 1);
+    a_log.compress_ops(3);
+    commit_add(&mut a_log, 1, 2);
+    a_log.push(&mut remote).unwrap();
+
+    git_log::fetch(&b_repo, &remote).unwrap();
+    let mut b_log = TLog::open(2, b_repo, password(b"password")).unwrap();
+    b_log.pull(&remote).unwrap();
+    assert_eq!(drain(&mut b_log), vec![1, 2]);
+}
+
+#[test]
+fn test_members_cant_sign_as_another_members_actor() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+    let mut remote = mk_remote(&remote_dir);
+
+    let mut alice = TLog::init(1, a_repo, "alice", password(b"alice pass"), settings()).unwrap();
+    alice
+        .add_member("bob", MemberKey::Password(b"bob pass".to_vec().into()))
+        .unwrap();
+    commit_add(&mut alice, 1, 1);
+    alice.push(&mut remote).unwrap();
+
+    // actor 1 is alice's, bob's key for it would differ from the one the vault trusts
+    git_log::fetch(&b_repo, &remote).unwrap();
+    assert_matches!(
+        TLog::open(1, b_repo, password(b"bob pass")).err(),
+        Some(Error::State(_))
+    );
+}
+
+#[test]
+fn test_rewritten_actor_keys_cant_vouch_for_forged_ops() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (mallory_dir, mallory_repo) = mk_repo();
+    let (remote_dir, remote_repo) = mk_repo();
+    let mut remote = mk_remote(&remote_dir);
+
+    let mut alice = TLog::init(1, a_repo, "alice", password(b"alice pass"), settings()).unwrap();
+    alice
+        .add_member("bob", MemberKey::Password(b"bob pass".to_vec().into()))
+        .unwrap();
+    alice.push(&mut remote).unwrap();
+
+    git_log::fetch(&b_repo, &remote).unwrap();
+    let mut bob = TLog::open(2, b_repo, password(b"bob pass")).unwrap();
+    bob.push(&mut remote).unwrap();
+
+    // mallory can push to the remote, she logs as actor 1 from a vault of
+    // her own and swaps in its actor keys, which map actor 1 to her key
+    let mut mallory = TLog::init(
+        1,
+        mallory_repo,
+        "mallory",
+        password(b"mallory pass"),
+        settings(),
+    )
+    .unwrap();
+    commit_add(&mut mallory, 1, 9);
+    remote_repo
+        .remote_anonymous(mallory_dir.path().to_str().unwrap())
+        .unwrap()
+        .fetch(&["refs/heads/actor_1:refs/heads/actor_1"], None, None)
+        .unwrap();
+    let mallory_meta =
+        git_log::Log::<TActor, TSet>::replica(git2::Repository::open(mallory_dir.path()).unwrap());
+    let forged = mallory_meta.read_meta("actor_keys").unwrap().unwrap();
+    git_log::Log::<TActor, TSet>::replica(remote_repo)
+        .write_meta("actor_keys", &forged)
+        .unwrap();
+
+    bob.pull(&remote).unwrap();
+    assert_matches!(bob.next(), Err(Error::BadSignature(_)));
+}
+
+#[test]
+fn test_recovery_phrase_opens_the_vault_after_a_forgotten_password() {
+    let (a_dir, a_repo) = mk_repo();
+
+    let mut a_log = TLog::init(1, a_repo, "alice", password(b"forgotten"), settings()).unwrap();
+    commit_add(&mut a_log, 1, 1);
+    let phrase = a_log.recovery_phrase().unwrap();
+    assert_eq!(a_log.rotate_data_key().unwrap(), 1);
+    commit_add(&mut a_log, 1, 2);
+    drop(a_log);
+
+    let a_repo = || git2::Repository::open(a_dir.path()).unwrap();
+    let mut a_log = TLog::open(1, a_repo(), Credential::RecoveryPhrase(phrase)).unwrap();
+    a_log.change_password(b"remembered").unwrap();
+    commit_add(&mut a_log, 1, 3);
+    drop(a_log);
+
+    assert!(TLog::open(1, a_repo(), password(b"remembered")).is_ok());
+    let typo = Credential::RecoveryPhrase("0000-0000".to_string().into());
+    assert_matches!(TLog::open(1, a_repo(), typo).err(), Some(Error::Parse(_)));
+}
+
+#[test]
+fn test_counter_nonces_refuse_positions_another_device_logged_to() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (b_dir, b_repo) = mk_repo();
+    let (_c_dir, c_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+    let mut remote = mk_remote(&remote_dir);
+
+    let mut a_log = TLog::init(1, a_repo, "alice", password(b"password"), settings()).unwrap();
+    a_log.push(&mut remote).unwrap();
+    git_log::fetch(&b_repo, &remote).unwrap();
+    let mut b_log = TLog::open(1, b_repo, password(b"password")).unwrap();
+
+    a_log.counter_nonces();
+    b_log.counter_nonces();
+    commit_add(&mut a_log, 1, 5);
+    commit_add(&mut a_log, 1, 6);
+    a_log.push(&mut remote).unwrap();
+
+    // b logs as actor 1 too, once it sees a's log it won't reuse a's positions
+    git_log::fetch(&git2::Repository::open(b_dir.path()).unwrap(), &remote).unwrap();
+    let set = TSet::new();
+    let op = set.add(7, set.read().derive_add_ctx(1));
+    assert_matches!(b_log.commit(op), Err(Error::State(_)));
+
+    commit_add(&mut a_log, 1, 7);
+    a_log.push(&mut remote).unwrap();
+    git_log::fetch(&c_repo, &remote).unwrap();
+    let mut c_log = TLog::open(3, c_repo, password(b"password")).unwrap();
+    c_log.pull(&remote).unwrap();
+    assert_eq!(drain(&mut c_log), vec![5, 6, 7]);
+}
+
+#[test]
+fn test_attachments_are_encrypted_and_checked_against_their_id() {
+    let (a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+    let mut remote = mk_remote(&remote_dir);
+
+    let mut a_log = TLog::init(1, a_repo, "alice", password(b"password"), settings()).unwrap();
+    let secret = b"a secret worth attaching".repeat(1000);
+    let (id, len) = a_log.put_attachment(&mut &secret[..]).unwrap();
+    assert_eq!(len, secret.len() as u64);
+    let (other_id, _) = a_log.put_attachment(&mut &b"something else"[..]).unwrap();
+    assert_ne!(id, crypto::sha256(&secret));
+
+    a_log.push(&mut remote).unwrap();
+    git_log::fetch(&b_repo, &remote).unwrap();
+    let b_log = TLog::open(2, b_repo, password(b"password")).unwrap();
+    assert!(!b_log.has_attachment(&id).unwrap());
+    b_log.fetch_attachment(&remote, &id).unwrap();
+    let mut read = Vec::new();
+    assert_eq!(b_log.read_attachment(&id, &mut read).unwrap(), len);
+    assert_eq!(read, secret);
+
+    // nothing in the repo holds the plaintext
+    let repo = git2::Repository::open(a_dir.path()).unwrap();
+    let commit = repo
+        .find_reference(&format!("refs/attachments/{}", hex(&id)))
+        .unwrap()
+        .peel_to_commit()
+        .unwrap();
+    for entry in commit.tree().unwrap().iter() {
+        let blob = repo.find_blob(entry.id()).unwrap();
+        assert!(!blob.content().windows(24).any(|w| w == &secret[..24]));
+    }
+
+    // an attachment swapped for another is caught
+    let other = repo
+        .find_reference(&format!("refs/attachments/{}", hex(&other_id)))
+        .unwrap()
+        .target()
+        .unwrap();
+    repo.reference(
+        &format!("refs/attachments/{}", hex(&id)),
+        other,
+        true,
+        "swap",
+    )
+    .unwrap();
+    assert_matches!(
+        a_log.read_attachment(&id, &mut Vec::new()),
+        Err(Error::Crypto(_))
+    );
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn test_storage_keys_are_kept_on_each_device() {
+    let (a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+    let mut remote = mk_remote(&remote_dir);
+
+    let mut a_log = TLog::init(1, a_repo, "alice", password(b"alice pw"), settings()).unwrap();
+    a_log
+        .add_member("bob", MemberKey::Password(b"bob pw".to_vec().into()))
+        .unwrap();
+    a_log.push(&mut remote).unwrap();
+    git_log::fetch(&b_repo, &remote).unwrap();
+    let b_log = TLog::open(2, b_repo, password(b"bob pw")).unwrap();
+
+    // bob holds every data key alice does, but not the key her storage is under
+    let stored_as = |log: &TLog| log.storage_cipher().unwrap().mac_key(b"key");
+    assert_ne!(stored_as(&a_log), stored_as(&b_log));
+    assert_eq!(a_log.rotate_data_key().unwrap(), 1);
+
+    let alice_storage = stored_as(&a_log);
+    drop(a_log);
+    let a_repo = git2::Repository::open(a_dir.path()).unwrap();
+    let a_log = TLog::open(1, a_repo, password(b"alice pw")).unwrap();
+    assert_eq!(stored_as(&a_log), alice_storage);
+}
+
+#[test]
+fn test_locked_vault_only_unlocks_for_the_member_who_locked_it() {
+    let (_a_dir, a_repo) = mk_repo();
+    let mut a_log = TLog::init(1, a_repo, "alice", password(b"alice pw"), settings()).unwrap();
+    a_log
+        .add_member("bob", MemberKey::Password(b"bob pw".to_vec().into()))
+        .unwrap();
+    commit_add(&mut a_log, 1, 5);
+
+    a_log.lock();
+    assert!(a_log.is_locked());
+    assert_matches!(a_log.next(), Err(Error::Locked));
+    assert_matches!(a_log.storage_cipher(), Err(Error::Locked));
+    assert_matches!(a_log.recovery_phrase(), Err(Error::Locked));
+    assert_matches!(a_log.unlock(password(b"bob pw")), Err(Error::Crypto(_)));
+    assert!(a_log.is_locked());
+
+    a_log.unlock(password(b"alice pw")).unwrap();
+    assert!(!a_log.is_locked());
+    commit_add(&mut a_log, 1, 6);
+}
+
+#[test]
+fn test_verify_reports_corrupt_commits_without_changing_anything() {
+    let (a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+    let mut remote = mk_remote(&remote_dir);
+
+    let mut a_log = TLog::init(1, a_repo, "alice", password(b"password"), settings()).unwrap();
+    commit_add(&mut a_log, 1, 5);
+    commit_add(&mut a_log, 1, 6);
+    a_log.push(&mut remote).unwrap();
+    git_log::fetch(&b_repo, &remote).unwrap();
+    let mut b_log = TLog::open(2, b_repo, password(b"password")).unwrap();
+    commit_add(&mut b_log, 2, 7);
+    b_log.push(&mut remote).unwrap();
+    a_log.pull(&remote).unwrap();
+
+    let report = a_log.verify().unwrap();
+    assert_eq!(report.ops, 3);
+    assert!(report.bad_commits.is_empty());
+
+    // pile a commit with a garbled op and one without an op on top of our log
+    let repo = git2::Repository::open(a_dir.path()).unwrap();
+    let sig = git2::Signature::now("mallory", "mallory@example.com").unwrap();
+    let mut tip = repo
+        .find_reference("refs/heads/actor_1")
+        .unwrap()
+        .peel_to_commit()
+        .unwrap();
+    let mut bad = Vec::new();
+    for op in [Some(&b"not an op"[..]), None] {
+        let mut builder = repo.treebuilder(None).unwrap();
+        if let Some(op) = op {
+            builder
+                .insert("op", repo.blob(op).unwrap(), 0o100_644)
+                .unwrap();
+        }
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        let oid = repo
+            .commit(Some("refs/heads/actor_1"), &sig, &sig, "op", &tree, &[&tip])
+            .unwrap();
+        tip = repo.find_commit(oid).unwrap();
+        bad.push(oid);
+    }
+
+    let report = a_log.verify().unwrap();
+    assert_eq!(report.ops, 3);
+    assert_eq!(
+        report
+            .bad_commits
+            .iter()
+            .map(|bad| bad.commit)
+            .collect::<Vec<_>>(),
+        bad
+    );
+    assert_matches!(
+        report.bad_commits[1].error,
+        Error::LogCommitDoesNotContainOp
+    );
+    assert_eq!(
+        repo.find_reference("refs/heads/actor_1").unwrap().target(),
+        Some(tip.id())
+    );
+}
+
+#[test]
+fn test_verify_reads_past_a_corrupt_op_in_the_middle_of_a_log() {
+    let (a_dir, a_repo) = mk_repo();
+    let mut a_log = TLog::init(1, a_repo, "alice", password(b"password"), settings()).unwrap();
+    commit_add(&mut a_log, 1, 5);
+    commit_add(&mut a_log, 1, 6);
+    commit_add(&mut a_log, 1, 7);
+    drop(a_log);
+
+    // the middle op's blob is damaged on disk
+    let repo = git2::Repository::open(a_dir.path()).unwrap();
+    let tip = repo
+        .find_reference("refs/heads/actor_1")
+        .unwrap()
+        .peel_to_commit()
+        .unwrap();
+    let middle = tip.parent(0).unwrap();
+    let blob = middle.tree().unwrap().get_name("op").unwrap().id().to_string();
+    let blob_path = a_dir
+        .path()
+        .join("objects")
+        .join(&blob[..2])
+        .join(&blob[2..]);
+    std::fs::remove_file(&blob_path).unwrap();
+    std::fs::write(&blob_path, b"bit rot").unwrap();
+
+    let a_repo = git2::Repository::open(a_dir.path()).unwrap();
+    let a_log = TLog::open(1, a_repo, password(b"password")).unwrap();
+    let report = a_log.verify().unwrap();
+    assert_eq!(report.ops, 2);
+    assert_eq!(
+        report
+            .bad_commits
+            .iter()
+            .map(|bad| bad.commit)
+            .collect::<Vec<_>>(),
+        vec![middle.id()]
+    );
+}
@@ -0,0 +1,449 @@
+use std::sync::{Arc, Mutex};
+
+use assert_matches::assert_matches;
+use hermitdb::{
+    crypto::SigningKey,
+    error::Error,
+    git_log::{self, Progress},
+    log::{BundleReplicable, LogReadable, LogReplicable},
+};
+
+mod common;
+
+use common::{commit_add, mk_named_remote, mk_remote, mk_repo, TActor, TSet};
+
+#[test]
+fn test_progress_is_reported_on_push_and_pull() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    let mut b_log: git_log::Log<TActor, TSet> = git_log::Log::new(2, b_repo);
+    commit_add(&mut a_log, 1, 42);
+
+    let pushed = Arc::new(Mutex::new(Vec::new()));
+    let pushed_ref = pushed.clone();
+    let mut remote = mk_remote(&remote_dir).with_progress(move |p| {
+        pushed_ref.lock().unwrap().push(p.clone());
+        true
+    });
+    assert_matches!(a_log.push(&mut remote), Ok(()));
+
+    assert!(pushed.lock().unwrap().contains(&Progress::PushUpdate {
+        refname: "refs/heads/actor_1".into(),
+        status: None,
+    }));
+
+    let fetched = Arc::new(Mutex::new(Vec::new()));
+    let fetched_ref = fetched.clone();
+    let remote = mk_remote(&remote_dir).with_progress(move |p| {
+        fetched_ref.lock().unwrap().push(p.clone());
+        true
+    });
+    assert_matches!(b_log.pull(&remote), Ok(()));
+
+    let fetched = fetched.lock().unwrap();
+    assert_matches!(fetched.last(), Some(Progress::Fetch { received_objects, total_objects, .. })
+        if received_objects == total_objects && *total_objects > 0);
+    assert_matches!(b_log.next(), Ok(Some(_)));
+}
+
+#[test]
+fn test_progress_callback_can_cancel_fetch() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    let mut b_log: git_log::Log<TActor, TSet> = git_log::Log::new(2, b_repo);
+    commit_add(&mut a_log, 1, 42);
+
+    // pushes can't be cancelled, they run to completion
+    let mut remote = mk_remote(&remote_dir).with_progress(|_| false);
+    assert_matches!(a_log.push(&mut remote), Ok(()));
+
+    assert_matches!(b_log.pull(&remote), Err(Error::SyncCancelled));
+    assert_matches!(b_log.next(), Ok(None));
+}
+
+#[test]
+fn test_push_of_diverged_history_is_rejected() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_imposter_dir, imposter_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    let mut imposter_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, imposter_repo);
+    commit_add(&mut a_log, 1, 42);
+    commit_add(&mut imposter_log, 1, 13);
+
+    assert_matches!(a_log.push(&mut mk_remote(&remote_dir)), Ok(()));
+    assert_matches!(
+        imposter_log.push(&mut mk_remote(&remote_dir)),
+        Err(Error::PushRejected(_))
+    );
+}
+
+#[test]
+fn test_rewritten_actor_history_is_quarantined() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (imposter_dir, imposter_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    let mut imposter_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, imposter_repo);
+    let mut b_log: git_log::Log<TActor, TSet> = git_log::Log::new(2, b_repo);
+
+    commit_add(&mut a_log, 1, 42);
+    commit_add(&mut a_log, 1, 43);
+    a_log.push(&mut mk_remote(&remote_dir)).unwrap();
+
+    b_log.pull(&mk_remote(&remote_dir)).unwrap();
+    while let Some(op) = b_log.next().unwrap() {
+        b_log.ack(&op).unwrap();
+    }
+
+    // force push a different history for actor 1
+    commit_add(&mut imposter_log, 1, 13);
+    git2::Repository::open(imposter_dir.path())
+        .unwrap()
+        .remote_anonymous(remote_dir.path().to_str().unwrap())
+        .unwrap()
+        .push(&["+refs/heads/actor_1:refs/heads/actor_1"], None)
+        .unwrap();
+
+    assert_matches!(
+        b_log.pull(&mk_remote(&remote_dir)),
+        Err(Error::HistoryRewritten(ref actors)) if actors == &vec!["1".to_string()]
+    );
+    assert_matches!(b_log.next(), Ok(None));
+    assert_eq!(b_log.quarantined().unwrap(), vec![1]);
+
+    // pulling again doesn't re-report the quarantined actor
+    assert_matches!(b_log.pull(&mk_remote(&remote_dir)), Ok(()));
+
+    assert_matches!(b_log.release_quarantine(&1), Ok(()));
+    assert_eq!(b_log.quarantined().unwrap(), vec![]);
+    let op = b_log.next().unwrap().unwrap();
+    assert_eq!(op.actor(), &1);
+    b_log.ack(&op).unwrap();
+    assert_matches!(b_log.next(), Ok(None));
+}
+
+#[test]
+fn test_push_only_sends_our_actor_branch_when_it_moved() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (remote_dir, remote_repo) = mk_repo();
+
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    commit_add(&mut a_log, 1, 42);
+
+    let updates = Arc::new(Mutex::new(Vec::new()));
+    let updates_ref = updates.clone();
+    let mut remote = mk_remote(&remote_dir).with_progress(move |p| {
+        if let Progress::PushUpdate { refname, .. } = p {
+            updates_ref.lock().unwrap().push(refname.clone());
+        }
+        true
+    });
+
+    a_log.push(&mut remote).unwrap();
+    assert_eq!(*updates.lock().unwrap(), vec!["refs/heads/actor_1".to_string()]);
+
+    let remote_branches: Vec<String> = remote_repo
+        .branches(None)
+        .unwrap()
+        .map(|b| b.unwrap().0.name().unwrap().unwrap().to_string())
+        .collect();
+    assert_eq!(remote_branches, vec!["actor_1".to_string()]);
+
+    // nothing moved, nothing is pushed
+    a_log.push(&mut remote).unwrap();
+    assert_eq!(updates.lock().unwrap().len(), 1);
+
+    commit_add(&mut a_log, 1, 43);
+    a_log.push(&mut remote).unwrap();
+    assert_eq!(updates.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn test_ops_fetched_from_several_remotes_are_seen_once() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (server_dir, _server_repo) = mk_repo();
+    let (usb_dir, _usb_repo) = mk_repo();
+
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    let mut b_log: git_log::Log<TActor, TSet> = git_log::Log::new(2, b_repo);
+    let mut server = mk_named_remote("server", &server_dir);
+    let mut usb = mk_named_remote("usb", &usb_dir);
+
+    commit_add(&mut a_log, 1, 1);
+    a_log.push(&mut usb).unwrap();
+    commit_add(&mut a_log, 1, 2);
+    a_log.push(&mut server).unwrap();
+
+    // usb is behind server
+    b_log.pull(&usb).unwrap();
+    b_log.pull(&server).unwrap();
+
+    let mut seen = 0;
+    while let Some(op) = b_log.next().unwrap() {
+        b_log.ack(&op).unwrap();
+        seen += 1;
+    }
+    assert_eq!(seen, 2);
+
+    // usb catches up, nothing new for b
+    a_log.push(&mut usb).unwrap();
+    b_log.pull(&usb).unwrap();
+    assert_matches!(b_log.next(), Ok(None));
+}
+
+#[test]
+fn test_diverged_meta_is_quarantined_until_released() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+    let mut remote = mk_remote(&remote_dir);
+
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    let mut b_log: git_log::Log<TActor, TSet> = git_log::Log::new(2, b_repo);
+    a_log.write_meta("settings", b"a's").unwrap();
+    a_log.push(&mut remote).unwrap();
+    b_log.write_meta("settings", b"b's").unwrap();
+
+    assert_matches!(b_log.pull(&remote), Err(Error::HistoryRewritten(rewritten))
+        if rewritten == vec!["meta_settings".to_string()]);
+    assert_eq!(b_log.read_meta("settings").unwrap(), Some(b"b's".to_vec()));
+
+    // ops still sync while the metadata is quarantined
+    commit_add(&mut b_log, 2, 1);
+    assert_matches!(b_log.sync(&mut remote), Ok(()));
+
+    b_log.release_meta_quarantine("settings").unwrap();
+    assert_eq!(b_log.read_meta("settings").unwrap(), Some(b"a's".to_vec()));
+    b_log.write_meta("settings", b"b's, on top of a's").unwrap();
+    b_log.push(&mut remote).unwrap();
+    a_log.pull(&remote).unwrap();
+    assert_eq!(
+        a_log.read_meta("settings").unwrap(),
+        Some(b"b's, on top of a's".to_vec())
+    );
+}
+
+#[test]
+fn test_bundles_carry_ops_between_devices() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let stick = tempfile::tempdir().unwrap();
+    let bundle = stick.path().join("vault.bundle");
+
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    let mut b_log: git_log::Log<TActor, TSet> = git_log::Log::new(2, b_repo);
+
+    commit_add(&mut a_log, 1, 1);
+    commit_add(&mut a_log, 1, 2);
+    a_log.export_bundle(&bundle).unwrap();
+
+    b_log.import_bundle(&bundle).unwrap();
+    let mut seen = 0;
+    while let Some(op) = b_log.next().unwrap() {
+        assert_eq!(op.actor(), &1);
+        b_log.ack(&op).unwrap();
+        seen += 1;
+    }
+    assert_eq!(seen, 2);
+
+    commit_add(&mut b_log, 2, 3);
+    b_log.export_bundle(&bundle).unwrap();
+
+    a_log.import_bundle(&bundle).unwrap();
+    let op = a_log.next().unwrap().unwrap();
+    assert_eq!(op.actor(), &2);
+    a_log.ack(&op).unwrap();
+    assert_matches!(a_log.next(), Ok(None));
+
+    // b has seen a's first two ops, the next bundle only carries the new one
+    commit_add(&mut a_log, 1, 4);
+    a_log.export_bundle(&bundle).unwrap();
+    let bundle_bytes = std::fs::read(&bundle).unwrap();
+    assert!(bundle_bytes.starts_with(b"# v2 git bundle\n-"));
+
+    b_log.import_bundle(&bundle).unwrap();
+    let op = b_log.next().unwrap().unwrap();
+    b_log.ack(&op).unwrap();
+    assert_matches!(b_log.next(), Ok(None));
+}
+
+#[test]
+fn test_bundle_missing_prerequisites_is_an_error() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (_c_dir, c_repo) = mk_repo();
+    let stick = tempfile::tempdir().unwrap();
+    let bundle = stick.path().join("vault.bundle");
+
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    let mut b_log: git_log::Log<TActor, TSet> = git_log::Log::new(2, b_repo);
+    let mut c_log: git_log::Log<TActor, TSet> = git_log::Log::new(3, c_repo);
+
+    commit_add(&mut a_log, 1, 1);
+    a_log.export_bundle(&bundle).unwrap();
+    b_log.import_bundle(&bundle).unwrap();
+    commit_add(&mut b_log, 2, 2);
+    b_log.export_bundle(&bundle).unwrap();
+    a_log.import_bundle(&bundle).unwrap();
+
+    commit_add(&mut a_log, 1, 2);
+    a_log.export_bundle(&bundle).unwrap();
+    assert_matches!(c_log.import_bundle(&bundle), Err(Error::State(_)));
+}
+
+#[test]
+fn test_bundles_carry_what_any_known_device_is_missing() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (_c_dir, c_repo) = mk_repo();
+    let stick = tempfile::tempdir().unwrap();
+    let bundle = stick.path().join("vault.bundle");
+
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    let mut b_log: git_log::Log<TActor, TSet> = git_log::Log::new(2, b_repo);
+    let mut c_log: git_log::Log<TActor, TSet> = git_log::Log::new(3, c_repo);
+    let drain = |log: &mut git_log::Log<TActor, TSet>| {
+        let mut seen = 0;
+        while let Some(op) = log.next().unwrap() {
+            log.ack(&op).unwrap();
+            seen += 1;
+        }
+        seen
+    };
+
+    commit_add(&mut a_log, 1, 1);
+    a_log.export_bundle(&bundle).unwrap();
+    b_log.import_bundle(&bundle).unwrap();
+    c_log.import_bundle(&bundle).unwrap();
+    assert_eq!(drain(&mut b_log), 1);
+    assert_eq!(drain(&mut c_log), 1);
+
+    commit_add(&mut c_log, 3, 3);
+    c_log.export_bundle(&bundle).unwrap();
+    a_log.import_bundle(&bundle).unwrap();
+    assert_eq!(drain(&mut a_log), 1);
+
+    commit_add(&mut a_log, 1, 2);
+    a_log.export_bundle(&bundle).unwrap();
+    b_log.import_bundle(&bundle).unwrap();
+    assert_eq!(drain(&mut b_log), 1);
+    commit_add(&mut b_log, 2, 4);
+    b_log.export_bundle(&bundle).unwrap();
+    a_log.import_bundle(&bundle).unwrap();
+    assert_eq!(drain(&mut a_log), 1);
+
+    // b has all of a's ops, but c still hasn't seen a's second op
+    commit_add(&mut a_log, 1, 5);
+    a_log.export_bundle(&bundle).unwrap();
+    c_log.import_bundle(&bundle).unwrap();
+    assert_eq!(drain(&mut c_log), 2);
+}
+
+#[test]
+fn test_compressed_and_uncompressed_ops_mix_in_a_log() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (remote_dir, remote_repo) = mk_repo();
+
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    let mut b_log: git_log::Log<TActor, TSet> = git_log::Log::new(2, b_repo);
+
+    commit_add(&mut a_log, 1, 1);
+    a_log.compress_ops(3);
+    commit_add(&mut a_log, 1, 2);
+    a_log.push(&mut mk_remote(&remote_dir)).unwrap();
+
+    let tip = remote_repo
+        .find_reference("refs/heads/actor_1")
+        .unwrap()
+        .peel_to_commit()
+        .unwrap();
+    let tree = tip.tree().unwrap();
+    assert!(tree.get_name("op").is_none());
+    assert!(tree.get_name("op.zst").is_some());
+
+    b_log.pull(&mk_remote(&remote_dir)).unwrap();
+    let mut seen = 0;
+    while let Some(op) = b_log.next().unwrap() {
+        b_log.ack(&op).unwrap();
+        seen += 1;
+    }
+    assert_eq!(seen, 2);
+}
+
+#[test]
+fn test_ops_decompressing_past_the_limit_are_refused() {
+    let (a_dir, a_repo) = mk_repo();
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    a_log.compress_ops(3);
+    commit_add(&mut a_log, 1, 1);
+
+    // a tiny blob that decompresses to 17MiB
+    let repo = git2::Repository::open(a_dir.path()).unwrap();
+    let bomb = zstd::bulk::compress(&vec![0u8; 17 * 1024 * 1024], 3).unwrap();
+    let mut builder = repo.treebuilder(None).unwrap();
+    builder
+        .insert("op.zst", repo.blob(&bomb).unwrap(), 0o100_644)
+        .unwrap();
+    let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+    let sig = git2::Signature::now("mallory", "mallory@example.com").unwrap();
+    let tip = repo
+        .find_reference("refs/heads/actor_1")
+        .unwrap()
+        .peel_to_commit()
+        .unwrap();
+    let bomb_oid = repo
+        .commit(Some("refs/heads/actor_1"), &sig, &sig, "op", &tree, &[&tip])
+        .unwrap();
+
+    let report = a_log.verify().unwrap();
+    assert_eq!(report.ops, 1);
+    assert_eq!(report.bad_commits.len(), 1);
+    assert_eq!(report.bad_commits[0].commit, bomb_oid);
+    assert_matches!(report.bad_commits[0].error, Error::State(_));
+}
+
+#[test]
+fn test_unsigned_ops_are_rejected_when_signatures_are_required() {
+    let (_a_dir, a_repo) = mk_repo();
+    let (_b_dir, b_repo) = mk_repo();
+    let (_c_dir, c_repo) = mk_repo();
+    let (remote_dir, _remote_repo) = mk_repo();
+
+    let a_key = SigningKey::generate().unwrap();
+    let c_key = SigningKey::generate().unwrap();
+    let mut a_log: git_log::Log<TActor, TSet> = git_log::Log::new(1, a_repo);
+    let mut b_log: git_log::Log<TActor, TSet> = git_log::Log::new(2, b_repo);
+    let mut c_log: git_log::Log<TActor, TSet> = git_log::Log::new(3, c_repo);
+
+    a_log.sign_ops(SigningKey::from_bytes(&a_key.to_bytes()).unwrap());
+    commit_add(&mut a_log, 1, 1);
+    a_log.push(&mut mk_remote(&remote_dir)).unwrap();
+    commit_add(&mut c_log, 3, 3); // c doesn't sign
+    c_log.push(&mut mk_remote(&remote_dir)).unwrap();
+
+    b_log.trust_actor(&1, a_key.verifying_key()).unwrap();
+    b_log.trust_actor(&3, c_key.verifying_key()).unwrap();
+    assert_matches!(
+        b_log.trust_actor(&1, c_key.verifying_key()),
+        Err(Error::State(_))
+    );
+    b_log.require_signed_ops();
+    b_log.pull(&mk_remote(&remote_dir)).unwrap();
+
+    let op = b_log.next().unwrap().unwrap();
+    assert_eq!(op.actor(), &1);
+    b_log.ack(&op).unwrap();
+    assert_matches!(b_log.next(), Err(Error::BadSignature(ref actor)) if actor == "3");
+}
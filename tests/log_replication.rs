@@ -1,7 +1,6 @@
 use std::num::NonZeroU32;
 
 use assert_matches::assert_matches;
-use git2;
 use hermitdb::{
     crdts::{map, CmRDT, Map, Orswot},
     crypto, encrypted_git_log, git_log,
@@ -29,7 +28,7 @@ impl Arbitrary for OpVec {
             let die_roll = u8::arbitrary(g);
             let key = TKey::arbitrary(g);
             let read_ctx = map.get(&key);
-            let add_ctx = read_ctx.derive_add_ctx(actor.clone());
+            let add_ctx = read_ctx.derive_add_ctx(actor);
             let op = match die_roll % 2 {
                 0 => {
                     // update Orswot
@@ -153,15 +152,15 @@ quickcheck! {
         let remote_dir = tempfile::tempdir().unwrap();
 
         let a_log_git = git2::Repository::init_bare(
-            &a_log_dir.path()
+            a_log_dir.path()
         ).unwrap();
 
         let b_log_git = git2::Repository::init_bare(
-            &b_log_dir.path()
+            b_log_dir.path()
         ).unwrap();
 
         let _remote_git = git2::Repository::init_bare(
-            &remote_dir.path()
+            remote_dir.path()
         ).unwrap();
 
         let a_log = git_log::Log::new(actor1, a_log_git);
@@ -185,24 +184,27 @@ quickcheck! {
         }
 
         let root_key = crypto::KDF {
-            pbkdf2_iters: NonZeroU32::new(1).unwrap(),
-            salt: [0u8; 256 / 8]
-        }.derive_root(b"password");
+            algorithm: crypto::KdfAlgorithm::Pbkdf2HmacSha256 {
+                iters: NonZeroU32::new(1).unwrap(),
+            },
+            salt: [0u8; 256 / 8],
+            requires_keyfile: false
+        }.derive_root(b"password", None).unwrap();
 
         let a_log_dir = tempfile::tempdir().unwrap();
         let b_log_dir = tempfile::tempdir().unwrap();
         let remote_dir = tempfile::tempdir().unwrap();
 
         let a_log_git = git2::Repository::init_bare(
-            &a_log_dir.path()
+            a_log_dir.path()
         ).unwrap();
 
         let b_log_git = git2::Repository::init_bare(
-            &b_log_dir.path()
+            b_log_dir.path()
         ).unwrap();
 
         let _remote_git = git2::Repository::init_bare(
-            &remote_dir.path()
+            remote_dir.path()
         ).unwrap();
 
         let a_log = encrypted_git_log::Log::new(
@@ -236,7 +238,7 @@ quickcheck! {
         let OpVec(actor, ops) = ops;
         let log_dir = tempfile::tempdir().unwrap();
         let log_path = log_dir.path();
-        let log_git = git2::Repository::init_bare(&log_path).unwrap();
+        let log_git = git2::Repository::init_bare(log_path).unwrap();
 
         let log = git_log::Log::new(actor, log_git);
 
@@ -249,12 +251,15 @@ quickcheck! {
         let OpVec(actor, ops) = ops;
         let log_dir = tempfile::tempdir().unwrap();
         let log_path = log_dir.path();
-        let log_git = git2::Repository::init_bare(&log_path).unwrap();
+        let log_git = git2::Repository::init_bare(log_path).unwrap();
 
         let root_key = crypto::KDF {
-            pbkdf2_iters: NonZeroU32::new(1).unwrap(),
-            salt: [0u8; 256 / 8]
-        }.derive_root(b"password");
+            algorithm: crypto::KdfAlgorithm::Pbkdf2HmacSha256 {
+                iters: NonZeroU32::new(1).unwrap(),
+            },
+            salt: [0u8; 256 / 8],
+            requires_keyfile: false
+        }.derive_root(b"password", None).unwrap();
 
         let log = encrypted_git_log::Log::new(
             actor,
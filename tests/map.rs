@@ -1,3 +1,5 @@
+#![allow(clippy::type_complexity)]
+
 use hermitdb::crdts::{map, mvreg, CmRDT, CvRDT, Dot, MVReg, Map, ResetRemove, VClock};
 use quickcheck::{quickcheck, TestResult};
 
@@ -0,0 +1,116 @@
+use assert_matches::assert_matches;
+use hermitdb::{
+    encrypted_git_log::{MemberKey, Settings},
+    error::Error,
+    git_log,
+    log::{AttachmentStore, LogReadable, LogReplicable},
+    relay::Relay,
+};
+
+mod common;
+
+use common::*;
+
+fn tip(repo: &git2::Repository, glob: &str) -> git2::Oid {
+    let mut refs = repo.references_glob(glob).unwrap();
+    let tip = refs.next().unwrap().unwrap().target().unwrap();
+    assert!(refs.next().is_none());
+    tip
+}
+
+#[test]
+fn test_relay_carries_an_encrypted_vault_between_remotes() {
+    let (_alice_dir, alice_repo) = mk_repo();
+    let (_bob_dir, bob_repo) = mk_repo();
+    let (_relay_dir, relay_repo) = mk_repo();
+    let (a_dir, _a_repo) = mk_repo();
+    let (b_dir, _b_repo) = mk_repo();
+    let mut remotes = [mk_named_remote("a", &a_dir), mk_named_remote("b", &b_dir)];
+
+    let settings = Settings { opaque: true, ..settings() };
+    let mut alice = TLog::init(1, alice_repo, "alice", password(b"alice pw"), settings).unwrap();
+    alice
+        .add_member("bob", MemberKey::Password(b"bob pw".to_vec().into()))
+        .unwrap();
+    commit_add(&mut alice, 1, 1);
+    let (photo, _) = alice.put_attachment(&mut &b"photo"[..]).unwrap();
+    alice.push(&mut remotes[0]).unwrap();
+
+    let mut relay = Relay::new(relay_repo);
+    relay.relay(&mut remotes).unwrap();
+
+    // bob only ever talks to b
+    git_log::fetch(&bob_repo, &remotes[1]).unwrap();
+    let mut bob = TLog::open(2, bob_repo, password(b"bob pw")).unwrap();
+    bob.pull(&remotes[1]).unwrap();
+    assert_eq!(drain(&mut bob), vec![1]);
+    bob.fetch_attachment(&remotes[1], &photo).unwrap();
+    let mut read = Vec::new();
+    bob.read_attachment(&photo, &mut read).unwrap();
+    assert_eq!(read, b"photo");
+
+    commit_add(&mut bob, 2, 2);
+    bob.push(&mut remotes[1]).unwrap();
+    relay.relay(&mut remotes).unwrap();
+    alice.pull(&remotes[0]).unwrap();
+    assert_eq!(drain(&mut alice), vec![2]);
+
+    let report = relay.verify().unwrap();
+    assert_eq!(report.ops, 2);
+    assert!(report.bad_commits.is_empty());
+}
+
+#[test]
+fn test_relay_refuses_malformed_commits() {
+    let (_alice_dir, alice_repo) = mk_repo();
+    let (relay_dir, relay_repo) = mk_repo();
+    let (a_dir, a_repo) = mk_repo();
+    let (b_dir, b_repo) = mk_repo();
+    let mut remotes = [mk_named_remote("a", &a_dir), mk_named_remote("b", &b_dir)];
+
+    let settings = Settings { opaque: true, ..settings() };
+    let mut alice = TLog::init(1, alice_repo, "alice", password(b"alice pw"), settings).unwrap();
+    commit_add(&mut alice, 1, 1);
+    alice.push(&mut remotes[0]).unwrap();
+    let mut relay = Relay::new(relay_repo);
+    relay.relay(&mut remotes).unwrap();
+    let relayed = tip(&b_repo, "refs/heads/actor_*");
+
+    // someone with write access to a piles a commit that isn't an op onto alice's branch
+    let branch = a_repo
+        .references_glob("refs/heads/actor_*")
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .name()
+        .unwrap()
+        .to_string();
+    let parent = a_repo.find_commit(relayed).unwrap();
+    let mut builder = a_repo.treebuilder(None).unwrap();
+    builder
+        .insert("op", a_repo.blob(b"op").unwrap(), 0o100_644)
+        .unwrap();
+    builder
+        .insert("payload", a_repo.blob(b"payload").unwrap(), 0o100_644)
+        .unwrap();
+    let tree = a_repo.find_tree(builder.write().unwrap()).unwrap();
+    let sig = git2::Signature::now("mallory", "mallory@example.com").unwrap();
+    a_repo
+        .commit(Some(&branch), &sig, &sig, "op", &tree, &[&parent])
+        .unwrap();
+
+    // the refused branch is named, under the remote we pulled it from
+    match relay.relay(&mut remotes) {
+        Err(Error::SyncFailed(failed)) => {
+            assert_eq!(failed.len(), 1);
+            assert_eq!(failed[0].0, "a");
+            assert_matches!(&failed[0].1, Error::PullRefused(refused)
+                if refused.len() == 1 && format!("refs/heads/{}", refused[0].0) == branch);
+        }
+        other => panic!("expected a's branch to be refused, got {:?}", other),
+    }
+    let relay_repo = git2::Repository::open(relay_dir.path()).unwrap();
+    assert_eq!(tip(&relay_repo, "refs/heads/actor_*"), relayed);
+    assert_eq!(tip(&b_repo, "refs/heads/actor_*"), relayed);
+}